@@ -0,0 +1,644 @@
+// One-shot subcommands, e.g. `ext2 fsck image.ext2`, as opposed to the
+// interactive shell in main.rs.
+
+use ext2::ext2::structs::{ERR_IGNORE, ERR_PANIC, ERR_RONLY};
+use ext2::ext2::{
+    find_backups, CompatFeatures, Ext2, Ext2Error, FormatOptions, Repairs, SuperblockCopy,
+    ROOT_INODE,
+};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+const USAGE: &str = "usage: ext2 [command args...]
+with no command, start the interactive shell on the built-in image
+
+commands:
+    fsck [--repair[=what,...]] [--dry-run] [-b block] [-D] image.ext2
+                       check an image for inconsistencies, and repair
+                       them in place; --dry-run only lists the repairs;
+                       -b uses the backup superblock at block instead of
+                       the primary, which --repair restores from it;
+                       -D indexes every directory bigger than a block
+    mkfs [options] image.ext2
+                       format an image, creating it if --size is given:
+                       -b block size, -I inode size, --inodes-per-group,
+                       -L label, -e errors behavior (continue,
+                       remount-ro or panic), -U uuid, -O feature,^feature...,
+                       --hash-seed uuid, --timestamp seconds (by default
+                       $SOURCE_DATE_EPOCH, for a reproducible image)
+    build --from dir|--tar archive.tar|--spec spec.toml [mkfs options] image.ext2
+                       format an image holding a copy of a host directory,
+                       the contents of a tar archive, or what a spec
+                       describes (see fixtures/stress.toml)
+    extract image.ext2 dir [path]
+                       copy the image, or the part of it at path, to a
+                       host directory
+    tar image.ext2 archive.tar [path]
+                       write the image, or the part of it at path, to a
+                       tar archive
+    dumpe2fs [-h] image.ext2
+                       describe the superblock and, without -h, where each
+                       block group keeps its metadata and what is free
+    fuse [-w] image.ext2 dir
+                       mount the image on dir until it is unmounted or
+                       this is interrupted; -w allows changes to it";
+
+/// Run the subcommand named by `args[0]` and return the process exit code
+pub fn run(args: &[String]) -> i32 {
+    match args[0].as_str() {
+        "fsck" => fsck(&args[1..]),
+        "mkfs" => mkfs(&args[1..]),
+        "build" => build(&args[1..]),
+        "extract" => extract(&args[1..]),
+        "tar" => tar(&args[1..]),
+        "dumpe2fs" => dumpe2fs(&args[1..]),
+        "fuse" => fuse(&args[1..]),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            0
+        }
+        other => {
+            eprintln!("ext2: unknown command {}\n{}", other, USAGE);
+            2
+        }
+    }
+}
+
+/// Read a whole image into memory for the lifetime of the program
+fn load_image(path: &str) -> std::io::Result<&'static mut [u8]> {
+    Ok(Box::leak(std::fs::read(path)?.into_boxed_slice()))
+}
+
+/// Parse a size such as `4096`, `512K`, `64M` or `1G`
+fn parse_size(size: &str) -> Option<usize> {
+    let (number, shift) = match size.char_indices().last()? {
+        (i, 'K' | 'k') => (&size[..i], 10),
+        (i, 'M' | 'm') => (&size[..i], 20),
+        (i, 'G' | 'g') => (&size[..i], 30),
+        _ => (size, 0),
+    };
+    number.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+/// `ext2 fsck [--repair[=what,...]] [--dry-run] [-b block] [-D] image.ext2`
+/// Exit codes follow e2fsck: 0 when clean, 1 when every problem was
+/// repaired, 4 when problems are left and 8 when the image couldn't be
+/// checked.
+fn fsck(args: &[String]) -> i32 {
+    const FSCK_USAGE: &str =
+        "usage: ext2 fsck [--repair[=what,...]] [--dry-run] [-b block] [-D] image.ext2
+repairs (all by default): inodes, bitmaps, lost+found, links, counts";
+    let mut repairs = None;
+    let mut dry_run = false;
+    let mut backup_block = None;
+    let mut index_dirs = false;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--repair" => repairs = Some(Repairs::all()),
+            "--dry-run" => dry_run = true,
+            // like e2fsck -D, which implies writing to the image
+            "-D" => {
+                index_dirs = true;
+                repairs.get_or_insert(Repairs::empty());
+            }
+            "-b" => match args.next().and_then(|block| block.parse::<u32>().ok()) {
+                Some(block) => backup_block = Some(block),
+                None => {
+                    eprintln!("{}", FSCK_USAGE);
+                    return 8;
+                }
+            },
+            arg if arg.starts_with("--repair=") => {
+                let mut selected = Repairs::empty();
+                for name in arg["--repair=".len()..].split(',') {
+                    selected |= match name {
+                        "inodes" => Repairs::BAD_INODES,
+                        "bitmaps" => Repairs::BITMAPS,
+                        "lost+found" => Repairs::LOST_FOUND,
+                        "links" => Repairs::LINK_COUNTS,
+                        "counts" => Repairs::FREE_COUNTS,
+                        "all" => Repairs::all(),
+                        other => {
+                            eprintln!("fsck: unknown repair {}\n{}", other, FSCK_USAGE);
+                            return 8;
+                        }
+                    };
+                }
+                repairs = Some(selected);
+            }
+            arg if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => {
+                eprintln!("{}", FSCK_USAGE);
+                return 8;
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{}", FSCK_USAGE);
+        return 8;
+    };
+    let disk = match load_image(path) {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("fsck: {}: {}", path, err);
+            return 8;
+        }
+    };
+    let start_addr = disk.as_ptr() as usize;
+
+    // e2fsck -b style: the backup is found by scanning, since the primary
+    // that would say where backups are may be what's broken
+    let mut backup = None;
+    if let Some(block) = backup_block {
+        match find_backups(disk)
+            .into_iter()
+            .find(|copy| copy.block == block)
+        {
+            Some(copy) => backup = Some(copy),
+            None => {
+                eprintln!("fsck: {}: no backup superblock at block {}", path, block);
+                return 8;
+            }
+        }
+    }
+    let open = |disk: &'static [u8], backup: Option<SuperblockCopy>| match backup {
+        Some(copy) => Ext2::new_from_backup(disk, start_addr, copy),
+        None => Ext2::new(disk, start_addr),
+    };
+
+    let mut repaired = false;
+    if let Some(repairs) = repairs {
+        if dry_run {
+            let ext2 = match open(disk, backup) {
+                Ok(ext2) => ext2,
+                Err(err) => {
+                    open_failed(path, &err, disk);
+                    return 8;
+                }
+            };
+            for repair in ext2.plan_repairs(repairs) {
+                println!("Would: {}", repair);
+            }
+        } else {
+            if let Some(copy) = backup.take() {
+                if let Err(err) = Ext2::restore_primary(disk, copy) {
+                    eprintln!("fsck: {}: {}", path, err);
+                    return 8;
+                }
+                println!(
+                    "Restored the superblock from the backup at block {}",
+                    copy.block
+                );
+                repaired = true;
+            }
+            let mut ext2 = match Ext2::new_mut(&mut disk[..], start_addr) {
+                Ok(ext2) => ext2,
+                Err(err) => {
+                    open_failed(path, &err, disk);
+                    return 8;
+                }
+            };
+            // a finished repair that leaves nothing wrong marks the image
+            // checked, as e2fsck does; and only a finished one is copied to
+            // the backups, so they stay usable if it stops part way
+            let result = ext2.repair(repairs).and_then(|done| {
+                if index_dirs {
+                    for dir in ext2.index_dirs()? {
+                        println!("Indexed directory inode {}", dir);
+                        repaired = true;
+                    }
+                }
+                if ext2.check().is_clean() {
+                    ext2.mark_checked()?;
+                }
+                ext2.sync()?;
+                Ok(done)
+            });
+            drop(ext2);
+            let done = match result {
+                Ok(done) => done,
+                Err(err) => {
+                    eprintln!("fsck: {}: repair stopped: {}", path, err);
+                    Vec::new()
+                }
+            };
+            for repair in &done {
+                println!("{}", repair);
+            }
+            repaired |= !done.is_empty();
+            // write back even after an error, so the image holds the
+            // repairs that were made rather than some of their blocks
+            if let Err(err) = std::fs::write(path, &disk[..]) {
+                eprintln!("fsck: {}: {}", path, err);
+                return 8;
+            }
+        }
+    }
+
+    let ext2 = match open(disk, backup) {
+        Ok(ext2) => ext2,
+        Err(err) => {
+            open_failed(path, &err, disk);
+            return 8;
+        }
+    };
+    let report = ext2.check();
+    print!("{}", report);
+    // what was checked is the backup, but the primary is what gets
+    // mounted; unless it was restored from the backup, it is wrong too
+    let bad_primary = match backup {
+        Some(copy) => open(disk, None).err().map(|err| (copy, err)),
+        None => None,
+    };
+    if let Some((copy, err)) = &bad_primary {
+        println!(
+            "Primary superblock is invalid ({}); fsck --repair -b {} restores it",
+            err, copy.block
+        );
+    }
+    if report.is_clean() && bad_primary.is_none() {
+        if repaired {
+            println!("{}: repaired", path);
+            1
+        } else {
+            println!("{}: clean", path);
+            0
+        }
+    } else {
+        let problems = report.problems.len() + bad_primary.iter().count();
+        println!("{}: {} problems found", path, problems);
+        4
+    }
+}
+
+/// Report why `fsck` couldn't open an image, and where it might find a
+/// backup superblock to try instead if the primary is what's bad
+fn open_failed(path: &str, err: &Ext2Error, disk: &[u8]) {
+    eprintln!("fsck: {}: {}", path, err);
+    if let Ext2Error::BadSuperblock(_) = err {
+        let backups = find_backups(disk);
+        if let Some(first) = backups.first() {
+            let blocks: Vec<String> = backups.iter().map(|copy| copy.block.to_string()).collect();
+            eprintln!(
+                "fsck: backup superblocks are at blocks {}; try fsck -b {}",
+                blocks.join(", "),
+                first.block
+            );
+        }
+    }
+}
+
+/// What `mkfs` and `build` were asked to make
+struct FormatArgs<'a> {
+    options: FormatOptions,
+    /// Bytes; by default the size of the existing image file
+    size: Option<usize>,
+    path: &'a str,
+    /// Host directory to copy into the image
+    from: Option<&'a str>,
+    /// Tar archive to unpack into the image
+    tar: Option<&'a str>,
+    /// Spec of what to create in the image
+    spec: Option<&'a str>,
+}
+
+const FORMAT_OPTIONS: &str = "[-b block-size] [-I inode-size] [--inodes-per-group n] \
+[-L label] [-e continue|remount-ro|panic] [-U uuid] [-O feature,^feature...] \
+[--hash-seed uuid] [--timestamp seconds] [--size size]";
+
+/// Parse the options of `mkfs`, or of `build` if `populate` is set, which
+/// takes one of `--from`, `--tar` and `--spec` as well
+fn parse_format_args<'a>(
+    command: &str,
+    args: &'a [String],
+    populate: bool,
+) -> Option<FormatArgs<'a>> {
+    let mut parsed = FormatArgs {
+        options: FormatOptions::default(),
+        size: None,
+        path: "",
+        from: None,
+        tar: None,
+        spec: None,
+    };
+    // https://reproducible-builds.org/specs/source-date-epoch/
+    if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        match epoch.parse() {
+            Ok(time) => parsed.options.fixed_time = Some(time),
+            Err(_) => {
+                eprintln!("{}: bad SOURCE_DATE_EPOCH {}", command, epoch);
+                return None;
+            }
+        }
+    }
+    let options = &mut parsed.options;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') && parsed.path.is_empty() {
+            parsed.path = arg;
+            continue;
+        }
+        let value = args.next()?;
+        let ok = match arg.as_str() {
+            "-b" | "--block-size" => parse_size(value)
+                .map(|block_size| options.block_size = Some(block_size))
+                .is_some(),
+            "-I" | "--inode-size" => value
+                .parse()
+                .map(|inode_size| options.inode_size = inode_size)
+                .is_ok(),
+            "--inodes-per-group" => value
+                .parse()
+                .map(|inodes| options.inodes_per_group = Some(inodes))
+                .is_ok(),
+            "-e" | "--errors" => {
+                options.errors = match value.as_str() {
+                    "continue" => ERR_IGNORE,
+                    "remount-ro" => ERR_RONLY,
+                    "panic" => ERR_PANIC,
+                    _ => 0,
+                };
+                options.errors != 0
+            }
+            "-L" | "--label" => {
+                options.label = value.clone();
+                true
+            }
+            "-U" | "--uuid" => uuid::Uuid::parse_str(value)
+                .map(|uuid| options.uuid = Some(uuid))
+                .is_ok(),
+            "--hash-seed" => uuid::Uuid::parse_str(value)
+                .map(|seed| options.hash_seed = Some(seed))
+                .is_ok(),
+            "--timestamp" => value
+                .parse()
+                .map(|time| options.fixed_time = Some(time))
+                .is_ok(),
+            "-O" | "--features" => value.split(',').all(|feature| {
+                let (name, enabled) = match feature.strip_prefix('^') {
+                    Some(name) => (name, false),
+                    None => (feature, true),
+                };
+                options.set_feature(name, enabled).is_ok()
+            }),
+            "-s" | "--size" => parse_size(value)
+                .map(|bytes| parsed.size = Some(bytes))
+                .is_some(),
+            "--from" if populate => {
+                parsed.from = Some(value);
+                true
+            }
+            "--tar" if populate => {
+                parsed.tar = Some(value);
+                true
+            }
+            "--spec" if populate => {
+                parsed.spec = Some(value);
+                true
+            }
+            _ => false,
+        };
+        if !ok {
+            eprintln!("{}: bad option {} {}", command, arg, value);
+            return None;
+        }
+    }
+    let sources = [parsed.from, parsed.tar, parsed.spec];
+    if parsed.path.is_empty() || populate && sources.iter().flatten().count() != 1 {
+        return None;
+    }
+    Some(parsed)
+}
+
+/// Format a new image as `args` describe, let `populate` fill it in, and
+/// write it out
+fn make_image(
+    command: &str,
+    args: &FormatArgs,
+    populate: impl FnOnce(&mut Ext2) -> std::io::Result<()>,
+) -> i32 {
+    let path = args.path;
+    let size = match args.size {
+        Some(size) => size,
+        None => match std::fs::metadata(path) {
+            Ok(metadata) => metadata.len() as usize,
+            Err(err) => {
+                eprintln!("{}: {}: {} (use --size to create it)", command, path, err);
+                return 8;
+            }
+        },
+    };
+    let mut disk = vec![0; size];
+    let mut ext2 = match Ext2::format(&mut disk[..], &args.options) {
+        Ok(ext2) => ext2,
+        Err(err) => {
+            eprintln!("{}: {}: {}", command, path, err);
+            return 8;
+        }
+    };
+    println!(
+        "Creating filesystem with {} {}k blocks and {} inodes",
+        ext2.superblock.blocks_count,
+        ext2.block_size / 1024,
+        ext2.superblock.inodes_count
+    );
+    println!("Filesystem UUID: {}", ext2.uuid);
+    if let Err(err) = populate(&mut ext2) {
+        eprintln!("{}: {}", command, err);
+        return 8;
+    }
+    // the kernel would have indexed them as they grew past a block
+    if ext2.superblock.compat().contains(CompatFeatures::DIR_INDEX) {
+        if let Err(err) = ext2.index_dirs() {
+            eprintln!("{}: {}: {}", command, path, err);
+            return 8;
+        }
+    }
+    if let Err(err) = ext2.sync() {
+        eprintln!("{}: {}: {}", command, path, err);
+        return 8;
+    }
+    drop(ext2);
+    if let Err(err) = std::fs::write(path, &disk) {
+        eprintln!("{}: {}: {}", command, path, err);
+        return 8;
+    }
+    0
+}
+
+/// `ext2 mkfs [options] image.ext2`
+fn mkfs(args: &[String]) -> i32 {
+    match parse_format_args("mkfs", args, false) {
+        Some(args) => make_image("mkfs", &args, |_| Ok(())),
+        None => {
+            eprintln!("usage: ext2 mkfs {} image.ext2", FORMAT_OPTIONS);
+            8
+        }
+    }
+}
+
+/// `ext2 build --from dir|--tar archive.tar|--spec spec.toml [options]
+/// image.ext2`, like genext2fs
+fn build(args: &[String]) -> i32 {
+    let Some(args) = parse_format_args("build", args, true) else {
+        eprintln!(
+            "usage: ext2 build --from dir|--tar archive.tar|--spec spec.toml {} image.ext2",
+            FORMAT_OPTIONS
+        );
+        return 8;
+    };
+    match (args.from, args.tar, args.spec) {
+        (Some(source), _, _) => make_image("build", &args, |ext2| {
+            ext2.import_dir(ROOT_INODE, Path::new(source))
+        }),
+        (_, Some(archive), _) => make_image("build", &args, |ext2| {
+            let input = File::open(archive)
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", archive, err)))?;
+            ext2.read_tar(ROOT_INODE, BufReader::new(input))
+        }),
+        (_, _, Some(spec)) => make_image("build", &args, |ext2| {
+            let spec = std::fs::read_to_string(spec)
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", spec, err)))?;
+            ext2.populate(ROOT_INODE, &spec)
+        }),
+        _ => unreachable!(),
+    }
+}
+
+/// `ext2 extract image.ext2 dir [path]`
+/// Exits with 1 if some special files couldn't be created, and 8 on errors.
+fn extract(args: &[String]) -> i32 {
+    let (image, target, path) = match args {
+        [image, target] => (image, target, "/"),
+        [image, target, path] => (image, target, path.as_str()),
+        _ => {
+            eprintln!("usage: ext2 extract image.ext2 dir [path]");
+            return 8;
+        }
+    };
+    let disk = match load_image(image) {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("extract: {}: {}", image, err);
+            return 8;
+        }
+    };
+    let ext2 = match Ext2::new(&disk[..], disk.as_ptr() as usize) {
+        Ok(ext2) => ext2,
+        Err(err) => {
+            eprintln!("extract: {}: {}", image, err);
+            return 8;
+        }
+    };
+    let inode = match ext2.resolve(ROOT_INODE, path) {
+        Ok(inode) => inode,
+        Err(err) => {
+            eprintln!("extract: {}: {}", path, err);
+            return 8;
+        }
+    };
+    match ext2.export(inode, Path::new(target)) {
+        Ok(skipped) if skipped.is_empty() => 0,
+        Ok(skipped) => {
+            for (file, err) in &skipped {
+                eprintln!("extract: skipped {}: {}", file.display(), err);
+            }
+            1
+        }
+        Err(err) => {
+            eprintln!("extract: {}", err);
+            8
+        }
+    }
+}
+
+/// `ext2 tar image.ext2 archive.tar [path]`
+fn tar(args: &[String]) -> i32 {
+    let (image, archive, path) = match args {
+        [image, archive] => (image, archive, "/"),
+        [image, archive, path] => (image, archive, path.as_str()),
+        _ => {
+            eprintln!("usage: ext2 tar image.ext2 archive.tar [path]");
+            return 8;
+        }
+    };
+    let disk = match load_image(image) {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("tar: {}: {}", image, err);
+            return 8;
+        }
+    };
+    let ext2 = match Ext2::new(&disk[..], disk.as_ptr() as usize) {
+        Ok(ext2) => ext2,
+        Err(err) => {
+            eprintln!("tar: {}: {}", image, err);
+            return 8;
+        }
+    };
+    let inode = match ext2.resolve(ROOT_INODE, path) {
+        Ok(inode) => inode,
+        Err(err) => {
+            eprintln!("tar: {}: {}", path, err);
+            return 8;
+        }
+    };
+    let result = File::create(archive).and_then(|out| ext2.write_tar(inode, BufWriter::new(out)));
+    if let Err(err) = result {
+        eprintln!("tar: {}: {}", archive, err);
+        return 8;
+    }
+    0
+}
+
+/// `ext2 dumpe2fs [-h] image.ext2`
+fn dumpe2fs(args: &[String]) -> i32 {
+    let (header_only, image) = match args {
+        [image] => (false, image),
+        [flag, image] if flag == "-h" => (true, image),
+        _ => {
+            eprintln!("usage: ext2 dumpe2fs [-h] image.ext2");
+            return 8;
+        }
+    };
+    let disk = match load_image(image) {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("dumpe2fs: {}: {}", image, err);
+            return 8;
+        }
+    };
+    let ext2 = match Ext2::new(&disk[..], disk.as_ptr() as usize) {
+        Ok(ext2) => ext2,
+        Err(err) => {
+            eprintln!("dumpe2fs: {}: {}", image, err);
+            return 8;
+        }
+    };
+    let mut report = ext2.report();
+    if header_only {
+        report.groups.clear();
+    }
+    print!("{}", report);
+    0
+}
+
+/// `ext2 fuse [-w] image.ext2 dir`
+fn fuse(args: &[String]) -> i32 {
+    let (writable, image, mountpoint) = match args {
+        [image, mountpoint] => (false, image, mountpoint),
+        [flag, image, mountpoint] if flag == "-w" => (true, image, mountpoint),
+        _ => {
+            eprintln!("usage: ext2 fuse [-w] image.ext2 dir");
+            return 8;
+        }
+    };
+    if let Err(err) = crate::fuse::serve(image, mountpoint, writable) {
+        eprintln!("fuse: {}: {}", image, err);
+        return 8;
+    }
+    0
+}
@@ -1,15 +1,49 @@
 
+mod acl;
+mod alloc;
+mod attr;
+pub mod backup;
+mod dir;
+pub mod error;
+pub mod features;
+mod file;
+pub mod fsck;
+mod hostfs;
+mod htree;
+mod layout;
+pub mod mkfs;
+mod mount;
+mod namei;
+pub mod repair;
+pub mod report;
+mod spec;
 pub mod structs;
-pub use crate::ext2::structs::TypePerm;
+mod tar;
+mod xattr;
+pub use crate::ext2::acl::{Acl, AclEntry, AclKind, AclTag, ACL_EXECUTE, ACL_READ, ACL_WRITE};
+pub use crate::ext2::attr::Metadata;
+pub use crate::ext2::backup::{find_backups, CopyState, SuperblockCopy};
+pub use crate::ext2::error::Ext2Error;
+pub use crate::ext2::features::{CompatFeatures, IncompatFeatures, RoCompatFeatures};
+pub use crate::ext2::fsck::{CheckReport, Problem};
+pub use crate::ext2::mkfs::FormatOptions;
+pub use crate::ext2::mount::MountWarning;
+pub use crate::ext2::namei::ROOT_INODE;
+pub use crate::ext2::repair::{Repair, Repairs};
+pub use crate::ext2::report::{ErrorReport, GroupReport, Report};
+pub use crate::ext2::structs::{InodeFlags, TypePerm};
 
-pub use crate::ext2::structs::{BlockGroupDescriptor, DirectoryEntry, Inode, Superblock};
+use crate::ext2::backup::check_superblock;
+pub use crate::ext2::structs::{
+    BlockGroupDescriptor, DefaultMountOpts, DirectoryEntry, Inode, Superblock,
+};
 use null_terminated::NulStr;
+use std::cell::Cell;
 use std::collections::VecDeque;
 use std::fmt;
 use std::io::ErrorKind;
-use std::mem;
 use uuid::Uuid;
-use zerocopy::ByteSlice;
+use zerocopy::{ByteSlice, ByteSliceMut};
 
 #[repr(C)]
 #[derive(Debug)]
@@ -19,8 +53,17 @@ pub struct Ext2 {
     pub blocks: Vec<&'static [u8]>,
     pub block_size: usize,
     pub uuid: Uuid,
-    pub block_offset: usize, // <- our "device data" actually starts at this index'th block of the device
-                             // so we have to subtract this number before indexing blocks[]
+    /// Our "device data" actually starts at this index'th block of the device,
+    /// so we have to subtract this number before indexing blocks[]
+    pub block_offset: usize,
+    device: *mut u8,
+    /// Can change while open, when corruption is found on a filesystem
+    /// whose `errors` policy is to stop writing
+    read_only: Cell<bool>,
+    /// `Superblock::state` from before `mount`, while mounted
+    mount_state: Option<u16>,
+    /// Stands in for the clock when set, for reproducible builds
+    fixed_time: Option<u32>,
 }
 
 const EXT2_MAGIC: u16 = 0xef53;
@@ -28,106 +71,217 @@ const EXT2_START_OF_SUPERBLOCK: usize = 1024;
 const EXT2_END_OF_SUPERBLOCK: usize = 2048;
 
 impl Ext2 {
-    pub fn new<B: ByteSlice + std::fmt::Debug>(device_bytes: B, start_addr: usize) -> Ext2 {
+    /// Open an image read-only. Every method that would modify the image
+    /// returns `Ext2Error::ReadOnly`. Images that need incompat features
+    /// outside `IncompatFeatures::SUPPORTED` are refused.
+    pub fn new<B: ByteSlice + std::fmt::Debug>(
+        device_bytes: B,
+        start_addr: usize,
+    ) -> error::Result<Ext2> {
+        Self::open(
+            device_bytes.as_ptr() as *mut u8,
+            device_bytes.len(),
+            start_addr,
+            true,
+            EXT2_START_OF_SUPERBLOCK,
+        )
+    }
+
+    /// Open an image for reading and writing. Modifications are made in
+    /// place in `device_bytes`, which must outlive the returned `Ext2`.
+    ///
+    /// Images with ro_compat features outside `RoCompatFeatures::SUPPORTED`
+    /// are opened read-only instead, as `read_only` tells.
+    pub fn new_mut<B: ByteSliceMut + std::fmt::Debug>(
+        mut device_bytes: B,
+        start_addr: usize,
+    ) -> error::Result<Ext2> {
+        Self::open(
+            device_bytes.as_mut_ptr(),
+            device_bytes.len(),
+            start_addr,
+            false,
+            EXT2_START_OF_SUPERBLOCK,
+        )
+    }
+
+    fn open(
+        device: *mut u8,
+        len: usize,
+        start_addr: usize,
+        read_only: bool,
+        superblock_offset: usize,
+    ) -> error::Result<Ext2> {
+        let device_bytes = unsafe { std::slice::from_raw_parts(device as *const u8, len) };
         // https://wiki.osdev.org/Ext2#Superblock
         // parse into Ext2 struct - without copying
 
-        // the superblock goes from bytes 1024 -> 2047
-        let header_body_bytes = device_bytes.split_at(EXT2_END_OF_SUPERBLOCK);
-
-        let superblock = unsafe {
-            &*(header_body_bytes
-                .0
-                .split_at(EXT2_START_OF_SUPERBLOCK)
-                .1
-                .as_ptr() as *const Superblock)
-        };
-        assert_eq!(superblock.magic, EXT2_MAGIC);
+        // the primary superblock goes from bytes 1024 -> 2047
+        let superblock_size = EXT2_END_OF_SUPERBLOCK - EXT2_START_OF_SUPERBLOCK;
+        if superblock_offset + superblock_size > len {
+            return Err(Ext2Error::BadSuperblock("image too short for a superblock"));
+        }
+        let superblock =
+            unsafe { &*(device_bytes[superblock_offset..].as_ptr() as *const Superblock) };
+        check_superblock(superblock, len).map_err(Ext2Error::BadSuperblock)?;
         // at this point, we strongly suspect these bytes are indeed an ext2 filesystem
 
-        println!("superblock:\n{:?}", superblock);
-        println!("size of Inode struct: {}", mem::size_of::<Inode>());
+        let unsupported = superblock.incompat().bits() & !IncompatFeatures::SUPPORTED.bits();
+        if unsupported != 0 {
+            return Err(Ext2Error::UnsupportedFeatures(unsafe {
+                IncompatFeatures::from_bits_unchecked(unsupported)
+            }));
+        }
+        let read_only =
+            read_only || superblock.ro_compat().bits() & !RoCompatFeatures::SUPPORTED.bits() != 0;
 
-        let block_group_count = superblock
-            .blocks_count
+        // block groups start counting from first_data_block
+        let block_group_count = (superblock.blocks_count - superblock.first_data_block)
             .div_ceil(superblock.blocks_per_group) as usize;
 
         let block_size: usize = 1024 << superblock.log_block_size;
-        println!(
-            "there are {} block groups and block_size = {}",
-            block_group_count, block_size
-        );
-        let block_groups_rest_bytes = header_body_bytes.1.split_at(block_size);
+        // the group descriptor table starts in the block after the superblock
+        let gdt_start = (superblock_offset / block_size + 1) * block_size;
 
         let block_groups = unsafe {
             std::slice::from_raw_parts(
-                block_groups_rest_bytes.0.as_ptr() as *const BlockGroupDescriptor,
+                device_bytes[gdt_start..].as_ptr() as *const BlockGroupDescriptor,
                 block_group_count,
             )
         };
 
-        println!("block group 0: {:?}", block_groups[0]);
-
-        let blocks = unsafe {
-            std::slice::from_raw_parts(
-                block_groups_rest_bytes.1.as_ptr() as *const u8,
-                // would rather use: device_bytes.as_ptr(),
-                superblock.blocks_count as usize * block_size,
-            )
-        }
-        .chunks(block_size)
-        .collect::<Vec<_>>();
+        // block numbers count from the start of the device, whatever the
+        // block size, so blocks[] does too
+        let blocks = device_bytes[..(superblock.blocks_count as usize * block_size).min(len)]
+            .chunks(block_size)
+            .collect::<Vec<_>>();
 
         // offset_bytes = the distance in bytes between the start of ext2 fs
         // in memory and where we have marked our block arrray to have began in memory
         let offset_bytes = (blocks[0].as_ptr() as usize) - start_addr;
         let block_offset = offset_bytes / block_size;
         let uuid = Uuid::from_bytes(superblock.fs_id);
-        Ext2 {
+        Ok(Ext2 {
             superblock,
             block_groups,
             blocks,
             block_size,
             uuid,
             block_offset,
+            device,
+            read_only: Cell::new(read_only),
+            mount_state: None,
+            fixed_time: None,
+        })
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only.get()
+    }
+
+    pub(crate) fn check_writable(&self) -> error::Result<()> {
+        if self.read_only() {
+            return Err(Ext2Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Current time in POSIX time, as stored in inode and superblock
+    /// timestamps, unless a fixed time has been set
+    pub fn now(&self) -> u32 {
+        if let Some(time) = self.fixed_time {
+            return time;
         }
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0)
+    }
+
+    /// Stamp everything with `time` from now on instead of the current
+    /// time, so the same operations always produce the same image; `None`
+    /// goes back to the clock. Times copied in from elsewhere, such as a
+    /// host file's, are clamped to `time` too, as SOURCE_DATE_EPOCH asks.
+    pub fn set_fixed_time(&mut self, time: Option<u32>) {
+        self.fixed_time = time;
+    }
+
+    /// `time`, or the fixed time if that is earlier
+    pub(crate) fn clamp_time(&self, time: u32) -> u32 {
+        self.fixed_time.map_or(time, |fixed| time.min(fixed))
+    }
+
+    // The accessors below hand out mutable views of the image. They are only
+    // sound on images opened with `new_mut`, so every public method that
+    // writes calls `check_writable` first.
+
+    pub(crate) fn superblock_mut(&mut self) -> &mut Superblock {
+        debug_assert!(!self.read_only());
+        unsafe { &mut *(self.device.add(EXT2_START_OF_SUPERBLOCK) as *mut Superblock) }
+    }
+
+    pub(crate) fn group_mut(&mut self, group: usize) -> &mut BlockGroupDescriptor {
+        debug_assert!(!self.read_only());
+        assert!(group < self.block_groups.len());
+        unsafe { &mut *(self.block_groups.as_ptr().add(group) as *mut BlockGroupDescriptor) }
+    }
+
+    pub(crate) fn block(&self, block: u32) -> &[u8] {
+        self.blocks[block as usize - self.block_offset]
+    }
+
+    pub(crate) fn block_mut(&mut self, block: u32) -> &mut [u8] {
+        debug_assert!(!self.read_only());
+        let block = self.blocks[block as usize - self.block_offset];
+        unsafe { std::slice::from_raw_parts_mut(block.as_ptr() as *mut u8, block.len()) }
+    }
+
+    pub(crate) fn inode_mut(&mut self, inode: usize) -> &mut Inode {
+        debug_assert!(!self.read_only());
+        unsafe { &mut *(self.inode_ptr(inode) as *mut Inode) }
     }
 
     // given a (1-indexed) inode number, return that #'s inode structure
     pub fn get_inode(&self, inode: usize) -> &Inode {
+        unsafe { &*self.inode_ptr(inode) }
+    }
+
+    fn inode_ptr(&self, inode: usize) -> *const Inode {
         let group: usize = (inode - 1) / self.superblock.inodes_per_group as usize;
         let index: usize = (inode - 1) % self.superblock.inodes_per_group as usize;
 
-        // println!("in get_inode, inode num = {}, index = {}, group = {}, block_offset = {}", inode, index, group, self.block_offset);
-        let inode_table_block =
-            (self.block_groups[group].inode_table_block) as usize - self.block_offset;
-        // println!("in get_inode, block number of inode table {}", inode_table_block);
-        let inode_table = unsafe {
-            std::slice::from_raw_parts(
-                self.blocks[inode_table_block].as_ptr() as *const Inode,
-                self.superblock.inodes_per_group as usize,
-            )
-        };
-        // probably want a Vec of BlockGroups in our Ext structure so we don't have to slice each time,
-        // but this works for now.
-        // println!("{:?}", inode_table);
-        &inode_table[index]
+        // entries of the inode table are inode_size() bytes apart, which
+        // need not be the size of our Inode struct
+        let offset = index * self.inode_size();
+        let inode_table_block = self.block_groups[group].inode_table_block;
+        let block = self.block(inode_table_block + (offset / self.block_size) as u32);
+        unsafe { block.as_ptr().add(offset % self.block_size) as *const Inode }
     }
 
     pub fn read_dir_inode(&self, inode: usize) -> std::io::Result<Vec<(usize, &NulStr)>> {
         let mut ret = Vec::new();
         let root = self.get_inode(inode);
         // println!("in read_dir_inode, #{} : {:?}", inode, root);
-        // println!("following direct pointer to data block: {}", root.direct_pointer[0]);
-        let entry_ptr = self.blocks[root.direct_pointer[0] as usize - self.block_offset].as_ptr();
-        let mut byte_offset: isize = 0;
-        // println!("root.size_low = {}", root.size_low);
-        while byte_offset < root.size_low as isize {
-            // <- todo, support large directories
-            let directory = unsafe { &*(entry_ptr.offset(byte_offset) as *const DirectoryEntry) };
-            // println!("{:?}", directory);
-            byte_offset += directory.entry_size as isize;
-            ret.push((directory.inode as usize, &directory.name));
+        for index in 0..root.size_low as usize / self.block_size {
+            let block = self.block_of(root, index);
+            if block == 0 {
+                continue;
+            }
+            let entry_ptr = self.block(block).as_ptr();
+            let mut byte_offset: isize = 0;
+            while byte_offset < self.block_size as isize {
+                let directory =
+                    unsafe { &*(entry_ptr.offset(byte_offset) as *const DirectoryEntry) };
+                // println!("{:?}", directory);
+                if directory.entry_size == 0 {
+                    break;
+                }
+                byte_offset += directory.entry_size as isize;
+                // unused entries (e.g. a deleted first entry of a block) have inode 0
+                if directory.inode != 0 {
+                    ret.push((directory.inode as usize, &directory.name));
+                }
+            }
         }
         Ok(ret)
     }
@@ -0,0 +1,449 @@
+// POSIX access control lists: permissions for named users and groups on
+// top of the owner/group/other bits of the mode, kept in the
+// `system.posix_acl_access` extended attribute. Directories can also have
+// a default ACL (`system.posix_acl_default`) that what is created in them
+// inherits.
+// https://man7.org/linux/man-pages/man5/acl.5.html
+
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::structs::TypePerm;
+use crate::ext2::Ext2;
+
+/// Version word of an ACL as ext2 stores it
+const DISK_VERSION: u32 = 1;
+/// Version word of an ACL as getxattr(2) returns it
+const XATTR_VERSION: u32 = 2;
+/// ID of the entries that don't name a user or group, in the xattr format
+const UNDEFINED_ID: u32 = u32::MAX;
+
+const TAG_USER_OBJ: u16 = 0x01;
+const TAG_USER: u16 = 0x02;
+const TAG_GROUP_OBJ: u16 = 0x04;
+const TAG_GROUP: u16 = 0x08;
+const TAG_MASK: u16 = 0x10;
+const TAG_OTHER: u16 = 0x20;
+
+/// Permission bits of an entry, and of the mask `Ext2::access` takes
+pub const ACL_READ: u16 = 4;
+pub const ACL_WRITE: u16 = 2;
+pub const ACL_EXECUTE: u16 = 1;
+const ACL_RWX: u16 = ACL_READ | ACL_WRITE | ACL_EXECUTE;
+
+/// Which of its ACLs an inode is asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclKind {
+    /// The ACL checked on access to the inode itself
+    Access,
+    /// The ACL a directory passes on to what is created in it
+    Default,
+}
+
+impl AclKind {
+    /// The extended attribute this ACL is kept in
+    pub fn xattr_name(&self) -> &'static str {
+        match self {
+            AclKind::Access => "system.posix_acl_access",
+            AclKind::Default => "system.posix_acl_default",
+        }
+    }
+
+    /// The kind of ACL kept in the extended attribute `name`, if any
+    pub fn from_xattr_name(name: &str) -> Option<AclKind> {
+        [AclKind::Access, AclKind::Default]
+            .into_iter()
+            .find(|kind| kind.xattr_name() == name)
+    }
+}
+
+/// Whom an ACL entry applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AclTag {
+    /// The owner of the file
+    UserObj,
+    /// The user with this ID
+    User(u32),
+    /// The group of the file
+    GroupObj,
+    /// The group with this ID
+    Group(u32),
+    /// The most the named entries and `GroupObj` may grant
+    Mask,
+    /// Everyone else
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    /// `ACL_READ`, `ACL_WRITE` and `ACL_EXECUTE` bits
+    pub perm: u16,
+}
+
+/// An access control list, with its entries in the order `AclTag` sorts
+/// them, as they are stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    pub entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// The ACL equivalent to the permission bits of `mode`
+    pub fn from_mode(mode: u16) -> Acl {
+        Acl {
+            entries: vec![
+                AclEntry {
+                    tag: AclTag::UserObj,
+                    perm: (mode >> 6) & ACL_RWX,
+                },
+                AclEntry {
+                    tag: AclTag::GroupObj,
+                    perm: (mode >> 3) & ACL_RWX,
+                },
+                AclEntry {
+                    tag: AclTag::Other,
+                    perm: mode & ACL_RWX,
+                },
+            ],
+        }
+    }
+
+    /// Decode an ACL in the format ext2 stores it in: a version word, then
+    /// entries of a tag and permissions, followed by an ID for named users
+    /// and groups
+    pub fn decode(bytes: &[u8]) -> Result<Acl> {
+        let bad = Ext2Error::Corrupt("bad ACL");
+        if bytes.len() < 4 || u32::from_le_bytes(bytes[..4].try_into().unwrap()) != DISK_VERSION {
+            return Err(bad);
+        }
+        let mut entries = Vec::new();
+        let mut rest = &bytes[4..];
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return Err(bad);
+            }
+            let tag = u16::from_le_bytes([rest[0], rest[1]]);
+            let perm = u16::from_le_bytes([rest[2], rest[3]]);
+            let named = matches!(tag, TAG_USER | TAG_GROUP);
+            let id = if named {
+                if rest.len() < 8 {
+                    return Err(bad);
+                }
+                let id = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+                rest = &rest[8..];
+                id
+            } else {
+                rest = &rest[4..];
+                UNDEFINED_ID
+            };
+            entries.push(AclEntry {
+                tag: decode_tag(tag, id).ok_or(bad.clone())?,
+                perm,
+            });
+        }
+        let acl = Acl { entries };
+        if !acl.is_valid() {
+            return Err(bad);
+        }
+        Ok(acl)
+    }
+
+    /// The ACL in the format `decode` reads
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = DISK_VERSION.to_le_bytes().to_vec();
+        for entry in &self.entries {
+            let (tag, id) = encode_tag(entry.tag);
+            bytes.extend_from_slice(&tag.to_le_bytes());
+            bytes.extend_from_slice(&entry.perm.to_le_bytes());
+            if let AclTag::User(_) | AclTag::Group(_) = entry.tag {
+                bytes.extend_from_slice(&id.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Decode an ACL in the format getxattr(2) and setxattr(2) use, where
+    /// every entry has an ID
+    pub fn from_xattr(bytes: &[u8]) -> Result<Acl> {
+        let bad = Ext2Error::InvalidArgument("bad ACL");
+        if bytes.len() < 4
+            || !(bytes.len() - 4).is_multiple_of(8)
+            || u32::from_le_bytes(bytes[..4].try_into().unwrap()) != XATTR_VERSION
+        {
+            return Err(bad);
+        }
+        let mut entries = Vec::new();
+        for entry in bytes[4..].chunks(8) {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let perm = u16::from_le_bytes([entry[2], entry[3]]);
+            let id = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+            entries.push(AclEntry {
+                tag: decode_tag(tag, id).ok_or(bad.clone())?,
+                perm,
+            });
+        }
+        let acl = Acl { entries };
+        if !acl.is_valid() {
+            return Err(bad);
+        }
+        Ok(acl)
+    }
+
+    /// The ACL in the format `from_xattr` reads
+    pub fn to_xattr(&self) -> Vec<u8> {
+        let mut bytes = XATTR_VERSION.to_le_bytes().to_vec();
+        for entry in &self.entries {
+            let (tag, id) = encode_tag(entry.tag);
+            bytes.extend_from_slice(&tag.to_le_bytes());
+            bytes.extend_from_slice(&entry.perm.to_le_bytes());
+            bytes.extend_from_slice(&id.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Whether the ACL is one the kernel accepts: its entries in order,
+    /// one each of `UserObj`, `GroupObj` and `Other`, no user or group
+    /// named twice, and a `Mask` if anyone is named
+    pub fn is_valid(&self) -> bool {
+        let count = |wanted: fn(&AclTag) -> bool| {
+            self.entries
+                .iter()
+                .filter(|entry| wanted(&entry.tag))
+                .count()
+        };
+        let named = count(|tag| matches!(tag, AclTag::User(_) | AclTag::Group(_)));
+        let masks = count(|tag| *tag == AclTag::Mask);
+        self.entries
+            .windows(2)
+            .all(|pair| pair[0].tag < pair[1].tag)
+            && self.entries.iter().all(|entry| entry.perm & !ACL_RWX == 0)
+            && count(|tag| *tag == AclTag::UserObj) == 1
+            && count(|tag| *tag == AclTag::GroupObj) == 1
+            && count(|tag| *tag == AclTag::Other) == 1
+            && masks <= 1
+            && (named == 0 || masks == 1)
+    }
+
+    /// The permission bits of the mode that go with this ACL, and whether
+    /// those say all it does, so that it needn't be stored
+    pub fn mode(&self) -> (u16, bool) {
+        let mut mode = 0;
+        let mut equivalent = true;
+        for entry in &self.entries {
+            match entry.tag {
+                AclTag::UserObj => mode |= entry.perm << 6,
+                AclTag::GroupObj => mode |= entry.perm << 3,
+                AclTag::Other => mode |= entry.perm,
+                AclTag::User(_) | AclTag::Group(_) | AclTag::Mask => equivalent = false,
+            }
+        }
+        // with a mask, the group bits show the mask instead
+        if let Some(mask) = self.find(AclTag::Mask) {
+            mode = (mode & !0o070) | mask.perm << 3;
+        }
+        (mode, equivalent)
+    }
+
+    /// Change the entries the permission bits of `mode` stand for, as
+    /// chmod(2) does
+    pub fn chmod(&mut self, mode: u16) {
+        let has_mask = self.find(AclTag::Mask).is_some();
+        for entry in &mut self.entries {
+            match entry.tag {
+                AclTag::UserObj => entry.perm = (mode >> 6) & ACL_RWX,
+                AclTag::Mask => entry.perm = (mode >> 3) & ACL_RWX,
+                AclTag::GroupObj if !has_mask => entry.perm = (mode >> 3) & ACL_RWX,
+                AclTag::Other => entry.perm = mode & ACL_RWX,
+                _ => {}
+            }
+        }
+    }
+
+    /// Restrict the ACL inherited by a new inode to the permission bits
+    /// of `mode` it was created with, and return those bits restricted to
+    /// what the ACL grants
+    fn create_mask(&mut self, mode: u16) -> u16 {
+        let mut mode = mode & 0o777;
+        let has_mask = self.find(AclTag::Mask).is_some();
+        for entry in &mut self.entries {
+            let shift = match entry.tag {
+                AclTag::UserObj => 6,
+                AclTag::Mask => 3,
+                AclTag::GroupObj if !has_mask => 3,
+                AclTag::Other => 0,
+                _ => continue,
+            };
+            entry.perm &= (mode >> shift) & ACL_RWX;
+            mode &= !(ACL_RWX << shift) | entry.perm << shift;
+        }
+        mode
+    }
+
+    fn find(&self, tag: AclTag) -> Option<&AclEntry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    /// Whether the ACL grants all the `ACL_*` bits of `mask` to a user
+    /// `uid` in groups `gids`, on a file owned by `owner` and `group`
+    fn permits(&self, owner: u32, group: u32, uid: u32, gids: &[u32], mask: u16) -> bool {
+        let limit = self.find(AclTag::Mask).map_or(ACL_RWX, |entry| entry.perm);
+        let grants = |perm: u16| perm & mask == mask;
+        if uid == owner {
+            return self
+                .find(AclTag::UserObj)
+                .is_some_and(|entry| grants(entry.perm));
+        }
+        if let Some(entry) = self.find(AclTag::User(uid)) {
+            return grants(entry.perm & limit);
+        }
+        // any of the groups the user is in may grant it, but being in one
+        // rules out falling back to `Other`
+        let mut in_group = false;
+        for entry in &self.entries {
+            let id = match entry.tag {
+                AclTag::GroupObj => group,
+                AclTag::Group(id) => id,
+                _ => continue,
+            };
+            if gids.contains(&id) {
+                in_group = true;
+                if grants(entry.perm & limit) {
+                    return true;
+                }
+            }
+        }
+        !in_group
+            && self
+                .find(AclTag::Other)
+                .is_some_and(|entry| grants(entry.perm))
+    }
+}
+
+fn decode_tag(tag: u16, id: u32) -> Option<AclTag> {
+    Some(match tag {
+        TAG_USER_OBJ => AclTag::UserObj,
+        TAG_USER => AclTag::User(id),
+        TAG_GROUP_OBJ => AclTag::GroupObj,
+        TAG_GROUP => AclTag::Group(id),
+        TAG_MASK => AclTag::Mask,
+        TAG_OTHER => AclTag::Other,
+        _ => return None,
+    })
+}
+
+fn encode_tag(tag: AclTag) -> (u16, u32) {
+    match tag {
+        AclTag::UserObj => (TAG_USER_OBJ, UNDEFINED_ID),
+        AclTag::User(id) => (TAG_USER, id),
+        AclTag::GroupObj => (TAG_GROUP_OBJ, UNDEFINED_ID),
+        AclTag::Group(id) => (TAG_GROUP, id),
+        AclTag::Mask => (TAG_MASK, UNDEFINED_ID),
+        AclTag::Other => (TAG_OTHER, UNDEFINED_ID),
+    }
+}
+
+impl Ext2 {
+    /// The `kind` ACL of `inode`, or `None` if it has none
+    pub fn get_acl(&self, inode: usize, kind: AclKind) -> Result<Option<Acl>> {
+        match self.get_xattr(inode, kind.xattr_name()) {
+            Ok(bytes) => match Acl::decode(&bytes) {
+                Ok(acl) => Ok(Some(acl)),
+                Err(err) => {
+                    self.fs_error("get_acl", inode, 0);
+                    Err(err)
+                }
+            },
+            Err(Ext2Error::NoAttribute) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Set the `kind` ACL of `inode` to `acl`, or remove it with `None`.
+    /// An access ACL also sets the permission bits of the mode, and isn't
+    /// stored if they say all it does. Only directories have a default
+    /// ACL.
+    pub fn set_acl(&mut self, inode: usize, kind: AclKind, acl: Option<&Acl>) -> Result<()> {
+        self.check_writable()?;
+        self.check_mutable(inode)?;
+        if acl.is_some_and(|acl| !acl.is_valid()) {
+            return Err(Ext2Error::InvalidArgument("bad ACL"));
+        }
+        let mut acl = acl;
+        match kind {
+            AclKind::Access => {
+                if let Some(set) = acl {
+                    let (mode, equivalent) = set.mode();
+                    let file = self.inode_mut(inode);
+                    file.type_perm =
+                        TypePerm::from_bits_truncate(file.type_perm.bits() & !0o777 | mode);
+                    if equivalent {
+                        acl = None;
+                    }
+                }
+            }
+            AclKind::Default => {
+                if !self.get_inode(inode).type_perm.is_dir() {
+                    return Err(match acl {
+                        Some(_) => Ext2Error::NotADirectory,
+                        None => Ext2Error::NoAttribute,
+                    });
+                }
+            }
+        }
+        match acl {
+            Some(acl) => self.set_xattr(inode, kind.xattr_name(), &acl.encode()),
+            None => match self.remove_xattr(inode, kind.xattr_name()) {
+                Err(Ext2Error::NoAttribute) => {
+                    let now = self.now();
+                    self.inode_mut(inode).ctime = now;
+                    Ok(())
+                }
+                result => result,
+            },
+        }
+    }
+
+    /// Give `inode`, just created in `parent`, the ACLs it inherits from
+    /// the default ACL of `parent`: that ACL restricted to the mode it was
+    /// created with, and the default ACL itself for a directory. The mode
+    /// is restricted to what the ACL grants in turn.
+    pub(crate) fn inherit_acl(&mut self, parent: usize, inode: usize) -> Result<()> {
+        let type_perm = self.get_inode(inode).type_perm;
+        if type_perm.is_symlink() {
+            return Ok(());
+        }
+        let default = match self.get_acl(parent, AclKind::Default)? {
+            Some(default) => default,
+            None => return Ok(()),
+        };
+        if type_perm.is_dir() {
+            self.set_xattr(inode, AclKind::Default.xattr_name(), &default.encode())?;
+        }
+        let mut access = default;
+        let mode = access.create_mask(type_perm.bits());
+        let file = self.inode_mut(inode);
+        file.type_perm = TypePerm::from_bits_truncate(file.type_perm.bits() & !0o777 | mode);
+        if !access.mode().1 {
+            self.set_xattr(inode, AclKind::Access.xattr_name(), &access.encode())?;
+        }
+        Ok(())
+    }
+
+    /// Whether a user `uid` in groups `gids` (the primary one included)
+    /// may access `inode` in the ways of `mask`: any of `ACL_READ`,
+    /// `ACL_WRITE` and `ACL_EXECUTE`. The access ACL decides if the inode
+    /// has one, the permission bits otherwise. Root may do anything but
+    /// execute a file nobody may execute.
+    pub fn access(&self, inode: usize, uid: u32, gids: &[u32], mask: u16) -> Result<bool> {
+        let file = self.get_inode(inode);
+        let mode = file.type_perm.bits();
+        let mask = mask & ACL_RWX;
+        if uid == 0 {
+            return Ok(mask & ACL_EXECUTE == 0 || file.type_perm.is_dir() || mode & 0o111 != 0);
+        }
+        let acl = match self.get_acl(inode, AclKind::Access)? {
+            Some(acl) => acl,
+            None => Acl::from_mode(mode),
+        };
+        Ok(acl.permits(file.uid(), file.gid(), uid, gids, mask))
+    }
+}
@@ -0,0 +1,152 @@
+// Block and inode allocation via the usage bitmaps of each block group.
+// https://wiki.osdev.org/Ext2#Block_Usage_Bitmap
+
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::Ext2;
+
+impl Ext2 {
+    /// Block group that holds the given (1-indexed) inode
+    pub(crate) fn inode_group(&self, inode: usize) -> usize {
+        (inode - 1) / self.superblock.inodes_per_group as usize
+    }
+
+    /// Block group that holds the given block
+    pub(crate) fn block_group(&self, block: u32) -> usize {
+        ((block - self.superblock.first_data_block) / self.superblock.blocks_per_group) as usize
+    }
+
+    /// The last group is usually shorter than `blocks_per_group`
+    pub(crate) fn blocks_in_group(&self, group: usize) -> usize {
+        let first = self.superblock.first_data_block as usize
+            + group * self.superblock.blocks_per_group as usize;
+        (self.superblock.blocks_count as usize - first)
+            .min(self.superblock.blocks_per_group as usize)
+    }
+
+    /// Mark a free block as used, preferring `goal_group`, and return it.
+    /// The block's contents are left as they were.
+    pub(crate) fn alloc_block(&mut self, goal_group: usize) -> Result<u32> {
+        let group_count = self.block_groups.len();
+        for i in 0..group_count {
+            let group = (goal_group + i) % group_count;
+            if self.block_groups[group].free_blocks_count == 0 {
+                continue;
+            }
+            let bitmap_block = self.block_groups[group].block_usage_addr;
+            let limit = self.blocks_in_group(group);
+            if let Some(bit) = find_clear_bit(self.block(bitmap_block), limit) {
+                set_bit(self.block_mut(bitmap_block), bit, true);
+                self.group_mut(group).free_blocks_count -= 1;
+                self.superblock_mut().free_blocks_count -= 1;
+                return Ok(self.superblock.first_data_block
+                    + (group * self.superblock.blocks_per_group as usize + bit) as u32);
+            }
+        }
+        Err(Ext2Error::NoSpace)
+    }
+
+    pub(crate) fn free_block(&mut self, block: u32) {
+        let group = self.block_group(block);
+        let bit = ((block - self.superblock.first_data_block) % self.superblock.blocks_per_group)
+            as usize;
+        let bitmap_block = self.block_groups[group].block_usage_addr;
+        if set_bit(self.block_mut(bitmap_block), bit, false) {
+            self.group_mut(group).free_blocks_count += 1;
+            self.superblock_mut().free_blocks_count += 1;
+        }
+    }
+
+    /// Mark a free inode as used, preferring `goal_group`, zero it and
+    /// return its number.
+    pub(crate) fn alloc_inode(&mut self, goal_group: usize, is_dir: bool) -> Result<usize> {
+        let group_count = self.block_groups.len();
+        let inodes_per_group = self.superblock.inodes_per_group as usize;
+        for i in 0..group_count {
+            let group = (goal_group + i) % group_count;
+            if self.block_groups[group].free_inodes_count == 0 {
+                continue;
+            }
+            let bitmap_block = self.block_groups[group].inode_usage_addr;
+            let mut bitmap = self.block(bitmap_block).to_vec();
+            // inodes below first_inode are reserved, even if their bit is clear
+            let reserved = self
+                .first_inode()
+                .saturating_sub(group * inodes_per_group + 1);
+            for bit in 0..reserved.min(inodes_per_group) {
+                set_bit(&mut bitmap, bit, true);
+            }
+            if let Some(bit) = find_clear_bit(&bitmap, inodes_per_group) {
+                set_bit(self.block_mut(bitmap_block), bit, true);
+                let group_desc = self.group_mut(group);
+                group_desc.free_inodes_count -= 1;
+                if is_dir {
+                    group_desc.dirs_count += 1;
+                }
+                self.superblock_mut().free_inodes_count -= 1;
+
+                let inode = group * inodes_per_group + bit + 1;
+                let inode_size = self.inode_size();
+                unsafe {
+                    std::ptr::write_bytes(
+                        self.inode_mut(inode) as *mut _ as *mut u8,
+                        0,
+                        inode_size,
+                    );
+                }
+                return Ok(inode);
+            }
+        }
+        Err(Ext2Error::NoSpace)
+    }
+
+    pub(crate) fn free_inode(&mut self, inode: usize, is_dir: bool) {
+        let group = self.inode_group(inode);
+        let bit = (inode - 1) % self.superblock.inodes_per_group as usize;
+        let bitmap_block = self.block_groups[group].inode_usage_addr;
+        if set_bit(self.block_mut(bitmap_block), bit, false) {
+            let group_desc = self.group_mut(group);
+            group_desc.free_inodes_count += 1;
+            if is_dir {
+                group_desc.dirs_count = group_desc.dirs_count.saturating_sub(1);
+            }
+            self.superblock_mut().free_inodes_count += 1;
+        }
+    }
+
+    /// First inode number not reserved for the filesystem itself
+    pub(crate) fn first_inode(&self) -> usize {
+        if self.superblock.is_dynamic() {
+            self.superblock.first_inode as usize
+        } else {
+            11
+        }
+    }
+
+    /// Size in bytes of one entry in the inode table
+    pub(crate) fn inode_size(&self) -> usize {
+        if self.superblock.is_dynamic() {
+            self.superblock.inode_size as usize
+        } else {
+            128
+        }
+    }
+}
+
+pub(crate) fn get_bit(bitmap: &[u8], bit: usize) -> bool {
+    bitmap[bit / 8] & (1 << (bit % 8)) != 0
+}
+
+/// Set or clear a bit, returning whether it changed
+pub(crate) fn set_bit(bitmap: &mut [u8], bit: usize, value: bool) -> bool {
+    let was = get_bit(bitmap, bit);
+    if value {
+        bitmap[bit / 8] |= 1 << (bit % 8);
+    } else {
+        bitmap[bit / 8] &= !(1 << (bit % 8));
+    }
+    was != value
+}
+
+fn find_clear_bit(bitmap: &[u8], limit: usize) -> Option<usize> {
+    (0..limit).find(|&bit| !get_bit(bitmap, bit))
+}
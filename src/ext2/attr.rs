@@ -0,0 +1,267 @@
+// Changing the attributes kept in an inode: permissions, ownership,
+// timestamps and flags, and the checks the immutable and append-only flags
+// make on changes.
+
+use crate::ext2::acl::AclKind;
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::namei::decode_device;
+use crate::ext2::structs::{InodeFlags, TypePerm};
+use crate::ext2::Ext2;
+
+/// The attributes of an inode, decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Inode number
+    pub inode: usize,
+    /// File type and permissions
+    pub type_perm: TypePerm,
+    /// Size in bytes
+    pub size: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub hard_links: u16,
+    /// Disk sectors (not blocks) in use
+    pub sectors: u32,
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+    /// (major, minor) device number of character and block devices
+    pub rdev: Option<(u32, u32)>,
+}
+
+impl Ext2 {
+    pub fn metadata(&self, inode: usize) -> Metadata {
+        let file = self.get_inode(inode);
+        let rdev = match file.type_perm.file_type() {
+            TypePerm::CHAR_DEVICE | TypePerm::BLOCK_DEVICE => {
+                Some(decode_device(&file.direct_pointer))
+            }
+            _ => None,
+        };
+        Metadata {
+            inode,
+            type_perm: file.type_perm,
+            size: file.size(),
+            uid: file.uid(),
+            gid: file.gid(),
+            hard_links: file.hard_links,
+            sectors: file.sectors_count,
+            atime: file.atime,
+            mtime: file.mtime,
+            ctime: file.ctime,
+            rdev,
+        }
+    }
+
+    /// Replace the permission, set-ID and sticky bits of `inode` with those
+    /// of `mode`. The file-type nibble of the inode is kept as it is, and
+    /// an access ACL is changed to match.
+    pub fn chmod(&mut self, inode: usize, mode: u16) -> Result<()> {
+        self.check_writable()?;
+        self.check_mutable(inode)?;
+        let now = self.now();
+        let file = self.inode_mut(inode);
+        let file_type = file.type_perm.bits() & TypePerm::TYPE_MASK;
+        file.type_perm = TypePerm::from_bits_truncate(file_type | (mode & !TypePerm::TYPE_MASK));
+        file.ctime = now;
+        if let Some(mut acl) = self.get_acl(inode, AclKind::Access)? {
+            acl.chmod(mode);
+            self.set_xattr(inode, AclKind::Access.xattr_name(), &acl.encode())?;
+        }
+        Ok(())
+    }
+
+    /// Change the owner and/or group of `inode`; `None` leaves that ID as is
+    pub fn chown(&mut self, inode: usize, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        self.check_writable()?;
+        self.check_mutable(inode)?;
+        let now = self.now();
+        let file = self.inode_mut(inode);
+        if let Some(uid) = uid {
+            file.set_uid(uid);
+        }
+        if let Some(gid) = gid {
+            file.set_gid(gid);
+        }
+        file.ctime = now;
+        Ok(())
+    }
+
+    /// Set the access and modification times of `inode` (in POSIX time)
+    pub fn utimes(&mut self, inode: usize, atime: u32, mtime: u32) -> Result<()> {
+        self.check_writable()?;
+        self.check_mutable(inode)?;
+        let now = self.now();
+        let file = self.inode_mut(inode);
+        file.atime = atime;
+        file.mtime = mtime;
+        file.ctime = now;
+        Ok(())
+    }
+
+    /// Set the access time, the modification time or both of `inode` to
+    /// now, as `touch` does. Unlike any other time, this is allowed on an
+    /// append-only inode: Linux only asks to be able to write to the file.
+    pub fn touch(&mut self, inode: usize, atime: bool, mtime: bool) -> Result<()> {
+        self.check_writable()?;
+        self.check_not_immutable(inode)?;
+        let now = self.now();
+        let file = self.inode_mut(inode);
+        if atime {
+            file.atime = now;
+        }
+        if mtime {
+            file.mtime = now;
+        }
+        file.ctime = now;
+        Ok(())
+    }
+
+    /// Set the flags of `inode` to `flags`. Only those in
+    /// `InodeFlags::USER_MODIFIABLE` may differ from the ones it has; the
+    /// rest describe how the inode is stored. This is how the immutable
+    /// flag is cleared, so it doesn't stop it.
+    pub fn set_flags(&mut self, inode: usize, flags: InodeFlags) -> Result<()> {
+        self.check_writable()?;
+        let file = self.get_inode(inode);
+        if !((file.flags ^ flags) - InodeFlags::USER_MODIFIABLE).is_empty() {
+            return Err(Ext2Error::InvalidArgument("flag cannot be changed"));
+        }
+        let now = self.now();
+        let file = self.inode_mut(inode);
+        file.flags = flags;
+        file.ctime = now;
+        Ok(())
+    }
+
+    /// Fail if `inode` is immutable, which forbids changing it in any way
+    pub(crate) fn check_not_immutable(&self, inode: usize) -> Result<()> {
+        if self.get_inode(inode).flags.contains(InodeFlags::IMMUTABLE) {
+            return Err(Ext2Error::NotPermitted("inode is immutable"));
+        }
+        Ok(())
+    }
+
+    /// Fail if `inode` is immutable or append-only, which both forbid
+    /// changing its attributes, truncating it, and linking or unlinking it
+    pub(crate) fn check_mutable(&self, inode: usize) -> Result<()> {
+        self.check_not_immutable(inode)?;
+        if self.get_inode(inode).flags.contains(InodeFlags::APPEND) {
+            return Err(Ext2Error::NotPermitted("inode is append-only"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext2::acl::Acl;
+    use crate::ext2::features::CompatFeatures;
+    use crate::ext2::mkfs::FormatOptions;
+    use crate::ext2::namei::ROOT_INODE;
+
+    /// A fresh image with a directory /d holding a file `a` with an
+    /// attribute, and a file /f with some data and an attribute
+    fn image(device: &mut [u8]) -> Ext2 {
+        let options = FormatOptions {
+            features_opt: CompatFeatures::EXT_ATTR,
+            ..Default::default()
+        };
+        let mut ext2 = Ext2::format(device, &options).unwrap();
+        let d = ext2.mkdir(ROOT_INODE, "d", 0o755).unwrap();
+        let a = ext2.create(d, "a", 0o644).unwrap();
+        let f = ext2.create(ROOT_INODE, "f", 0o644).unwrap();
+        ext2.write(f, 0, b"data").unwrap();
+        for inode in [a, f] {
+            ext2.set_xattr(inode, "user.x", b"1").unwrap();
+        }
+        ext2
+    }
+
+    fn not_permitted<T: std::fmt::Debug>(result: Result<T>) {
+        assert!(
+            matches!(result, Err(Ext2Error::NotPermitted(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    /// Every byte of the image, to see that nothing was changed
+    fn device_copy(ext2: &Ext2) -> Vec<u8> {
+        (0..ext2.superblock.blocks_count)
+            .flat_map(|block| ext2.block(block).to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn immutable_refuses_every_change() {
+        let mut device = vec![0u8; 4 << 20];
+        let mut ext2 = image(&mut device);
+        let d = ext2.lookup(ROOT_INODE, "d").unwrap();
+        let f = ext2.lookup(ROOT_INODE, "f").unwrap();
+        for inode in [d, f] {
+            let flags = ext2.get_inode(inode).flags | InodeFlags::IMMUTABLE;
+            ext2.set_flags(inode, flags).unwrap();
+        }
+        let before = device_copy(&ext2);
+
+        // the inode itself
+        not_permitted(ext2.chmod(f, 0o600));
+        not_permitted(ext2.chown(f, Some(1), Some(1)));
+        not_permitted(ext2.utimes(f, 1, 1));
+        not_permitted(ext2.touch(f, true, true));
+        not_permitted(ext2.truncate(f, 0));
+        not_permitted(ext2.write(f, 4, b"more"));
+        not_permitted(ext2.set_xattr(f, "user.y", b"2"));
+        not_permitted(ext2.remove_xattr(f, "user.x"));
+        let acl = Acl::from_mode(0o600);
+        not_permitted(ext2.set_acl(f, AclKind::Access, Some(&acl)));
+        not_permitted(ext2.link(f, ROOT_INODE, "g"));
+        not_permitted(ext2.unlink(ROOT_INODE, "f"));
+        not_permitted(ext2.rename(ROOT_INODE, "f", ROOT_INODE, "g"));
+
+        // entries in an immutable directory
+        not_permitted(ext2.create(d, "b", 0o644));
+        not_permitted(ext2.mkdir(d, "b", 0o755));
+        not_permitted(ext2.symlink(d, "b", "a"));
+        not_permitted(ext2.mknod(d, "b", TypePerm::FIFO, 0, 0));
+        not_permitted(ext2.link(f, d, "b"));
+        not_permitted(ext2.unlink(d, "a"));
+        not_permitted(ext2.rename(d, "a", ROOT_INODE, "b"));
+        not_permitted(ext2.rmdir(ROOT_INODE, "d"));
+        assert!(device_copy(&ext2) == before);
+
+        // clearing the flag is what makes it changeable again
+        let flags = ext2.get_inode(f).flags - InodeFlags::IMMUTABLE;
+        ext2.set_flags(f, flags).unwrap();
+        ext2.chmod(f, 0o600).unwrap();
+    }
+
+    #[test]
+    fn append_only_times() {
+        let mut device = vec![0u8; 4 << 20];
+        let mut ext2 = image(&mut device);
+        let f = ext2.lookup(ROOT_INODE, "f").unwrap();
+        let flags = ext2.get_inode(f).flags | InodeFlags::APPEND;
+        ext2.set_flags(f, flags).unwrap();
+        ext2.set_fixed_time(Some(1000));
+        not_permitted(ext2.utimes(f, 1, 1));
+        ext2.touch(f, false, true).unwrap();
+        let metadata = ext2.metadata(f);
+        assert_eq!(metadata.mtime, 1000);
+        assert_ne!(metadata.atime, 1000);
+    }
+
+    #[test]
+    fn set_flags_only_changes_user_modifiable() {
+        let mut device = vec![0u8; 4 << 20];
+        let mut ext2 = image(&mut device);
+        let f = ext2.lookup(ROOT_INODE, "f").unwrap();
+        let flags = ext2.get_inode(f).flags;
+        assert!(ext2.set_flags(f, flags | InodeFlags::INDEX).is_err());
+        assert!(ext2.set_flags(f, flags | InodeFlags::NODUMP).is_ok());
+        assert!(ext2.set_flags(f, InodeFlags::empty()).is_ok());
+        assert_eq!(ext2.get_inode(f).flags, InodeFlags::empty());
+    }
+}
@@ -0,0 +1,283 @@
+// Backup copies of the superblock and group descriptor table. Groups 0 and
+// 1 and the powers of 3, 5 and 7 start with one under sparse_super, every
+// group otherwise, so a damaged primary in group 0 can be recovered from
+// any of the others, as `e2fsck -b` does.
+// https://www.nongnu.org/ext2-doc/ext2.html#superblock
+
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::features::RoCompatFeatures;
+use crate::ext2::layout::sparse_group;
+use crate::ext2::structs::{BlockGroupDescriptor, Superblock};
+use crate::ext2::{Ext2, EXT2_MAGIC, EXT2_START_OF_SUPERBLOCK};
+use zerocopy::ByteSlice;
+
+/// Where one copy of the superblock lives. `block` counts in units of
+/// `block_size`, like the block `e2fsck -b` takes; the primary copy in group
+/// 0 is 1024 bytes into the image whatever the block size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SuperblockCopy {
+    pub group: usize,
+    pub block: u32,
+    pub block_size: usize,
+}
+
+impl SuperblockCopy {
+    /// Byte offset of the copy in the image
+    pub(crate) fn offset(&self) -> usize {
+        if self.group == 0 {
+            EXT2_START_OF_SUPERBLOCK
+        } else {
+            self.block as usize * self.block_size
+        }
+    }
+}
+
+/// How a backup compares with the superblock the image was opened with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyState {
+    Matches,
+    /// The backup isn't a usable superblock at all
+    Invalid(&'static str),
+    /// The backup describes a different filesystem; this names the first
+    /// field that differs
+    Differs(&'static str),
+}
+
+/// Check that `superblock` describes a filesystem that fits in `len`
+/// bytes, with geometry this implementation can lay out
+pub(crate) fn check_superblock(
+    superblock: &Superblock,
+    len: usize,
+) -> std::result::Result<(), &'static str> {
+    if superblock.magic != EXT2_MAGIC {
+        return Err("bad magic number");
+    }
+    // 64 KiB blocks are the largest any implementation creates
+    if superblock.log_block_size > 6 {
+        return Err("block size out of range");
+    }
+    let block_size = 1024usize << superblock.log_block_size;
+    let first_data_block = if block_size == 1024 { 1 } else { 0 };
+    if superblock.first_data_block != first_data_block {
+        return Err("first data block out of range");
+    }
+    if superblock.blocks_count <= first_data_block {
+        return Err("block count out of range");
+    }
+    let bits = 8 * block_size as u32;
+    if superblock.blocks_per_group == 0 || superblock.blocks_per_group > bits {
+        return Err("blocks per group out of range");
+    }
+    if superblock.inodes_per_group == 0 || superblock.inodes_per_group > bits {
+        return Err("inodes per group out of range");
+    }
+    let groups = (superblock.blocks_count - first_data_block).div_ceil(superblock.blocks_per_group);
+    if superblock.inodes_count as u64 != superblock.inodes_per_group as u64 * groups as u64 {
+        return Err("inode count doesn't match the groups");
+    }
+    if superblock.is_dynamic() {
+        let inode_size = superblock.inode_size as usize;
+        if !inode_size.is_power_of_two() || inode_size < 128 || inode_size > block_size {
+            return Err("inode size out of range");
+        }
+        if superblock.first_inode < 11 {
+            return Err("first inode out of range");
+        }
+    }
+    let gdt_end = (first_data_block as usize + 1) * block_size
+        + groups as usize * std::mem::size_of::<BlockGroupDescriptor>();
+    if gdt_end > len {
+        return Err("image too short for the group descriptor table");
+    }
+    Ok(())
+}
+
+/// Every group that starts with a superblock copy in a filesystem with this
+/// geometry, group 0 included
+fn copy_groups(superblock: &Superblock) -> impl Iterator<Item = usize> {
+    let groups = (superblock.blocks_count - superblock.first_data_block)
+        .div_ceil(superblock.blocks_per_group) as usize;
+    let sparse = superblock
+        .ro_compat()
+        .contains(RoCompatFeatures::SPARSE_SUPER);
+    (0..groups).filter(move |&group| !sparse || sparse_group(group))
+}
+
+fn copy_at(superblock: &Superblock, group: usize) -> SuperblockCopy {
+    SuperblockCopy {
+        group,
+        block: superblock.first_data_block + group as u32 * superblock.blocks_per_group,
+        block_size: 1024 << superblock.log_block_size,
+    }
+}
+
+/// The superblock at `offset`, if the image is long enough to hold one
+fn superblock_at(device: &[u8], offset: usize) -> Option<&Superblock> {
+    if offset + std::mem::size_of::<Superblock>() > device.len() {
+        return None;
+    }
+    Some(unsafe { &*(device[offset..].as_ptr() as *const Superblock) })
+}
+
+/// Look for backup superblocks without relying on the primary, which may be
+/// damaged: the first valid copy found that sits where its own geometry
+/// says a backup of its group belongs gives the geometry, and every copy it
+/// predicts that is valid too is returned, in group order.
+pub fn find_backups(device: &[u8]) -> Vec<SuperblockCopy> {
+    // copies start on a block boundary, so on a 1 KiB one whatever the
+    // block size; the one at 1024 is the primary
+    for offset in (2 * 1024..device.len()).step_by(1024) {
+        let Some(superblock) = superblock_at(device, offset) else {
+            break;
+        };
+        if superblock.magic != EXT2_MAGIC
+            || superblock.block_group == 0
+            || check_superblock(superblock, device.len()).is_err()
+        {
+            continue;
+        }
+        let group = superblock.block_group as usize;
+        if !copy_groups(superblock).any(|g| g == group)
+            || copy_at(superblock, group).offset() != offset
+        {
+            continue;
+        }
+        return copy_groups(superblock)
+            .skip(1)
+            .map(|group| copy_at(superblock, group))
+            .filter(|copy| {
+                superblock_at(device, copy.offset())
+                    .is_some_and(|found| check_superblock(found, device.len()).is_ok())
+            })
+            .collect();
+    }
+    Vec::new()
+}
+
+/// The first field in which two superblocks describe different
+/// filesystems. Counts, times and mount state are left out, since only the
+/// primary keeps those up to date.
+fn differing_field(a: &Superblock, b: &Superblock) -> Option<&'static str> {
+    let fields: [(&str, bool); 15] = [
+        ("magic", a.magic == b.magic),
+        ("rev_major", a.rev_major == b.rev_major),
+        ("inodes_count", a.inodes_count == b.inodes_count),
+        ("blocks_count", a.blocks_count == b.blocks_count),
+        ("first_data_block", a.first_data_block == b.first_data_block),
+        ("log_block_size", a.log_block_size == b.log_block_size),
+        ("blocks_per_group", a.blocks_per_group == b.blocks_per_group),
+        ("inodes_per_group", a.inodes_per_group == b.inodes_per_group),
+        ("fs_id", a.fs_id == b.fs_id),
+        ("features_opt", a.compat() == b.compat()),
+        ("features_req", a.incompat() == b.incompat()),
+        ("features_ronly", a.ro_compat() == b.ro_compat()),
+        (
+            "first_inode",
+            !a.is_dynamic() || a.first_inode == b.first_inode,
+        ),
+        (
+            "inode_size",
+            !a.is_dynamic() || a.inode_size == b.inode_size,
+        ),
+        (
+            "reserved_gdt_blocks",
+            !a.is_dynamic() || a.reserved_gdt_blocks == b.reserved_gdt_blocks,
+        ),
+    ];
+    fields
+        .iter()
+        .find(|(_, same)| !same)
+        .map(|&(field, _)| field)
+}
+
+impl Ext2 {
+    /// Open an image read-only from a backup superblock and the group
+    /// descriptor table after it, as found by `find_backups`, for when the
+    /// primary is damaged. `restore_primary` makes the fix permanent.
+    pub fn new_from_backup<B: ByteSlice + std::fmt::Debug>(
+        device_bytes: B,
+        start_addr: usize,
+        copy: SuperblockCopy,
+    ) -> Result<Ext2> {
+        Self::open(
+            device_bytes.as_ptr() as *mut u8,
+            device_bytes.len(),
+            start_addr,
+            true,
+            copy.offset(),
+        )
+    }
+
+    /// Overwrite the primary superblock and group descriptor table with the
+    /// backup `copy`. The backup's free counts are as old as the backup,
+    /// so the image wants `repair` with `Repairs::FREE_COUNTS` afterwards.
+    pub fn restore_primary(device_bytes: &mut [u8], copy: SuperblockCopy) -> Result<()> {
+        let len = device_bytes.len();
+        let superblock = superblock_at(device_bytes, copy.offset()).ok_or(
+            Ext2Error::BadSuperblock("backup is past the end of the image"),
+        )?;
+        check_superblock(superblock, len).map_err(Ext2Error::BadSuperblock)?;
+        let block_size = 1024usize << superblock.log_block_size;
+        if block_size != copy.block_size {
+            return Err(Ext2Error::BadSuperblock(
+                "backup has a different block size",
+            ));
+        }
+        let group_count = (superblock.blocks_count - superblock.first_data_block)
+            .div_ceil(superblock.blocks_per_group) as usize;
+        let gdt_len = group_count * std::mem::size_of::<BlockGroupDescriptor>();
+        let from = (copy.offset() / block_size + 1) * block_size;
+        let to = (superblock.first_data_block as usize + 1) * block_size;
+        if from + gdt_len > len {
+            return Err(Ext2Error::BadSuperblock(
+                "image too short for the backup group descriptor table",
+            ));
+        }
+
+        let size = std::mem::size_of::<Superblock>();
+        device_bytes.copy_within(
+            copy.offset()..copy.offset() + size,
+            EXT2_START_OF_SUPERBLOCK,
+        );
+        device_bytes.copy_within(from..from + gdt_len, to);
+        let primary = unsafe {
+            &mut *(device_bytes[EXT2_START_OF_SUPERBLOCK..].as_mut_ptr() as *mut Superblock)
+        };
+        primary.block_group = 0;
+        Ok(())
+    }
+
+    /// Where the backups of the superblock the image was opened with
+    /// should be, by its geometry
+    pub fn backups(&self) -> Vec<SuperblockCopy> {
+        copy_groups(self.superblock)
+            .skip(1)
+            .map(|group| copy_at(self.superblock, group))
+            .collect()
+    }
+
+    /// Compare each backup with the superblock the image was opened with
+    pub fn compare_backups(&self) -> Vec<(SuperblockCopy, CopyState)> {
+        let len = self.blocks.iter().map(|block| block.len()).sum();
+        self.backups()
+            .into_iter()
+            .map(|copy| {
+                let block = self.blocks.get(copy.block as usize - self.block_offset);
+                let state = match block {
+                    Some(block) if block.len() >= std::mem::size_of::<Superblock>() => {
+                        let backup = unsafe { &*(block.as_ptr() as *const Superblock) };
+                        match check_superblock(backup, len) {
+                            Err(why) => CopyState::Invalid(why),
+                            Ok(()) => match differing_field(self.superblock, backup) {
+                                Some(field) => CopyState::Differs(field),
+                                None => CopyState::Matches,
+                            },
+                        }
+                    }
+                    _ => CopyState::Invalid("backup is past the end of the image"),
+                };
+                (copy, state)
+            })
+            .collect()
+    }
+}
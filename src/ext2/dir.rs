@@ -0,0 +1,420 @@
+// Reading and modifying the entries stored in a directory's data blocks.
+// https://wiki.osdev.org/Ext2#Directories
+
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::features::IncompatFeatures;
+use crate::ext2::structs::{InodeFlags, TypeIndicator};
+use crate::ext2::Ext2;
+
+/// Size of the fixed part of a directory entry, before the name
+pub(crate) const DIR_ENTRY_HEADER: usize = 8;
+/// The name length is stored in a single byte
+pub(crate) const MAX_NAME_LENGTH: usize = 255;
+
+/// A directory entry together with where it lives
+#[derive(Debug, Clone)]
+pub(crate) struct DirRecord {
+    /// Device block holding the entry
+    pub block: u32,
+    /// Byte offset of the entry within `block`
+    pub offset: usize,
+    pub inode: u32,
+    pub entry_size: usize,
+    /// Raw type indicator byte (0 without the file type feature)
+    pub file_type: u8,
+    pub name: Vec<u8>,
+}
+
+/// Bytes an entry with a name of `name_length` needs, rounded up to 4
+pub(crate) fn record_size(name_length: usize) -> usize {
+    (DIR_ENTRY_HEADER + name_length + 3) & !3
+}
+
+impl Ext2 {
+    pub(crate) fn has_file_type(&self) -> bool {
+        self.superblock
+            .incompat()
+            .contains(IncompatFeatures::FILETYPE)
+    }
+
+    /// Every entry of directory `dir` in order, including unused
+    /// (`inode == 0`) ones
+    pub(crate) fn dir_records(&self, dir: usize) -> Vec<DirRecord> {
+        let mut ret = Vec::new();
+        let dir_inode = self.get_inode(dir);
+        let block_count = dir_inode.size_low as usize / self.block_size;
+        for index in 0..block_count {
+            let block = self.block_of(dir_inode, index);
+            if block == 0 {
+                continue;
+            }
+            self.block_records(dir, block, &mut ret);
+        }
+        ret
+    }
+
+    /// Add the entries of `block` of directory `dir` to `ret`, stopping at
+    /// the first corrupt one
+    pub(crate) fn block_records(&self, dir: usize, block: u32, ret: &mut Vec<DirRecord>) {
+        let bytes = self.block(block);
+        let mut offset = 0;
+        while offset + DIR_ENTRY_HEADER <= self.block_size {
+            let inode = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+            let entry_size =
+                u16::from_le_bytes(bytes[offset + 4..offset + 6].try_into().unwrap()) as usize;
+            let mut name_length = bytes[offset + 6] as usize;
+            let mut file_type = bytes[offset + 7];
+            if !self.has_file_type() {
+                name_length |= (file_type as usize) << 8;
+                file_type = 0;
+            }
+            if entry_size < DIR_ENTRY_HEADER
+                || offset + entry_size > self.block_size
+                || inode > self.superblock.inodes_count
+            {
+                // a corrupt record; the rest of the block can't be trusted
+                self.fs_error("block_records", dir, block);
+                break;
+            }
+            let name_end = (offset + DIR_ENTRY_HEADER + name_length).min(offset + entry_size);
+            ret.push(DirRecord {
+                block,
+                offset,
+                inode,
+                entry_size,
+                file_type,
+                name: bytes[offset + DIR_ENTRY_HEADER..name_end].to_vec(),
+            });
+            offset += entry_size;
+        }
+    }
+
+    fn find_record(&self, dir: usize, name: &str) -> Result<DirRecord> {
+        if !self.get_inode(dir).type_perm.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+        if self.is_indexed(dir) && (name == "." || name == "..") {
+            // these two are in block 0, the index root, which the index
+            // itself doesn't cover
+            let mut records = Vec::new();
+            match self.block_of(self.get_inode(dir), 0) {
+                0 => {}
+                block => self.block_records(dir, block, &mut records),
+            }
+            return records
+                .into_iter()
+                .find(|record| record.inode != 0 && record.name == name.as_bytes())
+                .ok_or(Ext2Error::NotFound);
+        }
+        if self.is_indexed(dir) {
+            match self.dx_find_record(dir, name.as_bytes()) {
+                Some(record) => return record.ok_or(Ext2Error::NotFound),
+                // a corrupt index; the entries themselves may still be fine
+                None => self.fs_error("dx_find_record", dir, 0),
+            }
+        }
+        self.dir_records(dir)
+            .into_iter()
+            .find(|record| record.inode != 0 && record.name == name.as_bytes())
+            .ok_or(Ext2Error::NotFound)
+    }
+
+    /// The inode that `name` refers to in directory `dir`
+    pub fn lookup(&self, dir: usize, name: &str) -> Result<usize> {
+        Ok(self.find_record(dir, name)?.inode as usize)
+    }
+
+    /// The inode and name of every entry in directory `dir`, `.` and `..`
+    /// included, in the order they are stored
+    pub fn entries(&self, dir: usize) -> Result<Vec<(usize, Vec<u8>)>> {
+        if !self.get_inode(dir).type_perm.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+        Ok(self
+            .dir_records(dir)
+            .into_iter()
+            .filter(|record| record.inode != 0)
+            .map(|record| (record.inode as usize, record.name))
+            .collect())
+    }
+
+    /// Whether `dir` holds nothing but `.` and `..`
+    pub fn is_empty_dir(&self, dir: usize) -> bool {
+        self.dir_records(dir)
+            .iter()
+            .all(|record| record.inode == 0 || record.name == b"." || record.name == b"..")
+    }
+
+    pub(crate) fn check_name(name: &str) -> Result<()> {
+        if name.is_empty() || name.contains('/') {
+            return Err(Ext2Error::InvalidArgument("invalid file name"));
+        }
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(Ext2Error::NameTooLong);
+        }
+        Ok(())
+    }
+
+    /// Add an entry for `inode` to directory `dir`.
+    ///
+    /// Every entry's `entry_size` reaches to the start of the next one, and
+    /// the last entry of each block is padded out to the end of the block,
+    /// so free space is the slack between the bytes an entry needs and its
+    /// `entry_size`. The first entry with enough slack for the new name is
+    /// split in two (an unused entry, such as a removed first entry of a
+    /// block, is simply reused). If no entry has room the directory grows
+    /// by a block.
+    ///
+    /// An indexed directory only has room in the leaf block the name's
+    /// hash leads to, which is split if it's full. If the index can't be
+    /// kept up to date it is dropped, which leaves an ordinary directory.
+    pub(crate) fn add_dir_entry(
+        &mut self,
+        dir: usize,
+        name: &str,
+        inode: usize,
+        file_type: TypeIndicator,
+    ) -> Result<()> {
+        Self::check_name(name)?;
+        let file_type = if self.has_file_type() {
+            file_type as u8
+        } else {
+            0
+        };
+
+        if self.get_inode(dir).flags.contains(InodeFlags::INDEX) {
+            if self.is_indexed(dir) && self.dx_add_entry(dir, name.as_bytes(), inode, file_type)? {
+                self.touch_dir(dir);
+                return Ok(());
+            }
+            // the index blocks read as empty directory blocks without it
+            self.inode_mut(dir).flags.remove(InodeFlags::INDEX);
+        }
+
+        if !self.insert_record(&self.dir_records(dir), name.as_bytes(), inode, file_type) {
+            let block = self.extend_dir(dir)?;
+            self.write_record(block, 0, self.block_size, inode, name.as_bytes(), file_type);
+        }
+        self.touch_dir(dir);
+        Ok(())
+    }
+
+    /// Put an entry in the first of `records` with room for it, as
+    /// `add_dir_entry` describes, and return whether one had room
+    pub(crate) fn insert_record(
+        &mut self,
+        records: &[DirRecord],
+        name: &[u8],
+        inode: usize,
+        file_type: u8,
+    ) -> bool {
+        let needed = record_size(name.len());
+        for record in records {
+            let used = if record.inode == 0 {
+                0
+            } else {
+                record_size(record.name.len())
+            };
+            if record.entry_size - used < needed {
+                continue;
+            }
+            if used > 0 {
+                let bytes = self.block_mut(record.block);
+                bytes[record.offset + 4..record.offset + 6]
+                    .copy_from_slice(&(used as u16).to_le_bytes());
+            }
+            self.write_record(
+                record.block,
+                record.offset + used,
+                record.entry_size - used,
+                inode,
+                name,
+                file_type,
+            );
+            return true;
+        }
+        false
+    }
+
+    /// Add a block to the end of directory `dir` holding a single unused
+    /// entry that spans it, and return the block
+    pub(crate) fn extend_dir(&mut self, dir: usize) -> Result<u32> {
+        let index = self.get_inode(dir).size_low as usize / self.block_size;
+        let block = self.block_of_alloc(dir, index)?;
+        self.write_record(block, 0, self.block_size, 0, b"", 0);
+        let block_size = self.block_size as u32;
+        self.inode_mut(dir).size_low += block_size;
+        Ok(block)
+    }
+
+    /// Remove the entry `name` from `dir` and return the inode it named.
+    /// The inode itself is left alone.
+    ///
+    /// The entry's space is merged into the entry before it, which may be
+    /// an unused one. The first entry of a block has nothing before it, so
+    /// it is marked unused (`inode == 0`) instead, keeping its size so a
+    /// later insertion can reuse the space.
+    pub(crate) fn remove_dir_entry(&mut self, dir: usize, name: &str) -> Result<usize> {
+        let record = self.find_record(dir, name)?;
+        self.remove_record(dir, &record);
+        Ok(record.inode as usize)
+    }
+
+    /// Remove one particular record of `dir`, as `remove_dir_entry` does
+    pub(crate) fn remove_record(&mut self, dir: usize, record: &DirRecord) {
+        let mut records = Vec::new();
+        self.block_records(dir, record.block, &mut records);
+        let previous = records
+            .into_iter()
+            .find(|other| other.offset + other.entry_size == record.offset);
+        let bytes = self.block_mut(record.block);
+        match previous {
+            Some(previous) => {
+                let merged = (previous.entry_size + record.entry_size) as u16;
+                bytes[previous.offset + 4..previous.offset + 6]
+                    .copy_from_slice(&merged.to_le_bytes());
+            }
+            None => bytes[record.offset..record.offset + 4].fill(0),
+        }
+        self.touch_dir(dir);
+    }
+
+    /// Point the existing entry `name` in `dir` at a different inode
+    pub(crate) fn set_dir_entry(
+        &mut self,
+        dir: usize,
+        name: &str,
+        inode: usize,
+        file_type: TypeIndicator,
+    ) -> Result<()> {
+        let record = self.find_record(dir, name)?;
+        let has_file_type = self.has_file_type();
+        let bytes = self.block_mut(record.block);
+        bytes[record.offset..record.offset + 4].copy_from_slice(&(inode as u32).to_le_bytes());
+        if has_file_type {
+            bytes[record.offset + 7] = file_type as u8;
+        }
+        self.touch_dir(dir);
+        Ok(())
+    }
+
+    /// Give the new directory `dir` its first block, holding `.` and `..`
+    /// (naming `parent`), and the two links those make to it
+    pub(crate) fn init_dir(&mut self, dir: usize, parent: usize) -> Result<()> {
+        let block = self.block_of_alloc(dir, 0)?;
+        let file_type = if self.has_file_type() {
+            TypeIndicator::Directory as u8
+        } else {
+            0
+        };
+        let dot_size = record_size(1);
+        self.write_record(block, 0, dot_size, dir, b".", file_type);
+        self.write_record(
+            block,
+            dot_size,
+            self.block_size - dot_size,
+            parent,
+            b"..",
+            file_type,
+        );
+        let block_size = self.block_size as u32;
+        let file = self.inode_mut(dir);
+        file.size_low = block_size;
+        file.hard_links = 2;
+        Ok(())
+    }
+
+    /// Write a complete entry at `offset`, zeroing the rest of its space
+    pub(crate) fn write_record(
+        &mut self,
+        block: u32,
+        offset: usize,
+        entry_size: usize,
+        inode: usize,
+        name: &[u8],
+        file_type: u8,
+    ) {
+        let bytes = &mut self.block_mut(block)[offset..offset + entry_size];
+        bytes.fill(0);
+        bytes[0..4].copy_from_slice(&(inode as u32).to_le_bytes());
+        bytes[4..6].copy_from_slice(&(entry_size as u16).to_le_bytes());
+        bytes[6] = name.len() as u8;
+        bytes[7] = file_type;
+        bytes[DIR_ENTRY_HEADER..DIR_ENTRY_HEADER + name.len()].copy_from_slice(name);
+    }
+
+    /// Rewrite directory `dir` with its entries packed together in their
+    /// current order, dropping unused entries and the slack left by
+    /// removals, then release the blocks at the end that are no longer
+    /// needed. An indexed directory is packed in hash order by rebuilding
+    /// its index instead.
+    pub fn compact_dir(&mut self, dir: usize) -> Result<()> {
+        self.check_writable()?;
+        if !self.get_inode(dir).type_perm.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+        if self.is_indexed(dir) {
+            return self.index_dir(dir);
+        }
+        self.inode_mut(dir).flags.remove(InodeFlags::INDEX);
+        // group the live entries into blocks
+        let mut layout: Vec<Vec<DirRecord>> = vec![Vec::new()];
+        let mut offset = 0;
+        for record in self.dir_records(dir) {
+            if record.inode == 0 {
+                continue;
+            }
+            let size = record_size(record.name.len());
+            if offset + size > self.block_size {
+                layout.push(Vec::new());
+                offset = 0;
+            }
+            layout.last_mut().unwrap().push(record);
+            offset += size;
+        }
+
+        for (index, records) in layout.iter().enumerate() {
+            let block = self.block_of_alloc(dir, index)?;
+            self.write_records(block, records);
+        }
+        self.free_blocks_from(dir, layout.len());
+        let size = (layout.len() * self.block_size) as u32;
+        self.inode_mut(dir).size_low = size;
+        self.touch_dir(dir);
+        Ok(())
+    }
+
+    /// Fill `block` with `records` packed together, the last padded out to
+    /// the end of the block, or with a single unused entry if there are
+    /// none
+    pub(crate) fn write_records(&mut self, block: u32, records: &[DirRecord]) {
+        if records.is_empty() {
+            self.write_record(block, 0, self.block_size, 0, b"", 0);
+            return;
+        }
+        let mut offset = 0;
+        for (i, record) in records.iter().enumerate() {
+            let entry_size = if i + 1 == records.len() {
+                self.block_size - offset
+            } else {
+                record_size(record.name.len())
+            };
+            self.write_record(
+                block,
+                offset,
+                entry_size,
+                record.inode as usize,
+                &record.name,
+                record.file_type,
+            );
+            offset += entry_size;
+        }
+    }
+
+    pub(crate) fn touch_dir(&mut self, dir: usize) {
+        let now = self.now();
+        let dir = self.inode_mut(dir);
+        dir.mtime = now;
+        dir.ctime = now;
+    }
+}
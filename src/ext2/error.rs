@@ -0,0 +1,129 @@
+use crate::ext2::features::{feature_names, CompatFeatures, IncompatFeatures, RoCompatFeatures};
+use std::fmt;
+use std::io::ErrorKind;
+
+/// Errors returned by operations that read or modify an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ext2Error {
+    /// No directory entry with the requested name exists
+    NotFound,
+    /// A directory was required but the inode is something else
+    NotADirectory,
+    /// The operation is not allowed on a directory
+    IsADirectory,
+    /// The directory still has entries besides `.` and `..`
+    DirectoryNotEmpty,
+    /// An entry with the requested name already exists
+    AlreadyExists,
+    /// The arguments describe an operation that makes no sense, such as
+    /// moving a directory underneath itself
+    InvalidArgument(&'static str),
+    /// The size is beyond what the block pointers of an inode can address
+    FileTooLarge,
+    /// A name is longer than the 255 bytes a directory entry can hold
+    NameTooLong,
+    /// There are no free blocks or inodes left
+    NoSpace,
+    /// The image was opened without write access
+    ReadOnly,
+    /// The image needs incompat features this implementation doesn't
+    /// support, so it can't be opened at all
+    UnsupportedFeatures(IncompatFeatures),
+    /// The superblock doesn't describe a filesystem that fits in the
+    /// image; a backup may still be good
+    BadSuperblock(&'static str),
+    /// The inode has no extended attribute with the requested name
+    NoAttribute,
+    /// The request needs something this implementation doesn't do, such
+    /// as an extended attribute namespace it doesn't know
+    Unsupported(&'static str),
+    /// Metadata the request needs is corrupt
+    Corrupt(&'static str),
+    /// The inode's flags forbid the change, such as one to an immutable
+    /// inode
+    NotPermitted(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, Ext2Error>;
+
+impl Ext2Error {
+    /// The `errno` value a system call failing the same way would set
+    pub fn errno(&self) -> i32 {
+        match self {
+            Ext2Error::NotFound => libc::ENOENT,
+            Ext2Error::NotADirectory => libc::ENOTDIR,
+            Ext2Error::IsADirectory => libc::EISDIR,
+            Ext2Error::DirectoryNotEmpty => libc::ENOTEMPTY,
+            Ext2Error::AlreadyExists => libc::EEXIST,
+            Ext2Error::InvalidArgument(_) => libc::EINVAL,
+            Ext2Error::FileTooLarge => libc::EFBIG,
+            Ext2Error::NameTooLong => libc::ENAMETOOLONG,
+            Ext2Error::NoSpace => libc::ENOSPC,
+            Ext2Error::ReadOnly => libc::EROFS,
+            Ext2Error::UnsupportedFeatures(_) => libc::EINVAL,
+            Ext2Error::BadSuperblock(_) => libc::EINVAL,
+            Ext2Error::NoAttribute => libc::ENODATA,
+            Ext2Error::Unsupported(_) => libc::EOPNOTSUPP,
+            Ext2Error::Corrupt(_) => libc::EUCLEAN,
+            Ext2Error::NotPermitted(_) => libc::EPERM,
+        }
+    }
+}
+
+impl fmt::Display for Ext2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ext2Error::NotFound => write!(f, "no such file or directory"),
+            Ext2Error::NotADirectory => write!(f, "not a directory"),
+            Ext2Error::IsADirectory => write!(f, "is a directory"),
+            Ext2Error::DirectoryNotEmpty => write!(f, "directory not empty"),
+            Ext2Error::AlreadyExists => write!(f, "file exists"),
+            Ext2Error::InvalidArgument(why) => write!(f, "invalid argument: {}", why),
+            Ext2Error::FileTooLarge => write!(f, "file too large"),
+            Ext2Error::NameTooLong => write!(f, "file name too long"),
+            Ext2Error::NoSpace => write!(f, "no space left on device"),
+            Ext2Error::ReadOnly => write!(f, "read-only file system"),
+            Ext2Error::UnsupportedFeatures(features) => write!(
+                f,
+                "unsupported features: {}",
+                feature_names(
+                    CompatFeatures::empty(),
+                    *features,
+                    RoCompatFeatures::empty()
+                )
+                .join(" ")
+            ),
+            Ext2Error::BadSuperblock(why) => write!(f, "bad superblock: {}", why),
+            Ext2Error::NoAttribute => write!(f, "no such attribute"),
+            Ext2Error::Unsupported(why) => write!(f, "operation not supported: {}", why),
+            Ext2Error::Corrupt(why) => write!(f, "filesystem corrupt: {}", why),
+            Ext2Error::NotPermitted(why) => write!(f, "operation not permitted: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for Ext2Error {}
+
+impl From<Ext2Error> for std::io::Error {
+    fn from(err: Ext2Error) -> std::io::Error {
+        let kind = match err {
+            Ext2Error::NotFound => ErrorKind::NotFound,
+            Ext2Error::NotADirectory => ErrorKind::NotADirectory,
+            Ext2Error::IsADirectory => ErrorKind::IsADirectory,
+            Ext2Error::DirectoryNotEmpty => ErrorKind::DirectoryNotEmpty,
+            Ext2Error::AlreadyExists => ErrorKind::AlreadyExists,
+            Ext2Error::InvalidArgument(_) => ErrorKind::InvalidInput,
+            Ext2Error::FileTooLarge => ErrorKind::FileTooLarge,
+            Ext2Error::NameTooLong => ErrorKind::InvalidFilename,
+            Ext2Error::NoSpace => ErrorKind::StorageFull,
+            Ext2Error::ReadOnly => ErrorKind::ReadOnlyFilesystem,
+            Ext2Error::UnsupportedFeatures(_) => ErrorKind::Unsupported,
+            Ext2Error::BadSuperblock(_) => ErrorKind::InvalidData,
+            Ext2Error::NoAttribute => ErrorKind::NotFound,
+            Ext2Error::Unsupported(_) => ErrorKind::Unsupported,
+            Ext2Error::Corrupt(_) => ErrorKind::InvalidData,
+            Ext2Error::NotPermitted(_) => ErrorKind::PermissionDenied,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
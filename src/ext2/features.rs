@@ -0,0 +1,245 @@
+// The three feature fields of the superblock. An implementation may mount
+// a filesystem with compat features it doesn't know as if they weren't
+// there, must not write to one with ro_compat features it doesn't know, and
+// must not touch one with incompat features it doesn't know at all.
+// https://www.nongnu.org/ext2-doc/ext2.html#s-feature-compat
+
+use bitflags::bitflags;
+
+bitflags! {
+    /// `Superblock::features_opt`: features that don't change how the
+    /// filesystem has to be read or written
+    #[repr(transparent)]
+    pub struct CompatFeatures: u32 {
+        /// Blocks are preallocated for new directories
+        const DIR_PREALLOC = 0x0001;
+        const IMAGIC_INODES = 0x0002;
+        /// An ext3 journal lives in `journal_inode`
+        const HAS_JOURNAL = 0x0004;
+        /// Extended attributes
+        const EXT_ATTR = 0x0008;
+        /// Blocks are reserved after the GDT for growing it, owned by
+        /// inode 7
+        const RESIZE_INODE = 0x0010;
+        /// Large directories are indexed by name hashes
+        const DIR_INDEX = 0x0020;
+        const LAZY_BG = 0x0040;
+        const SPARSE_SUPER2 = 0x0200;
+        const FAST_COMMIT = 0x0400;
+        const STABLE_INODES = 0x0800;
+        const ORPHAN_FILE = 0x1000;
+    }
+}
+
+bitflags! {
+    /// `Superblock::features_req`: features without which the filesystem
+    /// can't be read correctly
+    #[repr(transparent)]
+    pub struct IncompatFeatures: u32 {
+        const COMPRESSION = 0x0001;
+        /// Directory entries record the file type
+        const FILETYPE = 0x0002;
+        /// The journal has to be replayed
+        const RECOVER = 0x0004;
+        /// This is an external journal device
+        const JOURNAL_DEV = 0x0008;
+        const META_BG = 0x0010;
+        const EXTENTS = 0x0040;
+        const SIXTY_FOUR_BIT = 0x0080;
+        const MMP = 0x0100;
+        const FLEX_BG = 0x0200;
+        const EA_INODE = 0x0400;
+        const DIRDATA = 0x1000;
+        const CSUM_SEED = 0x2000;
+        const LARGEDIR = 0x4000;
+        const INLINE_DATA = 0x8000;
+        const ENCRYPT = 0x10000;
+        const CASEFOLD = 0x20000;
+    }
+}
+
+bitflags! {
+    /// `Superblock::features_ronly`: features without which the
+    /// filesystem can be read but not safely written
+    #[repr(transparent)]
+    pub struct RoCompatFeatures: u32 {
+        /// Only some groups carry superblock and GDT backups
+        const SPARSE_SUPER = 0x0001;
+        /// Files may be 4 GiB or bigger, using `size_high`
+        const LARGE_FILE = 0x0002;
+        const BTREE_DIR = 0x0004;
+        const HUGE_FILE = 0x0008;
+        const GDT_CSUM = 0x0010;
+        const DIR_NLINK = 0x0020;
+        const EXTRA_ISIZE = 0x0040;
+        const QUOTA = 0x0100;
+        const BIGALLOC = 0x0200;
+        const METADATA_CSUM = 0x0400;
+        const REPLICA = 0x0800;
+        const READONLY = 0x1000;
+        const PROJECT = 0x2000;
+        const SHARED_BLOCKS = 0x4000;
+        const VERITY = 0x8000;
+        const ORPHAN_PRESENT = 0x10000;
+    }
+}
+
+impl IncompatFeatures {
+    /// What this implementation understands; `Ext2::new` refuses images
+    /// with any other bits set
+    pub const SUPPORTED: IncompatFeatures = IncompatFeatures::FILETYPE;
+}
+
+impl RoCompatFeatures {
+    /// What this implementation keeps up to date when writing; images with
+    /// any other bits set are opened read-only
+    pub const SUPPORTED: RoCompatFeatures =
+        RoCompatFeatures::SPARSE_SUPER.union(RoCompatFeatures::LARGE_FILE);
+}
+
+/// One feature bit, from whichever of the three fields it belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Compat(CompatFeatures),
+    Incompat(IncompatFeatures),
+    RoCompat(RoCompatFeatures),
+}
+
+/// Every known feature by the name mke2fs and dumpe2fs give it, in the
+/// order dumpe2fs lists them
+pub const FEATURES: [(&str, Feature); 43] = [
+    (
+        "dir_prealloc",
+        Feature::Compat(CompatFeatures::DIR_PREALLOC),
+    ),
+    (
+        "imagic_inodes",
+        Feature::Compat(CompatFeatures::IMAGIC_INODES),
+    ),
+    ("has_journal", Feature::Compat(CompatFeatures::HAS_JOURNAL)),
+    ("ext_attr", Feature::Compat(CompatFeatures::EXT_ATTR)),
+    (
+        "resize_inode",
+        Feature::Compat(CompatFeatures::RESIZE_INODE),
+    ),
+    ("dir_index", Feature::Compat(CompatFeatures::DIR_INDEX)),
+    ("lazy_bg", Feature::Compat(CompatFeatures::LAZY_BG)),
+    (
+        "sparse_super2",
+        Feature::Compat(CompatFeatures::SPARSE_SUPER2),
+    ),
+    ("fast_commit", Feature::Compat(CompatFeatures::FAST_COMMIT)),
+    (
+        "stable_inodes",
+        Feature::Compat(CompatFeatures::STABLE_INODES),
+    ),
+    ("orphan_file", Feature::Compat(CompatFeatures::ORPHAN_FILE)),
+    (
+        "compression",
+        Feature::Incompat(IncompatFeatures::COMPRESSION),
+    ),
+    ("filetype", Feature::Incompat(IncompatFeatures::FILETYPE)),
+    (
+        "needs_recovery",
+        Feature::Incompat(IncompatFeatures::RECOVER),
+    ),
+    (
+        "journal_dev",
+        Feature::Incompat(IncompatFeatures::JOURNAL_DEV),
+    ),
+    ("meta_bg", Feature::Incompat(IncompatFeatures::META_BG)),
+    ("extent", Feature::Incompat(IncompatFeatures::EXTENTS)),
+    ("64bit", Feature::Incompat(IncompatFeatures::SIXTY_FOUR_BIT)),
+    ("mmp", Feature::Incompat(IncompatFeatures::MMP)),
+    ("flex_bg", Feature::Incompat(IncompatFeatures::FLEX_BG)),
+    ("ea_inode", Feature::Incompat(IncompatFeatures::EA_INODE)),
+    ("dirdata", Feature::Incompat(IncompatFeatures::DIRDATA)),
+    (
+        "metadata_csum_seed",
+        Feature::Incompat(IncompatFeatures::CSUM_SEED),
+    ),
+    ("large_dir", Feature::Incompat(IncompatFeatures::LARGEDIR)),
+    (
+        "inline_data",
+        Feature::Incompat(IncompatFeatures::INLINE_DATA),
+    ),
+    ("encrypt", Feature::Incompat(IncompatFeatures::ENCRYPT)),
+    ("casefold", Feature::Incompat(IncompatFeatures::CASEFOLD)),
+    (
+        "sparse_super",
+        Feature::RoCompat(RoCompatFeatures::SPARSE_SUPER),
+    ),
+    (
+        "large_file",
+        Feature::RoCompat(RoCompatFeatures::LARGE_FILE),
+    ),
+    ("btree_dir", Feature::RoCompat(RoCompatFeatures::BTREE_DIR)),
+    ("huge_file", Feature::RoCompat(RoCompatFeatures::HUGE_FILE)),
+    ("uninit_bg", Feature::RoCompat(RoCompatFeatures::GDT_CSUM)),
+    ("dir_nlink", Feature::RoCompat(RoCompatFeatures::DIR_NLINK)),
+    (
+        "extra_isize",
+        Feature::RoCompat(RoCompatFeatures::EXTRA_ISIZE),
+    ),
+    ("quota", Feature::RoCompat(RoCompatFeatures::QUOTA)),
+    ("bigalloc", Feature::RoCompat(RoCompatFeatures::BIGALLOC)),
+    (
+        "metadata_csum",
+        Feature::RoCompat(RoCompatFeatures::METADATA_CSUM),
+    ),
+    ("replica", Feature::RoCompat(RoCompatFeatures::REPLICA)),
+    ("read-only", Feature::RoCompat(RoCompatFeatures::READONLY)),
+    ("project", Feature::RoCompat(RoCompatFeatures::PROJECT)),
+    (
+        "shared_blocks",
+        Feature::RoCompat(RoCompatFeatures::SHARED_BLOCKS),
+    ),
+    ("verity", Feature::RoCompat(RoCompatFeatures::VERITY)),
+    (
+        "orphan_present",
+        Feature::RoCompat(RoCompatFeatures::ORPHAN_PRESENT),
+    ),
+];
+
+impl Feature {
+    /// The feature mke2fs calls `name`
+    pub fn from_name(name: &str) -> Option<Feature> {
+        FEATURES
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|&(_, feature)| feature)
+    }
+}
+
+/// Names of every bit set in the three fields, compat first, with bits no
+/// feature is known for as dumpe2fs shows them: `FEATURE_C12` for bit 12
+/// of the compat field, `I` and `R` for incompat and ro_compat
+pub fn feature_names(
+    compat: CompatFeatures,
+    incompat: IncompatFeatures,
+    ro_compat: RoCompatFeatures,
+) -> Vec<String> {
+    let fields = [
+        ('C', compat.bits()),
+        ('I', incompat.bits()),
+        ('R', ro_compat.bits()),
+    ];
+    let mut names = Vec::new();
+    for (letter, bits) in fields {
+        for bit in (0..32).filter(|bit| bits & 1 << bit != 0) {
+            let known = FEATURES
+                .iter()
+                .find(|(_, feature)| match (letter, feature) {
+                    ('C', Feature::Compat(flag)) => flag.bits() == 1 << bit,
+                    ('I', Feature::Incompat(flag)) => flag.bits() == 1 << bit,
+                    ('R', Feature::RoCompat(flag)) => flag.bits() == 1 << bit,
+                    _ => false,
+                });
+            match known {
+                Some((name, _)) => names.push(name.to_string()),
+                None => names.push(format!("FEATURE_{}{}", letter, bit)),
+            }
+        }
+    }
+    names
+}
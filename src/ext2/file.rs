@@ -0,0 +1,460 @@
+// Mapping a file's logical blocks onto device blocks through the direct,
+// singly, doubly and triply indirect pointers of its inode.
+// https://wiki.osdev.org/Ext2#Inode_Data_Structure
+
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::features::RoCompatFeatures;
+use crate::ext2::structs::{Inode, InodeFlags, TypePerm};
+use crate::ext2::Ext2;
+
+/// Number of direct block pointers in an inode
+pub(crate) const DIRECT_POINTERS: usize = 12;
+
+impl Ext2 {
+    /// We divide by 4 because each block pointer is 32 bits, or 4 bytes
+    pub(crate) fn pointers_per_block(&self) -> usize {
+        self.block_size / 4
+    }
+
+    /// Depth of indirection (0 for a direct pointer) needed to reach logical
+    /// block `index`, and the slot to follow at each level below the inode
+    fn block_path(&self, index: usize) -> (usize, Vec<usize>) {
+        let per_block = self.pointers_per_block();
+        if index < DIRECT_POINTERS {
+            return (0, vec![index]);
+        }
+        let index = index - DIRECT_POINTERS;
+        if index < per_block {
+            return (1, vec![index]);
+        }
+        let index = index - per_block;
+        if index < per_block * per_block {
+            return (2, vec![index / per_block, index % per_block]);
+        }
+        let index = index - per_block * per_block;
+        (
+            3,
+            vec![
+                index / (per_block * per_block),
+                (index / per_block) % per_block,
+                index % per_block,
+            ],
+        )
+    }
+
+    /// Device block holding logical block `index` of `inode`, or 0 if that
+    /// part of the file is a hole
+    pub fn block_of(&self, inode: &Inode, index: usize) -> u32 {
+        let (depth, slots) = self.block_path(index);
+        let mut block = match depth {
+            0 => inode.direct_pointer[index],
+            1 => inode.indirect_pointer,
+            2 => inode.doubly_indirect,
+            _ => inode.triply_indirect,
+        };
+        // a direct pointer's slot is the one in the inode
+        let mut slots = slots.into_iter().skip(if depth == 0 { 1 } else { 0 });
+        loop {
+            if block == 0 {
+                return 0;
+            }
+            if !self.valid_block(block) {
+                // a pointer out of the filesystem reads as a hole
+                self.fs_error("block_of", 0, block);
+                return 0;
+            }
+            match slots.next() {
+                Some(slot) => block = read_pointer(self.block(block), slot),
+                None => return block,
+            }
+        }
+    }
+
+    /// Like `block_of`, but allocates the data block (and any indirect
+    /// blocks on the way to it) if that part of the file is a hole
+    pub(crate) fn block_of_alloc(&mut self, inode: usize, index: usize) -> Result<u32> {
+        let (depth, slots) = self.block_path(index);
+        let file = *self.get_inode(inode);
+        let mut block = match depth {
+            0 => file.direct_pointer[index],
+            1 => file.indirect_pointer,
+            2 => file.doubly_indirect,
+            _ => file.triply_indirect,
+        };
+        if block == 0 {
+            block = self.alloc_file_block(inode)?;
+            let file = self.inode_mut(inode);
+            match depth {
+                0 => file.direct_pointer[index] = block,
+                1 => file.indirect_pointer = block,
+                2 => file.doubly_indirect = block,
+                _ => file.triply_indirect = block,
+            }
+        }
+        if depth == 0 {
+            return Ok(block);
+        }
+        for slot in slots {
+            let mut next = read_pointer(self.block(block), slot);
+            if next == 0 {
+                next = self.alloc_file_block(inode)?;
+                write_pointer(self.block_mut(block), slot, next);
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Allocate a zeroed block near `inode` and charge it to the inode
+    fn alloc_file_block(&mut self, inode: usize) -> Result<u32> {
+        let block = self.alloc_block(self.inode_group(inode))?;
+        self.block_mut(block).fill(0);
+        let sectors = (self.block_size / 512) as u32;
+        self.inode_mut(inode).sectors_count += sectors;
+        Ok(block)
+    }
+
+    /// Whether the block pointers of `inode` point at blocks. Device nodes
+    /// and fast symlinks store other data in them.
+    pub(crate) fn has_data_blocks(&self, inode: &Inode) -> bool {
+        match inode.type_perm.file_type() {
+            TypePerm::FILE | TypePerm::DIRECTORY => true,
+            TypePerm::SYMLINK => {
+                let xattr_sectors = if inode.ext_attribute_block != 0 {
+                    (self.block_size / 512) as u32
+                } else {
+                    0
+                };
+                inode.sectors_count > xattr_sectors
+            }
+            _ => false,
+        }
+    }
+
+    /// Release every data and indirect block of `inode`
+    pub(crate) fn free_data_blocks(&mut self, inode: usize) {
+        self.free_blocks_from(inode, 0);
+    }
+
+    /// Release the data blocks at logical index `keep` and beyond, along
+    /// with any indirect blocks left pointing at nothing
+    pub(crate) fn free_blocks_from(&mut self, inode: usize, keep: usize) {
+        let file = *self.get_inode(inode);
+        if !self.has_data_blocks(&file) {
+            return;
+        }
+        for index in keep..DIRECT_POINTERS {
+            let block = file.direct_pointer[index];
+            if block != 0 {
+                self.free_file_block(inode, block);
+                self.inode_mut(inode).direct_pointer[index] = 0;
+            }
+        }
+        let per_block = self.pointers_per_block();
+        let mut first = DIRECT_POINTERS;
+        for depth in 1..=3 {
+            let root = match depth {
+                1 => file.indirect_pointer,
+                2 => file.doubly_indirect,
+                _ => file.triply_indirect,
+            };
+            if root != 0 && self.free_block_tree(inode, root, depth, first, keep) {
+                let file = self.inode_mut(inode);
+                match depth {
+                    1 => file.indirect_pointer = 0,
+                    2 => file.doubly_indirect = 0,
+                    _ => file.triply_indirect = 0,
+                }
+            }
+            first += per_block.pow(depth as u32);
+        }
+    }
+
+    /// Free the blocks at logical index `keep` and beyond below an
+    /// indirect block of the given depth whose first data block is logical
+    /// block `first`. Returns whether `block` itself was freed because
+    /// nothing below it is left.
+    fn free_block_tree(
+        &mut self,
+        inode: usize,
+        block: u32,
+        depth: usize,
+        first: usize,
+        keep: usize,
+    ) -> bool {
+        // number of data blocks below `block`
+        let covered = self.pointers_per_block().pow(depth as u32);
+        if first + covered <= keep {
+            return false;
+        }
+        if depth == 0 {
+            self.free_file_block(inode, block);
+            return true;
+        }
+        let span = covered / self.pointers_per_block();
+        for slot in 0..self.pointers_per_block() {
+            let child_first = first + slot * span;
+            if child_first + span <= keep {
+                continue;
+            }
+            let child = read_pointer(self.block(block), slot);
+            if child != 0 && self.free_block_tree(inode, child, depth - 1, child_first, keep) {
+                write_pointer(self.block_mut(block), slot, 0);
+            }
+        }
+        if self.block(block).iter().all(|&byte| byte == 0) {
+            self.free_file_block(inode, block);
+            return true;
+        }
+        false
+    }
+
+    /// Free a block and stop charging it to `inode`, zeroing it first if
+    /// `inode` is marked for secure deletion
+    fn free_file_block(&mut self, inode: usize, block: u32) {
+        if self
+            .get_inode(inode)
+            .flags
+            .contains(InodeFlags::SECURE_DELETE)
+        {
+            self.block_mut(block).fill(0);
+        }
+        self.free_block(block);
+        let sectors = (self.block_size / 512) as u32;
+        let file = self.inode_mut(inode);
+        file.sectors_count = file.sectors_count.saturating_sub(sectors);
+    }
+
+    /// Largest file the block pointers of an inode can address
+    pub(crate) fn max_file_size(&self) -> u64 {
+        let per_block = self.pointers_per_block() as u64;
+        let blocks = DIRECT_POINTERS as u64 + per_block + per_block.pow(2) + per_block.pow(3);
+        blocks * self.block_size as u64
+    }
+
+    /// Shrink or grow regular file `inode` to `new_size` bytes.
+    ///
+    /// Shrinking frees the data blocks past the new end, and the indirect
+    /// blocks that no longer point at anything, then zeroes the rest of the
+    /// new last block so the dropped bytes can't reappear by growing the
+    /// file again. Growing only changes the size, leaving a hole.
+    pub fn truncate(&mut self, inode: usize, new_size: u64) -> Result<()> {
+        self.check_writable()?;
+        self.check_mutable(inode)?;
+        let file = *self.get_inode(inode);
+        if file.type_perm.is_dir() {
+            return Err(Ext2Error::IsADirectory);
+        }
+        if !file.type_perm.is_file() {
+            return Err(Ext2Error::InvalidArgument("can only truncate regular files"));
+        }
+        if new_size > self.max_file_size() {
+            return Err(Ext2Error::FileTooLarge);
+        }
+        let old_size = file.size();
+
+        // bytes between the smaller size and the end of its block become
+        // either unreachable or part of the file, so make them zero
+        let tail = (old_size.min(new_size) % self.block_size as u64) as usize;
+        if tail != 0 {
+            let index = (old_size.min(new_size) / self.block_size as u64) as usize;
+            let block = self.block_of(&file, index);
+            if block != 0 {
+                self.block_mut(block)[tail..].fill(0);
+            }
+        }
+        if new_size < old_size {
+            let keep = new_size.div_ceil(self.block_size as u64) as usize;
+            self.free_blocks_from(inode, keep);
+        }
+
+        self.set_file_size(inode, new_size);
+        Ok(())
+    }
+
+    /// Read from regular file `inode` starting at byte `offset` into
+    /// `buffer`. Holes read as zeros. Returns the bytes read, which is
+    /// fewer than `buffer` holds only at the end of the file.
+    pub fn read(&self, inode: usize, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        let file = self.get_inode(inode);
+        if file.type_perm.is_dir() {
+            return Err(Ext2Error::IsADirectory);
+        }
+        if !file.type_perm.is_file() {
+            return Err(Ext2Error::InvalidArgument("can only read regular files"));
+        }
+        let size = file.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let wanted = (buffer.len() as u64).min(size - offset) as usize;
+
+        let block_size = self.block_size as u64;
+        let mut read = 0;
+        while read < wanted {
+            let position = offset + read as u64;
+            let within = (position % block_size) as usize;
+            let length = (self.block_size - within).min(wanted - read);
+            let target = &mut buffer[read..read + length];
+            match self.block_of(file, (position / block_size) as usize) {
+                0 => target.fill(0),
+                block => target.copy_from_slice(&self.block(block)[within..within + length]),
+            }
+            read += length;
+        }
+        Ok(read)
+    }
+
+    /// Write `data` into regular file `inode` starting at byte `offset`,
+    /// allocating blocks for the holes it fills and growing the file if it
+    /// ends past the old end. Returns the bytes written, which is fewer
+    /// than asked for only if space ran out part way. An append-only file
+    /// can only be written at its end.
+    pub fn write(&mut self, inode: usize, offset: u64, data: &[u8]) -> Result<usize> {
+        self.check_writable()?;
+        self.check_not_immutable(inode)?;
+        let file = *self.get_inode(inode);
+        if file.type_perm.is_dir() {
+            return Err(Ext2Error::IsADirectory);
+        }
+        if !file.type_perm.is_file() {
+            return Err(Ext2Error::InvalidArgument("can only write regular files"));
+        }
+        if file.flags.contains(InodeFlags::APPEND) && offset != file.size() {
+            return Err(Ext2Error::NotPermitted("inode is append-only"));
+        }
+        if offset + data.len() as u64 > self.max_file_size() {
+            return Err(Ext2Error::FileTooLarge);
+        }
+
+        let block_size = self.block_size as u64;
+        let mut written = 0;
+        while written < data.len() {
+            let position = offset + written as u64;
+            let within = (position % block_size) as usize;
+            let length = (self.block_size - within).min(data.len() - written);
+            let block = match self.block_of_alloc(inode, (position / block_size) as usize) {
+                Ok(block) => block,
+                Err(err) if written == 0 => return Err(err),
+                Err(_) => break,
+            };
+            self.block_mut(block)[within..within + length]
+                .copy_from_slice(&data[written..written + length]);
+            written += length;
+        }
+
+        let end = offset + written as u64;
+        self.set_file_size(inode, end.max(file.size()));
+        Ok(written)
+    }
+
+    /// Set the size of a regular file, which counts as modifying it
+    fn set_file_size(&mut self, inode: usize, size: u64) {
+        if size > u32::MAX as u64 {
+            self.superblock_mut().features_ronly |= RoCompatFeatures::LARGE_FILE;
+        }
+        let now = self.now();
+        let file = self.inode_mut(inode);
+        file.set_size(size);
+        file.mtime = now;
+        file.ctime = now;
+    }
+}
+
+pub(crate) fn read_pointer(block: &[u8], slot: usize) -> u32 {
+    u32::from_le_bytes(block[slot * 4..slot * 4 + 4].try_into().unwrap())
+}
+
+pub(crate) fn write_pointer(block: &mut [u8], slot: usize, pointer: u32) {
+    block[slot * 4..slot * 4 + 4].copy_from_slice(&pointer.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext2::mkfs::FormatOptions;
+    use crate::ext2::namei::ROOT_INODE;
+
+    /// A fresh image with 1 KiB blocks and, in the root, a file for each of
+    /// `names` of 14 blocks of 0xaa, two of them past the indirect block
+    fn image_with_file(device: &mut [u8], names: &[&str]) -> Ext2 {
+        let options = FormatOptions {
+            block_size: Some(1024),
+            ..Default::default()
+        };
+        let mut ext2 = Ext2::format(device, &options).unwrap();
+        for name in names {
+            let inode = ext2.create(ROOT_INODE, name, 0o644).unwrap();
+            ext2.write(inode, 0, &[0xaa; 14 * 1024]).unwrap();
+            assert_ne!(ext2.get_inode(inode).indirect_pointer, 0);
+        }
+        ext2
+    }
+
+    /// The data blocks of `inode`, then its indirect block
+    fn blocks(ext2: &Ext2, inode: usize) -> Vec<u32> {
+        let file = ext2.get_inode(inode);
+        let mut blocks: Vec<u32> = (0..14).map(|index| ext2.block_of(file, index)).collect();
+        blocks.push(file.indirect_pointer);
+        blocks
+    }
+
+    fn secure_delete(ext2: &mut Ext2, inode: usize) {
+        let flags = ext2.get_inode(inode).flags | InodeFlags::SECURE_DELETE;
+        ext2.set_flags(inode, flags).unwrap();
+    }
+
+    #[test]
+    fn append_only_write() {
+        let mut device = vec![0u8; 4 << 20];
+        let mut ext2 = image_with_file(&mut device, &["f"]);
+        let f = ext2.lookup(ROOT_INODE, "f").unwrap();
+        let flags = ext2.get_inode(f).flags | InodeFlags::APPEND;
+        ext2.set_flags(f, flags).unwrap();
+        let size = ext2.get_inode(f).size();
+        for offset in [0, size - 1, size + 1] {
+            assert!(matches!(
+                ext2.write(f, offset, b"x"),
+                Err(Ext2Error::NotPermitted(_))
+            ));
+        }
+        assert!(matches!(
+            ext2.truncate(f, 0),
+            Err(Ext2Error::NotPermitted(_))
+        ));
+        assert_eq!(ext2.write(f, size, b"x"), Ok(1));
+        assert_eq!(ext2.get_inode(f).size(), size + 1);
+    }
+
+    #[test]
+    fn secure_delete_zeroes_truncated_blocks() {
+        let mut device = vec![0u8; 4 << 20];
+        let mut ext2 = image_with_file(&mut device, &["f"]);
+        let f = ext2.lookup(ROOT_INODE, "f").unwrap();
+        let blocks = blocks(&ext2, f);
+        secure_delete(&mut ext2, f);
+        ext2.truncate(f, 1024).unwrap();
+        assert!(ext2.block(blocks[0]).iter().all(|&byte| byte == 0xaa));
+        for &block in &blocks[1..] {
+            assert!(ext2.block(block).iter().all(|&byte| byte == 0));
+        }
+    }
+
+    #[test]
+    fn secure_delete_zeroes_unlinked_blocks() {
+        let mut device = vec![0u8; 4 << 20];
+        let mut ext2 = image_with_file(&mut device, &["f", "g"]);
+        let f = ext2.lookup(ROOT_INODE, "f").unwrap();
+        let g = ext2.lookup(ROOT_INODE, "g").unwrap();
+        let f_blocks = blocks(&ext2, f);
+        let g_blocks = blocks(&ext2, g);
+        secure_delete(&mut ext2, f);
+        ext2.unlink(ROOT_INODE, "f").unwrap();
+        ext2.unlink(ROOT_INODE, "g").unwrap();
+        for &block in &f_blocks {
+            assert!(ext2.block(block).iter().all(|&byte| byte == 0));
+        }
+        // without the flag, the data is left where it was
+        assert!(ext2.block(g_blocks[0]).iter().all(|&byte| byte == 0xaa));
+    }
+}
@@ -0,0 +1,726 @@
+// Consistency checking in the style of e2fsck. The passes are the classic
+// ones: block pointers and block ownership, directory structure,
+// connectivity to the root, reference counts, and finally bitmaps and free
+// counts against what the first four passes found in use.
+// https://e2fsprogs.sourceforge.net/ext2intro.html
+
+use crate::ext2::alloc::get_bit;
+use crate::ext2::dir::DIR_ENTRY_HEADER;
+use crate::ext2::features::IncompatFeatures;
+use crate::ext2::file::read_pointer;
+use crate::ext2::layout::RESIZE_INODE;
+use crate::ext2::namei::ROOT_INODE;
+use crate::ext2::Ext2;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+
+/// One inconsistency found by `Ext2::check`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// An inode points at a block outside the filesystem
+    BadBlockPointer { inode: usize, block: u32 },
+    /// An inode claims a block that holds filesystem metadata
+    MetadataBlockClaimed { inode: usize, block: u32 },
+    /// More than one inode claims the same block
+    DuplicateBlock { block: u32, inodes: Vec<usize> },
+    /// The root inode is not a directory in use
+    BadRoot,
+    /// A directory record is malformed; the rest of its block is skipped
+    BadDirEntry {
+        dir: usize,
+        block: u32,
+        offset: usize,
+        reason: &'static str,
+    },
+    /// The first entry of a directory is not `.` naming the directory
+    BadDot { dir: usize },
+    /// The second entry of a directory is not `..`
+    MissingDotDot { dir: usize },
+    /// A directory entry names an inode that is not in use
+    EntryToUnusedInode {
+        dir: usize,
+        name: String,
+        inode: usize,
+    },
+    /// The `..` entry of a directory doesn't name its parent
+    WrongDotDot {
+        dir: usize,
+        recorded: usize,
+        parent: usize,
+    },
+    /// An inode in use can't be reached from the root directory
+    Unconnected { inode: usize },
+    /// `hard_links` differs from the number of entries naming the inode
+    LinkCount {
+        inode: usize,
+        recorded: u16,
+        counted: u32,
+    },
+    /// The block bitmap disagrees with what is in use
+    BlockBitmap { block: u32, in_use: bool },
+    /// The inode bitmap disagrees with what is in use
+    InodeBitmap { inode: usize, in_use: bool },
+    GroupFreeBlocks {
+        group: usize,
+        recorded: u16,
+        counted: u32,
+    },
+    GroupFreeInodes {
+        group: usize,
+        recorded: u16,
+        counted: u32,
+    },
+    GroupDirs {
+        group: usize,
+        recorded: u16,
+        counted: u32,
+    },
+    /// The superblock's free block count is wrong
+    FreeBlocks { recorded: u32, counted: u32 },
+    /// The superblock's free inode count is wrong
+    FreeInodes { recorded: u32, counted: u32 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BadBlockPointer { inode, block } => {
+                write!(f, "Inode {} has illegal block {}", inode, block)
+            }
+            Problem::MetadataBlockClaimed { inode, block } => write!(
+                f,
+                "Inode {} claims block {}, which holds filesystem metadata",
+                inode, block
+            ),
+            Problem::DuplicateBlock { block, inodes } => {
+                write!(f, "Block {} is claimed by inodes {:?}", block, inodes)
+            }
+            Problem::BadRoot => write!(f, "Root inode is not a directory"),
+            Problem::BadDirEntry {
+                dir,
+                block,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "Directory inode {}, block {}, offset {}: {}",
+                dir, block, offset, reason
+            ),
+            Problem::BadDot { dir } => {
+                write!(f, "Missing '.' in directory inode {}", dir)
+            }
+            Problem::MissingDotDot { dir } => {
+                write!(f, "Missing '..' in directory inode {}", dir)
+            }
+            Problem::EntryToUnusedInode { dir, name, inode } => write!(
+                f,
+                "Entry '{}' in directory inode {} has deleted/unused inode {}",
+                name, dir, inode
+            ),
+            Problem::WrongDotDot {
+                dir,
+                recorded,
+                parent,
+            } => write!(
+                f,
+                "'..' in directory inode {} is {}, should be {}",
+                dir, recorded, parent
+            ),
+            Problem::Unconnected { inode } => {
+                write!(f, "Inode {} is not connected to the root", inode)
+            }
+            Problem::LinkCount {
+                inode,
+                recorded,
+                counted,
+            } => write!(
+                f,
+                "Inode {} ref count is {}, should be {}",
+                inode, recorded, counted
+            ),
+            Problem::BlockBitmap { block, in_use } => write!(
+                f,
+                "Block bitmap differs: block {} should be {}",
+                block,
+                if *in_use { "used" } else { "free" }
+            ),
+            Problem::InodeBitmap { inode, in_use } => write!(
+                f,
+                "Inode bitmap differs: inode {} should be {}",
+                inode,
+                if *in_use { "used" } else { "free" }
+            ),
+            Problem::GroupFreeBlocks {
+                group,
+                recorded,
+                counted,
+            } => write!(
+                f,
+                "Free blocks count wrong for group #{} ({}, counted={})",
+                group, recorded, counted
+            ),
+            Problem::GroupFreeInodes {
+                group,
+                recorded,
+                counted,
+            } => write!(
+                f,
+                "Free inodes count wrong for group #{} ({}, counted={})",
+                group, recorded, counted
+            ),
+            Problem::GroupDirs {
+                group,
+                recorded,
+                counted,
+            } => write!(
+                f,
+                "Directories count wrong for group #{} ({}, counted={})",
+                group, recorded, counted
+            ),
+            Problem::FreeBlocks { recorded, counted } => write!(
+                f,
+                "Free blocks count wrong ({}, counted={})",
+                recorded, counted
+            ),
+            Problem::FreeInodes { recorded, counted } => write!(
+                f,
+                "Free inodes count wrong ({}, counted={})",
+                recorded, counted
+            ),
+        }
+    }
+}
+
+/// Everything `Ext2::check` found wrong with an image
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Marks a block in `Scan::owner` as filesystem metadata
+const METADATA: usize = usize::MAX;
+
+/// What the passes found in use, indexed by inode or block number
+pub(crate) struct Scan {
+    pub in_use: Vec<bool>,
+    pub block_in_use: Vec<bool>,
+    /// Inode claiming each block, or `METADATA`, or 0
+    owner: Vec<usize>,
+    /// Directory entries naming each inode
+    pub references: Vec<u32>,
+    /// Parent found by walking down from the root, or 0 if unreachable
+    pub parent: Vec<usize>,
+    /// What each directory's `..` entry says
+    pub dot_dot: Vec<usize>,
+    /// Subdirectories named by each directory, other than `.` and `..`
+    children: Vec<Vec<usize>>,
+    pub problems: Vec<Problem>,
+}
+
+impl Ext2 {
+    /// Check the image for inconsistencies without changing anything
+    pub fn check(&self) -> CheckReport {
+        CheckReport {
+            problems: self.scan().problems,
+        }
+    }
+
+    pub(crate) fn scan(&self) -> Scan {
+        let inodes_count = self.superblock.inodes_count as usize;
+        let blocks_count = self.superblock.blocks_count as usize;
+        let mut scan = Scan {
+            in_use: vec![false; inodes_count + 1],
+            block_in_use: vec![false; blocks_count],
+            owner: vec![0; blocks_count],
+            references: vec![0; inodes_count + 1],
+            parent: vec![0; inodes_count + 1],
+            dot_dot: vec![0; inodes_count + 1],
+            children: vec![Vec::new(); inodes_count + 1],
+            problems: Vec::new(),
+        };
+        self.check_blocks(&mut scan);
+        self.check_directories(&mut scan);
+        self.check_connectivity(&mut scan);
+        self.check_link_counts(&mut scan);
+        self.check_bitmaps(&mut scan);
+        scan
+    }
+
+    /// Pass 1: which inodes are in use, and which blocks each one claims
+    fn check_blocks(&self, scan: &mut Scan) {
+        for block in 0..self.superblock.first_data_block as usize {
+            scan.owner[block] = METADATA;
+        }
+        for group in 0..self.block_groups.len() {
+            for block in self.group_metadata_blocks(group) {
+                scan.owner[block as usize] = METADATA;
+            }
+        }
+
+        let mut duplicates: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+        for inode in 1..scan.in_use.len() {
+            let file = *self.get_inode(inode);
+            let in_use = if inode < self.first_inode() {
+                // reserved inodes are always allocated
+                true
+            } else {
+                file.hard_links > 0
+            };
+            if !in_use {
+                continue;
+            }
+            scan.in_use[inode] = true;
+            if inode < self.first_inode() && inode != ROOT_INODE && file.type_perm.bits() == 0 {
+                continue;
+            }
+
+            if inode == RESIZE_INODE {
+                // its indirect blocks are the reserved GDT blocks, already
+                // counted as metadata; only the top block is its own
+                if file.doubly_indirect != 0 {
+                    self.claim(scan, &mut duplicates, inode, file.doubly_indirect, 0);
+                }
+            } else if self.has_data_blocks(&file) {
+                for &block in file.direct_pointer.iter().filter(|&&block| block != 0) {
+                    self.claim(scan, &mut duplicates, inode, block, 0);
+                }
+                let roots = [
+                    file.indirect_pointer,
+                    file.doubly_indirect,
+                    file.triply_indirect,
+                ];
+                for (depth, &block) in roots.iter().enumerate() {
+                    if block != 0 {
+                        self.claim(scan, &mut duplicates, inode, block, depth + 1);
+                    }
+                }
+            }
+
+            // extended attribute blocks may be shared between inodes
+            let xattr = file.ext_attribute_block;
+            if xattr != 0 {
+                if !self.valid_block(xattr) {
+                    scan.problems.push(Problem::BadBlockPointer {
+                        inode,
+                        block: xattr,
+                    });
+                } else if scan.owner[xattr as usize] == METADATA {
+                    scan.problems.push(Problem::MetadataBlockClaimed {
+                        inode,
+                        block: xattr,
+                    });
+                } else {
+                    scan.block_in_use[xattr as usize] = true;
+                }
+            }
+        }
+
+        for (block, inodes) in duplicates {
+            scan.problems
+                .push(Problem::DuplicateBlock { block, inodes });
+        }
+        for block in 0..scan.owner.len() {
+            if scan.owner[block] != 0 {
+                scan.block_in_use[block] = true;
+            }
+        }
+    }
+
+    /// Record that `inode` uses `block`, and everything below it if it is
+    /// an indirect block of the given depth
+    fn claim(
+        &self,
+        scan: &mut Scan,
+        duplicates: &mut BTreeMap<u32, Vec<usize>>,
+        inode: usize,
+        block: u32,
+        depth: usize,
+    ) {
+        if !self.valid_block(block) {
+            scan.problems
+                .push(Problem::BadBlockPointer { inode, block });
+            return;
+        }
+        match scan.owner[block as usize] {
+            0 => scan.owner[block as usize] = inode,
+            METADATA => {
+                scan.problems
+                    .push(Problem::MetadataBlockClaimed { inode, block });
+                return;
+            }
+            other => duplicates
+                .entry(block)
+                .or_insert_with(|| vec![other])
+                .push(inode),
+        }
+        if depth > 0 {
+            for slot in 0..self.pointers_per_block() {
+                let child = read_pointer(self.block(block), slot);
+                if child != 0 {
+                    self.claim(scan, duplicates, inode, child, depth - 1);
+                }
+            }
+        }
+    }
+
+    /// Pass 2: the records of every directory, and what they refer to
+    fn check_directories(&self, scan: &mut Scan) {
+        let has_file_type = self
+            .superblock
+            .incompat()
+            .contains(IncompatFeatures::FILETYPE);
+        let inodes_count = scan.in_use.len() - 1;
+        for dir in 1..=inodes_count {
+            let dir_inode = *self.get_inode(dir);
+            if !scan.in_use[dir] || !dir_inode.type_perm.is_dir() {
+                continue;
+            }
+            let mut position = 0;
+            for index in 0..dir_inode.size_low as usize / self.block_size {
+                let block = self.block_of(&dir_inode, index);
+                if block == 0 || !self.valid_block(block) {
+                    continue;
+                }
+                let bytes = self.block(block);
+                let mut offset = 0;
+                while offset < self.block_size {
+                    let mut bad = |reason| {
+                        scan.problems.push(Problem::BadDirEntry {
+                            dir,
+                            block,
+                            offset,
+                            reason,
+                        })
+                    };
+                    if offset + DIR_ENTRY_HEADER > self.block_size {
+                        bad("record header crosses the end of the block");
+                        break;
+                    }
+                    let inode =
+                        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+                    let entry_size =
+                        u16::from_le_bytes(bytes[offset + 4..offset + 6].try_into().unwrap())
+                            as usize;
+                    let mut name_length = bytes[offset + 6] as usize;
+                    if !has_file_type {
+                        name_length |= (bytes[offset + 7] as usize) << 8;
+                    }
+                    if entry_size < DIR_ENTRY_HEADER {
+                        bad("record length is too small");
+                        break;
+                    }
+                    if !entry_size.is_multiple_of(4) {
+                        bad("record length is not a multiple of 4");
+                        break;
+                    }
+                    if offset + entry_size > self.block_size {
+                        bad("record crosses the end of the block");
+                        break;
+                    }
+                    if DIR_ENTRY_HEADER + name_length > entry_size {
+                        bad("name is longer than the record");
+                        break;
+                    }
+                    let name =
+                        &bytes[offset + DIR_ENTRY_HEADER..offset + DIR_ENTRY_HEADER + name_length];
+                    if position == 0 && (name != b"." || inode != dir) {
+                        scan.problems.push(Problem::BadDot { dir });
+                    }
+                    if position == 1 {
+                        if name == b".." {
+                            scan.dot_dot[dir] = inode;
+                        } else {
+                            scan.problems.push(Problem::MissingDotDot { dir });
+                        }
+                    }
+                    position += 1;
+                    offset += entry_size;
+
+                    if inode == 0 {
+                        continue;
+                    }
+                    if inode > inodes_count {
+                        scan.problems.push(Problem::BadDirEntry {
+                            dir,
+                            block,
+                            offset: offset - entry_size,
+                            reason: "inode number out of range",
+                        });
+                        continue;
+                    }
+                    if !scan.in_use[inode] {
+                        scan.problems.push(Problem::EntryToUnusedInode {
+                            dir,
+                            name: String::from_utf8_lossy(name).into_owned(),
+                            inode,
+                        });
+                        continue;
+                    }
+                    scan.references[inode] += 1;
+                    if name != b"." && name != b".." && self.get_inode(inode).type_perm.is_dir() {
+                        scan.children[dir].push(inode);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pass 3: every directory is reachable from the root, and its `..`
+    /// names the directory it was reached from
+    fn check_connectivity(&self, scan: &mut Scan) {
+        if !scan.in_use[ROOT_INODE] || !self.get_inode(ROOT_INODE).type_perm.is_dir() {
+            scan.problems.push(Problem::BadRoot);
+            return;
+        }
+        scan.parent[ROOT_INODE] = ROOT_INODE;
+        let mut queue = VecDeque::from([ROOT_INODE]);
+        while let Some(dir) = queue.pop_front() {
+            for i in 0..scan.children[dir].len() {
+                let child = scan.children[dir][i];
+                if scan.parent[child] == 0 {
+                    scan.parent[child] = dir;
+                    queue.push_back(child);
+                }
+            }
+        }
+        for dir in 1..scan.in_use.len() {
+            if !scan.in_use[dir]
+                || dir < self.first_inode() && dir != ROOT_INODE
+                || !self.get_inode(dir).type_perm.is_dir()
+            {
+                continue;
+            }
+            if scan.parent[dir] == 0 {
+                scan.problems.push(Problem::Unconnected { inode: dir });
+            } else if scan.dot_dot[dir] != 0 && scan.dot_dot[dir] != scan.parent[dir] {
+                scan.problems.push(Problem::WrongDotDot {
+                    dir,
+                    recorded: scan.dot_dot[dir],
+                    parent: scan.parent[dir],
+                });
+            }
+        }
+    }
+
+    /// Pass 4: link counts match the directory entries naming each inode
+    fn check_link_counts(&self, scan: &mut Scan) {
+        for inode in 1..scan.in_use.len() {
+            if !scan.in_use[inode] || inode < self.first_inode() && inode != ROOT_INODE {
+                continue;
+            }
+            let file = self.get_inode(inode);
+            if file.type_perm.is_dir() {
+                if scan.parent[inode] == 0 {
+                    // already reported by pass 3
+                    continue;
+                }
+            } else if scan.references[inode] == 0 {
+                scan.problems.push(Problem::Unconnected { inode });
+                continue;
+            }
+            if file.hard_links as u32 != scan.references[inode] {
+                scan.problems.push(Problem::LinkCount {
+                    inode,
+                    recorded: file.hard_links,
+                    counted: scan.references[inode],
+                });
+            }
+        }
+    }
+
+    /// Pass 5: bitmaps and free counts agree with what is in use
+    fn check_bitmaps(&self, scan: &mut Scan) {
+        let inodes_per_group = self.superblock.inodes_per_group as usize;
+        let mut free_blocks = 0;
+        let mut free_inodes = 0;
+        for group in 0..self.block_groups.len() {
+            let desc = &self.block_groups[group];
+            let first = self.group_first_block(group) as usize;
+            let bitmap = self.block(desc.block_usage_addr);
+            let mut group_free_blocks = 0;
+            for bit in 0..self.blocks_in_group(group) {
+                let in_use = scan.block_in_use[first + bit];
+                if !in_use {
+                    group_free_blocks += 1;
+                }
+                if get_bit(bitmap, bit) != in_use {
+                    scan.problems.push(Problem::BlockBitmap {
+                        block: (first + bit) as u32,
+                        in_use,
+                    });
+                }
+            }
+
+            let bitmap = self.block(desc.inode_usage_addr);
+            let mut group_free_inodes = 0;
+            let mut dirs = 0;
+            for bit in 0..inodes_per_group {
+                let inode = group * inodes_per_group + bit + 1;
+                let in_use = scan.in_use[inode];
+                if !in_use {
+                    group_free_inodes += 1;
+                } else if self.get_inode(inode).type_perm.is_dir() {
+                    dirs += 1;
+                }
+                if get_bit(bitmap, bit) != in_use {
+                    scan.problems.push(Problem::InodeBitmap { inode, in_use });
+                }
+            }
+
+            if desc.free_blocks_count as u32 != group_free_blocks {
+                scan.problems.push(Problem::GroupFreeBlocks {
+                    group,
+                    recorded: desc.free_blocks_count,
+                    counted: group_free_blocks,
+                });
+            }
+            if desc.free_inodes_count as u32 != group_free_inodes {
+                scan.problems.push(Problem::GroupFreeInodes {
+                    group,
+                    recorded: desc.free_inodes_count,
+                    counted: group_free_inodes,
+                });
+            }
+            if desc.dirs_count as u32 != dirs {
+                scan.problems.push(Problem::GroupDirs {
+                    group,
+                    recorded: desc.dirs_count,
+                    counted: dirs,
+                });
+            }
+            free_blocks += group_free_blocks;
+            free_inodes += group_free_inodes;
+        }
+
+        if self.superblock.free_blocks_count != free_blocks {
+            scan.problems.push(Problem::FreeBlocks {
+                recorded: self.superblock.free_blocks_count,
+                counted: free_blocks,
+            });
+        }
+        if self.superblock.free_inodes_count != free_inodes {
+            scan.problems.push(Problem::FreeInodes {
+                recorded: self.superblock.free_inodes_count,
+                counted: free_inodes,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext2::alloc::set_bit;
+    use crate::ext2::mkfs::FormatOptions;
+    use crate::ext2::repair::{Repair, Repairs};
+
+    /// A fresh image with a directory /d holding a file `a`, and a file /f
+    /// with one block of data
+    fn image(device: &mut [u8]) -> Ext2 {
+        let mut ext2 = Ext2::format(device, &FormatOptions::default()).unwrap();
+        let d = ext2.mkdir(ROOT_INODE, "d", 0o755).unwrap();
+        ext2.create(d, "a", 0o644).unwrap();
+        let f = ext2.create(ROOT_INODE, "f", 0o644).unwrap();
+        ext2.write(f, 0, &[1; 1024]).unwrap();
+        assert!(ext2.check().is_clean());
+        ext2
+    }
+
+    #[test]
+    fn bad_rec_len() {
+        let mut device = vec![0u8; 4 << 20];
+        let mut ext2 = image(&mut device);
+        let d = ext2.lookup(ROOT_INODE, "d").unwrap();
+        let block = ext2.block_of(ext2.get_inode(d), 0);
+        // `..` comes after the 12 bytes of `.`
+        ext2.block_mut(block)[16..18].copy_from_slice(&13u16.to_le_bytes());
+        let bad = Problem::BadDirEntry {
+            dir: d,
+            block,
+            offset: 12,
+            reason: "record length is not a multiple of 4",
+        };
+        assert!(ext2.check().problems.contains(&bad));
+
+        // no repair rewrites directory records, so it is still there after
+        // the rest has been fixed
+        ext2.repair(Repairs::all()).unwrap();
+        assert!(ext2.check().problems.contains(&bad));
+    }
+
+    #[test]
+    fn block_bitmap_mismatch() {
+        let mut device = vec![0u8; 4 << 20];
+        let mut ext2 = image(&mut device);
+        let f = ext2.lookup(ROOT_INODE, "f").unwrap();
+        let block = ext2.block_of(ext2.get_inode(f), 0);
+        let bitmap = ext2.block_groups[ext2.block_group(block)].block_usage_addr;
+        let bit = ((block - ext2.superblock.first_data_block) % ext2.superblock.blocks_per_group)
+            as usize;
+        set_bit(ext2.block_mut(bitmap), bit, false);
+        let problem = Problem::BlockBitmap {
+            block,
+            in_use: true,
+        };
+        assert_eq!(ext2.check().problems, vec![problem.clone()]);
+
+        let done = ext2.repair(Repairs::BITMAPS).unwrap();
+        assert_eq!(done, vec![Repair::Correct(problem)]);
+        assert!(get_bit(ext2.block(bitmap), bit));
+        assert!(ext2.check().is_clean());
+    }
+
+    #[test]
+    fn wrong_link_count() {
+        let mut device = vec![0u8; 4 << 20];
+        let mut ext2 = image(&mut device);
+        let f = ext2.lookup(ROOT_INODE, "f").unwrap();
+        ext2.inode_mut(f).hard_links = 5;
+        let problem = Problem::LinkCount {
+            inode: f,
+            recorded: 5,
+            counted: 1,
+        };
+        assert_eq!(ext2.check().problems, vec![problem.clone()]);
+
+        let done = ext2.repair(Repairs::LINK_COUNTS).unwrap();
+        assert_eq!(done, vec![Repair::Correct(problem)]);
+        assert_eq!(ext2.get_inode(f).hard_links, 1);
+        assert!(ext2.check().is_clean());
+    }
+
+    #[test]
+    fn orphaned_inode() {
+        let mut device = vec![0u8; 4 << 20];
+        let mut ext2 = image(&mut device);
+        let f = ext2.lookup(ROOT_INODE, "f").unwrap();
+        // the entry goes, but the inode keeps its link
+        ext2.remove_dir_entry(ROOT_INODE, "f").unwrap();
+        let problems = ext2.check().problems;
+        assert!(problems.contains(&Problem::Unconnected { inode: f }));
+
+        let done = ext2
+            .repair(Repairs::LOST_FOUND | Repairs::LINK_COUNTS)
+            .unwrap();
+        assert!(done.contains(&Repair::Reconnect { inode: f }));
+        let lost_found = ext2.lookup(ROOT_INODE, "lost+found").unwrap();
+        assert_eq!(ext2.lookup(lost_found, &format!("#{}", f)), Ok(f));
+        assert_eq!(ext2.get_inode(f).hard_links, 1);
+        assert!(ext2.check().is_clean());
+    }
+}
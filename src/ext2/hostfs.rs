@@ -0,0 +1,304 @@
+// Copying directory trees between the host and the image, so images can be
+// built and inspected without mounting them.
+
+use crate::ext2::error::Ext2Error;
+use crate::ext2::structs::TypePerm;
+use crate::ext2::Ext2;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Image inodes of host files with several links, by host (device, inode)
+type Links = HashMap<(u64, u64), usize>;
+/// Host paths of image inodes with several links, by image inode
+type Exported = HashMap<usize, PathBuf>;
+
+impl Ext2 {
+    /// Copy everything below host directory `source` into directory `dir`
+    /// of the image, then give `dir` the attributes of `source`.
+    ///
+    /// Regular files, directories, symlinks, FIFOs, sockets and devices are
+    /// recreated with their permissions, owners, and access and
+    /// modification times; files with several links inside `source` keep
+    /// sharing one inode. Blocks of zeros become holes. A directory that
+    /// already exists in the image, such as lost+found, is merged into.
+    pub fn import_dir(&mut self, dir: usize, source: &Path) -> io::Result<()> {
+        let mut links = Links::new();
+        self.import_entries(dir, source, &mut links)?;
+        self.copy_attributes(dir, &fs::symlink_metadata(source)?)?;
+        Ok(())
+    }
+
+    fn import_entries(&mut self, dir: usize, source: &Path, links: &mut Links) -> io::Result<()> {
+        let mut entries = fs::read_dir(source)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let file_name = entry.file_name();
+            let name = file_name.to_str().ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}: name is not UTF-8", path.display()),
+                )
+            })?;
+            self.import_entry(dir, name, &path, links).map_err(|err| {
+                io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+            })?;
+        }
+        Ok(())
+    }
+
+    fn import_entry(
+        &mut self,
+        dir: usize,
+        name: &str,
+        path: &Path,
+        links: &mut Links,
+    ) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        let key = (metadata.dev(), metadata.ino());
+        if !file_type.is_dir() && metadata.nlink() > 1 {
+            if let Some(&inode) = links.get(&key) {
+                return Ok(self.link(inode, dir, name)?);
+            }
+        }
+
+        let inode = if file_type.is_dir() {
+            let inode = match self.lookup(dir, name) {
+                Ok(inode) if self.get_inode(inode).type_perm.is_dir() => inode,
+                _ => self.mkdir(dir, name, 0o755)?,
+            };
+            self.import_entries(inode, path, links)?;
+            inode
+        } else if file_type.is_file() {
+            let inode = self.create(dir, name, 0o644)?;
+            self.import_data(inode, &mut fs::File::open(path)?)?;
+            inode
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(path)?;
+            let target = target.to_str().ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, "symlink target is not UTF-8")
+            })?;
+            self.symlink(dir, name, target)?
+        } else {
+            let kind = if file_type.is_fifo() {
+                TypePerm::FIFO
+            } else if file_type.is_socket() {
+                TypePerm::SOCKET
+            } else if file_type.is_char_device() {
+                TypePerm::CHAR_DEVICE
+            } else {
+                TypePerm::BLOCK_DEVICE
+            };
+            let (major, minor) = split_rdev(metadata.rdev());
+            self.mknod(dir, name, kind, major, minor)?
+        };
+        self.copy_attributes(inode, &metadata)?;
+        if !file_type.is_dir() && metadata.nlink() > 1 {
+            links.insert(key, inode);
+        }
+        Ok(())
+    }
+
+    /// Copy everything `source` reads into the empty regular file `inode`
+    pub(crate) fn import_data(&mut self, inode: usize, source: &mut impl Read) -> io::Result<()> {
+        let mut buffer = vec![0; self.block_size];
+        let mut offset = 0;
+        loop {
+            let length = read_block(source, &mut buffer)?;
+            if length == 0 {
+                break;
+            }
+            // blocks of zeros are left as holes
+            if buffer[..length].iter().any(|&byte| byte != 0)
+                && self.write(inode, offset, &buffer[..length])? < length
+            {
+                return Err(Ext2Error::NoSpace.into());
+            }
+            offset += length as u64;
+        }
+        // covers a hole at the end
+        self.truncate(inode, offset)?;
+        Ok(())
+    }
+
+    /// Give `inode` the permissions, owner and times of a host file. The
+    /// change time can't be set; it becomes the current time. With a fixed
+    /// time set, later times are clamped to it.
+    fn copy_attributes(&mut self, inode: usize, metadata: &fs::Metadata) -> io::Result<()> {
+        if !metadata.file_type().is_symlink() {
+            self.chmod(inode, (metadata.mode() & 0o7777) as u16)?;
+        }
+        self.chown(inode, Some(metadata.uid()), Some(metadata.gid()))?;
+        let atime = self.clamp_time(metadata.atime() as u32);
+        let mtime = self.clamp_time(metadata.mtime() as u32);
+        self.utimes(inode, atime, mtime)?;
+        Ok(())
+    }
+
+    /// Recreate `inode` and everything below it at host path `target`.
+    ///
+    /// Directories, regular files (keeping their holes), symlinks and FIFOs
+    /// are created with their permissions and access and modification
+    /// times; inodes with several links inside the tree become hard links
+    /// on the host too. Owners are left to whoever runs this. Devices and
+    /// sockets usually need privileges to create; those that can't be
+    /// created are skipped and returned along with the reason.
+    pub fn export(&self, inode: usize, target: &Path) -> io::Result<Vec<(PathBuf, io::Error)>> {
+        let mut exported = Exported::new();
+        let mut skipped = Vec::new();
+        self.export_entry(inode, target, &mut exported, &mut skipped)?;
+        Ok(skipped)
+    }
+
+    fn export_entry(
+        &self,
+        inode: usize,
+        path: &Path,
+        exported: &mut Exported,
+        skipped: &mut Vec<(PathBuf, io::Error)>,
+    ) -> io::Result<()> {
+        let metadata = self.metadata(inode);
+        let file_type = metadata.type_perm.file_type();
+        let mode = (metadata.type_perm.bits() & !TypePerm::TYPE_MASK) as u32;
+        if file_type != TypePerm::DIRECTORY && metadata.hard_links > 1 {
+            if let Some(first) = exported.get(&inode) {
+                return fs::hard_link(first, path);
+            }
+        }
+
+        match file_type {
+            TypePerm::DIRECTORY => {
+                // an existing directory, such as the output directory, is
+                // merged into
+                if let Err(err) = fs::create_dir(path) {
+                    if err.kind() != ErrorKind::AlreadyExists || !path.is_dir() {
+                        return Err(err);
+                    }
+                }
+                for record in self.dir_records(inode) {
+                    if record.inode == 0 || record.name == b"." || record.name == b".." {
+                        continue;
+                    }
+                    let child = path.join(OsStr::from_bytes(&record.name));
+                    self.export_entry(record.inode as usize, &child, exported, skipped)
+                        .map_err(|err| {
+                            io::Error::new(err.kind(), format!("{}: {}", child.display(), err))
+                        })?;
+                }
+            }
+            TypePerm::FILE => self.export_data(inode, path)?,
+            TypePerm::SYMLINK => {
+                let target = self.readlink(inode)?;
+                std::os::unix::fs::symlink(OsStr::from_bytes(&target), path)?;
+            }
+            _ => {
+                let created = if file_type == TypePerm::FIFO {
+                    check(unsafe { libc::mkfifo(c_path(path)?.as_ptr(), mode) })
+                } else {
+                    let kind = match file_type {
+                        TypePerm::CHAR_DEVICE => libc::S_IFCHR,
+                        TypePerm::BLOCK_DEVICE => libc::S_IFBLK,
+                        _ => libc::S_IFSOCK,
+                    };
+                    let (major, minor) = metadata.rdev.unwrap_or((0, 0));
+                    let rdev = libc::makedev(major, minor);
+                    check(unsafe { libc::mknod(c_path(path)?.as_ptr(), kind | mode, rdev) })
+                };
+                if let Err(err) = created {
+                    skipped.push((path.to_path_buf(), err));
+                    return Ok(());
+                }
+            }
+        }
+
+        // permissions and times go last, so that filling in a directory
+        // neither needs write access to it nor changes its times
+        if file_type != TypePerm::SYMLINK {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        set_times(path, metadata.atime, metadata.mtime)?;
+        if file_type != TypePerm::DIRECTORY && metadata.hard_links > 1 {
+            exported.insert(inode, path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Copy the contents of regular file `inode` to a new host file,
+    /// seeking over its holes
+    fn export_data(&self, inode: usize, path: &Path) -> io::Result<()> {
+        let host = fs::File::create(path)?;
+        let file = self.get_inode(inode);
+        let size = file.size();
+        let mut buffer = vec![0; self.block_size];
+        for index in 0..size.div_ceil(self.block_size as u64) as usize {
+            if self.block_of(file, index) == 0 {
+                continue;
+            }
+            let offset = (index * self.block_size) as u64;
+            let length = self.read(inode, offset, &mut buffer)?;
+            host.write_all_at(&buffer[..length], offset)?;
+        }
+        // covers a hole at the end
+        host.set_len(size)?;
+        Ok(())
+    }
+}
+
+/// `path` for passing to libc
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "path contains a NUL byte"))
+}
+
+/// The error of a libc call returning -1 on failure
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the access and modification times of a host file without following
+/// it if it is a symlink
+fn set_times(path: &Path, atime: u32, mtime: u32) -> io::Result<()> {
+    let time = |seconds: u32| libc::timespec {
+        tv_sec: seconds as libc::time_t,
+        tv_nsec: 0,
+    };
+    let times = [time(atime), time(mtime)];
+    check(unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path(path)?.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })
+}
+
+/// Fill `buffer` from `source`, stopping short only at its end
+fn read_block(source: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match source.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// (major, minor) of a Linux `dev_t`
+fn split_rdev(rdev: u64) -> (u32, u32) {
+    let major = (rdev >> 8) & 0xfff | (rdev >> 32) & !0xfff;
+    let minor = rdev & 0xff | (rdev >> 12) & !0xff;
+    (major as u32, minor as u32)
+}
@@ -0,0 +1,653 @@
+// Hashed directory indexes (dir_index, "htree"). The first block of an
+// indexed directory holds `.` and `..` and then a table of name hashes and
+// the directory blocks whose entries start at each hash, optionally through
+// one more level of such tables; the leaves are ordinary directory blocks.
+// Old implementations see the index blocks as blocks with one huge empty
+// entry, so the directory still reads correctly without the index.
+// https://www.kernel.org/doc/html/latest/filesystems/ext4/directory.html#hash-tree-directories
+
+use crate::ext2::dir::{record_size, DirRecord};
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::features::CompatFeatures;
+use crate::ext2::namei::ROOT_INODE;
+use crate::ext2::structs::{InodeFlags, TypeIndicator, FLAGS_UNSIGNED_HASH};
+use crate::ext2::Ext2;
+
+/// `dx_root_info::hash_version` values; the unsigned variants are only
+/// chosen by the superblock's `FLAGS_UNSIGNED_HASH`, never stored
+pub(crate) const HASH_LEGACY: u8 = 0;
+pub(crate) const HASH_HALF_MD4: u8 = 1;
+pub(crate) const HASH_TEA: u8 = 2;
+pub(crate) const HASH_LEGACY_UNSIGNED: u8 = 3;
+pub(crate) const HASH_HALF_MD4_UNSIGNED: u8 = 4;
+pub(crate) const HASH_TEA_UNSIGNED: u8 = 5;
+
+/// Offset of `dx_root_info`, after the `.` and `..` entries
+const ROOT_INFO: usize = 24;
+/// Size of `dx_root_info`
+const ROOT_INFO_LENGTH: usize = 8;
+/// Size of a `(hash, block)` index entry
+const DX_ENTRY: usize = 8;
+/// Deepest tree the kernel builds without the large_dir feature
+const MAX_INDIRECT_LEVELS: u8 = 1;
+/// The last hash a name may have, kept free to mean end-of-directory
+const HASH_EOF: u32 = 0x7fffffff;
+
+/// Seed used when the superblock's is all zero
+const DEFAULT_SEED: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+/// The legacy hash used before half-MD4
+fn dx_hack_hash(name: &[u8], unsigned: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12a3fe2du32, 0x37abe8f9u32);
+    for &byte in name {
+        let c = if unsigned {
+            byte as i32
+        } else {
+            byte as i8 as i32
+        };
+        let mut hash = hash1.wrapping_add(hash0 ^ c.wrapping_mul(7152373) as u32);
+        if hash & 0x80000000 != 0 {
+            hash = hash.wrapping_sub(0x7fffffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Pack up to `buf.len()` words of `name` big-end first, padding with a
+/// word made of its length
+fn str2hashbuf(name: &[u8], length: usize, buf: &mut [u32], unsigned: bool) {
+    let mut pad = length as u32 | (length as u32) << 8;
+    pad |= pad << 16;
+    let mut val = pad;
+    let mut words = 0;
+    for (i, &byte) in name.iter().take(buf.len() * 4).enumerate() {
+        let c = if unsigned {
+            byte as u32
+        } else {
+            byte as i8 as u32
+        };
+        val = c.wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[words] = val;
+            val = pad;
+            words += 1;
+        }
+    }
+    if words < buf.len() {
+        buf[words] = val;
+        words += 1;
+    }
+    buf[words..].fill(pad);
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let [mut a, mut b, mut c, mut d] = *buf;
+    macro_rules! round {
+        ($f:ident, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a
+                .wrapping_add($f($b, $c, $d))
+                .wrapping_add($x)
+                .rotate_left($s)
+        };
+    }
+    round!(f, a, b, c, d, input[0], 3);
+    round!(f, d, a, b, c, input[1], 7);
+    round!(f, c, d, a, b, input[2], 11);
+    round!(f, b, c, d, a, input[3], 19);
+    round!(f, a, b, c, d, input[4], 3);
+    round!(f, d, a, b, c, input[5], 7);
+    round!(f, c, d, a, b, input[6], 11);
+    round!(f, b, c, d, a, input[7], 19);
+
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9e3779b9;
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let [a, b, c, d] = *input;
+    let mut sum = 0u32;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// The major and minor hash of `name` with hash `version`, as the kernel
+/// and e2fsprogs compute it, or `None` for an unknown version. The major
+/// hash always has its low bit clear.
+pub(crate) fn dirhash(version: u8, name: &[u8], seed: [u32; 4]) -> Option<(u32, u32)> {
+    let mut buf = if seed == [0; 4] { DEFAULT_SEED } else { seed };
+    let unsigned = version >= HASH_LEGACY_UNSIGNED;
+    let (hash, minor) = match version {
+        HASH_LEGACY | HASH_LEGACY_UNSIGNED => (dx_hack_hash(name, unsigned), 0),
+        HASH_HALF_MD4 | HASH_HALF_MD4_UNSIGNED => {
+            let mut input = [0u32; 8];
+            for (i, chunk) in name.chunks(32).enumerate() {
+                str2hashbuf(chunk, name.len() - 32 * i, &mut input, unsigned);
+                half_md4_transform(&mut buf, &input);
+            }
+            (buf[1], buf[2])
+        }
+        HASH_TEA | HASH_TEA_UNSIGNED => {
+            let mut input = [0u32; 4];
+            for (i, chunk) in name.chunks(16).enumerate() {
+                str2hashbuf(chunk, name.len() - 16 * i, &mut input, unsigned);
+                tea_transform(&mut buf, &input);
+            }
+            (buf[0], buf[1])
+        }
+        _ => return None,
+    };
+    let mut hash = hash & !1;
+    if hash == HASH_EOF << 1 {
+        hash = (HASH_EOF - 1) << 1;
+    }
+    Some((hash, minor))
+}
+
+/// One index block on the way down the tree: where its table is, the
+/// table's `(hash, block)` entries, the first of which stands for every
+/// hash below the second, and which of them was followed
+struct Frame {
+    /// Device block holding the table
+    block: u32,
+    /// Offset of the table, which starts with its limit and count in
+    /// place of the first entry's hash
+    offset: usize,
+    limit: usize,
+    entries: Vec<(u32, u32)>,
+    at: usize,
+}
+
+/// The root of a directory's index
+struct DxRoot {
+    frame: Frame,
+    /// The hash to use, with the superblock's choice of signed or
+    /// unsigned chars applied
+    version: u8,
+    /// Levels of index nodes below the root
+    levels: u8,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Ext2 {
+    /// Whether `dir` should be looked up through its hash index
+    pub(crate) fn is_indexed(&self, dir: usize) -> bool {
+        self.superblock.compat().contains(CompatFeatures::DIR_INDEX)
+            && self.get_inode(dir).flags.contains(InodeFlags::INDEX)
+    }
+
+    /// The hash an index that says it uses `stored` actually uses
+    fn hash_version(&self, stored: u8) -> u8 {
+        if stored <= HASH_TEA && self.superblock.flags & FLAGS_UNSIGNED_HASH != 0 {
+            stored + HASH_LEGACY_UNSIGNED
+        } else {
+            stored
+        }
+    }
+
+    /// The device block holding block `index` of `dir`, if the directory
+    /// has one there
+    fn dir_block(&self, dir: usize, index: u32) -> Option<u32> {
+        let inode = self.get_inode(dir);
+        if index as usize >= inode.size_low as usize / self.block_size {
+            return None;
+        }
+        match self.block_of(inode, index as usize) {
+            0 => None,
+            block => Some(block),
+        }
+    }
+
+    /// The index table at `offset` in `block`, if its limit is `limit` and
+    /// its count makes sense
+    fn dx_frame(&self, block: u32, offset: usize, limit: usize) -> Option<Frame> {
+        let bytes = self.block(block);
+        if read_u16(bytes, offset) as usize != limit {
+            return None;
+        }
+        let count = read_u16(bytes, offset + 2) as usize;
+        if count == 0 || count > limit {
+            return None;
+        }
+        let entries = (0..count)
+            .map(|i| {
+                let entry = offset + i * DX_ENTRY;
+                let hash = if i == 0 { 0 } else { read_u32(bytes, entry) };
+                (hash, read_u32(bytes, entry + 4) & 0x0fffffff)
+            })
+            .collect();
+        Some(Frame {
+            block,
+            offset,
+            limit,
+            entries,
+            at: 0,
+        })
+    }
+
+    /// The root of the index of `dir`, if it makes sense
+    fn dx_root(&self, dir: usize) -> Option<DxRoot> {
+        let block = self.dir_block(dir, 0)?;
+        let bytes = self.block(block);
+        // `..` covers the rest of the block, hiding the index
+        if read_u16(bytes, 12 + 4) as usize != self.block_size - 12
+            || read_u32(bytes, ROOT_INFO) != 0
+        {
+            return None;
+        }
+        let version = bytes[ROOT_INFO + 4];
+        let info_length = bytes[ROOT_INFO + 5] as usize;
+        let levels = bytes[ROOT_INFO + 6];
+        let unused_flags = bytes[ROOT_INFO + 7];
+        if version > HASH_TEA
+            || info_length != ROOT_INFO_LENGTH
+            || levels > MAX_INDIRECT_LEVELS
+            || unused_flags & 1 != 0
+        {
+            return None;
+        }
+        let offset = ROOT_INFO + info_length;
+        Some(DxRoot {
+            frame: self.dx_frame(block, offset, (self.block_size - offset) / DX_ENTRY)?,
+            version: self.hash_version(version),
+            levels,
+        })
+    }
+
+    /// Follow `frames` down to a leaf of a tree with `levels` levels below
+    /// the root, taking the entry for `hash` in each index block on the way
+    /// (or the first, for `None`), and return the leaf's directory block
+    fn dx_descend(
+        &self,
+        dir: usize,
+        levels: u8,
+        frames: &mut Vec<Frame>,
+        hash: Option<u32>,
+    ) -> Option<u32> {
+        loop {
+            let frame = frames.last_mut().unwrap();
+            if let Some(hash) = hash {
+                // the last entry whose hash is at most `hash`
+                frame.at = frame.entries[1..].partition_point(|&(start, _)| start <= hash);
+            }
+            let index = frame.entries[frame.at].1;
+            if frames.len() > levels as usize {
+                return Some(index);
+            }
+            let block = self.dir_block(dir, index)?;
+            let bytes = self.block(block);
+            // an index node passes for a block with one empty entry
+            if read_u32(bytes, 0) != 0 || read_u16(bytes, 4) as usize != self.block_size {
+                return None;
+            }
+            frames.push(self.dx_frame(block, 8, (self.block_size - 8) / DX_ENTRY)?);
+        }
+    }
+
+    /// Look `name` up in the hash index of `dir`. `Some(None)` means the
+    /// index says it isn't there; `None` that the index is corrupt.
+    pub(crate) fn dx_find_record(&self, dir: usize, name: &[u8]) -> Option<Option<DirRecord>> {
+        let root = self.dx_root(dir)?;
+        let (hash, _) = dirhash(root.version, name, self.superblock.hash_seed)?;
+        let mut frames = vec![root.frame];
+        let mut leaf = self.dx_descend(dir, root.levels, &mut frames, Some(hash))?;
+        loop {
+            let block = self.dir_block(dir, leaf)?;
+            let mut records = Vec::new();
+            self.block_records(dir, block, &mut records);
+            if let Some(record) = records
+                .into_iter()
+                .find(|record| record.inode != 0 && record.name == name)
+            {
+                return Some(Some(record));
+            }
+            // names with the same hash may carry on into the next leaf,
+            // which then starts at that hash with the low bit set
+            let Some(level) = frames
+                .iter()
+                .rposition(|frame| frame.at + 1 < frame.entries.len())
+            else {
+                return Some(None);
+            };
+            let next = frames[level].entries[frames[level].at + 1].0;
+            if next & !1 != hash {
+                return Some(None);
+            }
+            frames.truncate(level + 1);
+            frames[level].at += 1;
+            leaf = self.dx_descend(dir, root.levels, &mut frames, None)?;
+        }
+    }
+
+    /// Add an entry to `dir` through its index, splitting the leaf it
+    /// belongs in if that is full, and the index block above that if it is
+    /// full too. Returns false, having added nothing, if the index is
+    /// corrupt or already as big as it can get.
+    pub(crate) fn dx_add_entry(
+        &mut self,
+        dir: usize,
+        name: &[u8],
+        inode: usize,
+        file_type: u8,
+    ) -> Result<bool> {
+        // each pass adds the entry or makes room for it a level up; a
+        // split leaf can still be too full for a long name, once
+        for _ in 0..5 {
+            let Some(root) = self.dx_root(dir) else {
+                return Ok(false);
+            };
+            let Some((hash, _)) = dirhash(root.version, name, self.superblock.hash_seed) else {
+                return Ok(false);
+            };
+            let mut frames = vec![root.frame];
+            let Some(block) = self
+                .dx_descend(dir, root.levels, &mut frames, Some(hash))
+                .and_then(|leaf| self.dir_block(dir, leaf))
+            else {
+                return Ok(false);
+            };
+            let mut records = Vec::new();
+            self.block_records(dir, block, &mut records);
+            if self.insert_record(&records, name, inode, file_type) {
+                return Ok(true);
+            }
+
+            let parent = frames.last().unwrap();
+            if parent.entries.len() < parent.limit {
+                if !self.split_leaf(dir, root.version, parent, block)? {
+                    return Ok(false);
+                }
+            } else if root.levels == 0 {
+                self.grow_index(dir, &frames[0])?;
+            } else if frames[0].entries.len() < frames[0].limit {
+                self.split_node(dir, &frames[0], &frames[1])?;
+            } else {
+                return Ok(false);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Move the entries of leaf `block` with the higher hashes into a new
+    /// block at the end of `dir`, and add it to the index block `parent`
+    /// after the leaf. Returns false if there is nothing to split.
+    fn split_leaf(&mut self, dir: usize, version: u8, parent: &Frame, block: u32) -> Result<bool> {
+        let mut records = Vec::new();
+        self.block_records(dir, block, &mut records);
+        let seed = self.superblock.hash_seed;
+        let mut hashed: Vec<(u32, DirRecord)> = records
+            .into_iter()
+            .filter(|record| record.inode != 0)
+            .map(|record| {
+                let hash = dirhash(version, &record.name, seed).map_or(0, |(hash, _)| hash);
+                (hash, record)
+            })
+            .collect();
+        if hashed.len() < 2 {
+            return Ok(false);
+        }
+        hashed.sort_by_key(|&(hash, _)| hash);
+        // about half the bytes go
+        let total: usize = hashed
+            .iter()
+            .map(|(_, record)| record_size(record.name.len()))
+            .sum();
+        let mut split = hashed.len();
+        let mut moved = 0;
+        while split > 1 && moved < total / 2 {
+            split -= 1;
+            moved += record_size(hashed[split].1.name.len());
+        }
+        let mut split_hash = hashed[split].0;
+        if hashed[split - 1].0 == split_hash {
+            // the hash carries on into the new leaf
+            split_hash |= 1;
+        }
+
+        let index = self.get_inode(dir).size_low / self.block_size as u32;
+        let new_block = self.extend_dir(dir)?;
+        let records: Vec<DirRecord> = hashed.into_iter().map(|(_, record)| record).collect();
+        self.write_records(block, &records[..split]);
+        self.write_records(new_block, &records[split..]);
+        self.dx_insert(parent, split_hash, index);
+        Ok(true)
+    }
+
+    /// Add `(hash, index)` to the table of `frame` after the entry that was
+    /// followed, which the caller has checked there is room for
+    fn dx_insert(&mut self, frame: &Frame, hash: u32, index: u32) {
+        let bytes = self.block_mut(frame.block);
+        let at = frame.offset + (frame.at + 1) * DX_ENTRY;
+        let end = frame.offset + frame.entries.len() * DX_ENTRY;
+        bytes.copy_within(at..end, at + DX_ENTRY);
+        bytes[at..at + 4].copy_from_slice(&hash.to_le_bytes());
+        bytes[at + 4..at + 8].copy_from_slice(&index.to_le_bytes());
+        let count = frame.entries.len() as u16 + 1;
+        bytes[frame.offset + 2..frame.offset + 4].copy_from_slice(&count.to_le_bytes());
+    }
+
+    /// Write an index table with `entries` at `offset` in `block`
+    fn write_dx_table(&mut self, block: u32, offset: usize, limit: usize, entries: &[(u32, u32)]) {
+        let bytes = self.block_mut(block);
+        for (i, &(hash, index)) in entries.iter().enumerate() {
+            let entry = offset + i * DX_ENTRY;
+            bytes[entry..entry + 4].copy_from_slice(&hash.to_le_bytes());
+            bytes[entry + 4..entry + 8].copy_from_slice(&index.to_le_bytes());
+        }
+        bytes[offset..offset + 2].copy_from_slice(&(limit as u16).to_le_bytes());
+        bytes[offset + 2..offset + 4].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+    }
+
+    /// Give the full root of `dir` a level of index nodes below it: its
+    /// table moves to a new node, which becomes the root's only entry
+    fn grow_index(&mut self, dir: usize, root: &Frame) -> Result<()> {
+        let index = self.get_inode(dir).size_low / self.block_size as u32;
+        let node = self.extend_dir(dir)?;
+        self.write_dx_table(node, 8, (self.block_size - 8) / DX_ENTRY, &root.entries);
+        self.write_dx_table(root.block, root.offset, root.limit, &[(0, index)]);
+        self.block_mut(root.block)[ROOT_INFO + 6] = 1;
+        Ok(())
+    }
+
+    /// Move the upper half of the full index node `node` to a new node,
+    /// and add that to the root after it
+    fn split_node(&mut self, dir: usize, root: &Frame, node: &Frame) -> Result<()> {
+        let index = self.get_inode(dir).size_low / self.block_size as u32;
+        let new_node = self.extend_dir(dir)?;
+        let keep = node.entries.len() / 2;
+        let moved = &node.entries[keep..];
+        self.write_dx_table(new_node, 8, node.limit, moved);
+        self.write_dx_table(node.block, node.offset, node.limit, &node.entries[..keep]);
+        self.dx_insert(root, moved[0].0, index);
+        Ok(())
+    }
+
+    /// Build a hash index for directory `dir`, or rebuild the one it has,
+    /// as `e2fsck -D` does: the entries are sorted by hash and packed into
+    /// leaf blocks after the root, and after a level of index nodes if the
+    /// root can't list every leaf. A directory that fits in one block
+    /// needs no index and is only compacted.
+    pub fn index_dir(&mut self, dir: usize) -> Result<()> {
+        self.check_writable()?;
+        if !self.get_inode(dir).type_perm.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+        if !self.superblock.compat().contains(CompatFeatures::DIR_INDEX) {
+            return Err(Ext2Error::InvalidArgument(
+                "filesystem doesn't have the dir_index feature",
+            ));
+        }
+        let stored = self.superblock.def_hash_version;
+        let version = self.hash_version(stored);
+        let seed = self.superblock.hash_seed;
+        let mut parent = dir;
+        let mut hashed = Vec::new();
+        for record in self.dir_records(dir) {
+            match &record.name[..] {
+                _ if record.inode == 0 => {}
+                b"." => {}
+                b".." => parent = record.inode as usize,
+                name => {
+                    let (hash, minor) = dirhash(version, name, seed)
+                        .ok_or(Ext2Error::InvalidArgument("unknown directory hash"))?;
+                    hashed.push((hash, minor, record));
+                }
+            }
+        }
+        let dots = record_size(1) + record_size(2);
+        let total: usize = hashed
+            .iter()
+            .map(|(_, _, record)| record_size(record.name.len()))
+            .sum();
+        if dots + total <= self.block_size {
+            self.inode_mut(dir).flags.remove(InodeFlags::INDEX);
+            return self.compact_dir(dir);
+        }
+
+        // each leaf starts at the hash of its first entry, with the low
+        // bit set if the leaf before it ends with the same hash
+        hashed.sort_by_key(|&(hash, minor, _)| (hash, minor));
+        let mut leaves: Vec<(u32, Vec<DirRecord>)> = Vec::new();
+        let mut used = self.block_size;
+        let mut last_hash = None;
+        for (hash, _, record) in hashed {
+            let size = record_size(record.name.len());
+            if used + size > self.block_size {
+                let start = match last_hash {
+                    None => 0,
+                    Some(last) if last == hash => hash | 1,
+                    Some(_) => hash,
+                };
+                leaves.push((start, Vec::new()));
+                used = 0;
+            }
+            leaves.last_mut().unwrap().1.push(record);
+            used += size;
+            last_hash = Some(hash);
+        }
+        let root_limit = (self.block_size - ROOT_INFO - ROOT_INFO_LENGTH) / DX_ENTRY;
+        let node_limit = (self.block_size - 8) / DX_ENTRY;
+        let nodes = if leaves.len() <= root_limit {
+            0
+        } else {
+            leaves.len().div_ceil(node_limit)
+        };
+        if nodes > root_limit {
+            return Err(Ext2Error::NoSpace);
+        }
+
+        // the root, then the nodes, then the leaves
+        let first_leaf = 1 + nodes;
+        let mut entries = Vec::new();
+        for (i, (start, records)) in leaves.iter().enumerate() {
+            let block = self.block_of_alloc(dir, first_leaf + i)?;
+            self.write_records(block, records);
+            entries.push((*start, (first_leaf + i) as u32));
+        }
+        if nodes > 0 {
+            let mut root_entries = Vec::new();
+            for (i, chunk) in entries.chunks(node_limit).enumerate() {
+                let block = self.block_of_alloc(dir, 1 + i)?;
+                self.write_records(block, &[]);
+                self.write_dx_table(block, 8, node_limit, chunk);
+                root_entries.push((chunk[0].0, 1 + i as u32));
+            }
+            entries = root_entries;
+        }
+        let root = self.block_of_alloc(dir, 0)?;
+        let file_type = if self.has_file_type() {
+            TypeIndicator::Directory as u8
+        } else {
+            0
+        };
+        self.write_record(root, 0, record_size(1), dir, b".", file_type);
+        self.write_record(
+            root,
+            record_size(1),
+            self.block_size - record_size(1),
+            parent,
+            b"..",
+            file_type,
+        );
+        let bytes = self.block_mut(root);
+        bytes[ROOT_INFO + 4] = stored;
+        bytes[ROOT_INFO + 5] = ROOT_INFO_LENGTH as u8;
+        bytes[ROOT_INFO + 6] = (nodes > 0) as u8;
+        self.write_dx_table(root, ROOT_INFO + ROOT_INFO_LENGTH, root_limit, &entries);
+
+        let blocks = first_leaf + leaves.len();
+        self.free_blocks_from(dir, blocks);
+        let size = (blocks * self.block_size) as u32;
+        let file = self.inode_mut(dir);
+        file.size_low = size;
+        file.flags.insert(InodeFlags::INDEX);
+        self.touch_dir(dir);
+        Ok(())
+    }
+
+    /// Index every directory that spans more than one block, as `e2fsck -D`
+    /// does, and return them. /lost+found is left alone, since it is kept
+    /// big on purpose.
+    pub fn index_dirs(&mut self) -> Result<Vec<usize>> {
+        let lost_found = self.lookup(ROOT_INODE, "lost+found").ok();
+        let inodes_count = self.superblock.inodes_count as usize;
+        let mut indexed = Vec::new();
+        for dir in std::iter::once(ROOT_INODE).chain(self.first_inode()..=inodes_count) {
+            let file = self.get_inode(dir);
+            if file.hard_links == 0
+                || !file.type_perm.is_dir()
+                || file.size_low as usize <= self.block_size
+                || Some(dir) == lost_found
+            {
+                continue;
+            }
+            self.index_dir(dir)?;
+            indexed.push(dir);
+        }
+        Ok(indexed)
+    }
+}
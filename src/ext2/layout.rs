@@ -0,0 +1,131 @@
+// Where the fixed metadata of each block group lives: superblock and group
+// descriptor table copies, bitmaps and the inode table.
+// https://wiki.osdev.org/Ext2#Block_Groups
+
+use crate::ext2::error::Result;
+use crate::ext2::features::{CompatFeatures, RoCompatFeatures};
+use crate::ext2::structs::Superblock;
+use crate::ext2::{Ext2, EXT2_END_OF_SUPERBLOCK, EXT2_START_OF_SUPERBLOCK};
+
+/// Inode holding the reserved GDT blocks when resize_inode is set
+pub(crate) const RESIZE_INODE: usize = 7;
+
+/// Whether a group holds a superblock copy when sparse_super is set:
+/// groups 0 and 1 and the powers of 3, 5 and 7
+pub(crate) fn sparse_group(group: usize) -> bool {
+    if group <= 1 {
+        return true;
+    }
+    [3, 5, 7].iter().any(|&base| {
+        let mut power = base;
+        while power < group {
+            power *= base;
+        }
+        power == group
+    })
+}
+
+impl Ext2 {
+    /// Whether block group `group` starts with a copy of the superblock and
+    /// group descriptor table
+    pub(crate) fn group_has_superblock(&self, group: usize) -> bool {
+        !self
+            .superblock
+            .ro_compat()
+            .contains(RoCompatFeatures::SPARSE_SUPER)
+            || sparse_group(group)
+    }
+
+    /// Whether `block` is inside the filesystem, so a block pointer to it
+    /// can be followed
+    pub(crate) fn valid_block(&self, block: u32) -> bool {
+        block >= self.superblock.first_data_block && block < self.superblock.blocks_count
+    }
+
+    /// First block of block group `group`
+    pub(crate) fn group_first_block(&self, group: usize) -> u32 {
+        self.superblock.first_data_block + (group as u32) * self.superblock.blocks_per_group
+    }
+
+    /// Blocks taken by one copy of the group descriptor table
+    pub(crate) fn gdt_blocks(&self) -> usize {
+        std::mem::size_of_val(self.block_groups).div_ceil(self.block_size)
+    }
+
+    /// Blocks kept free after each GDT copy for growing the filesystem
+    pub(crate) fn reserved_gdt_blocks(&self) -> usize {
+        if self
+            .superblock
+            .compat()
+            .contains(CompatFeatures::RESIZE_INODE)
+        {
+            self.superblock.reserved_gdt_blocks as usize
+        } else {
+            0
+        }
+    }
+
+    /// Blocks taken by the inode table of one group
+    pub(crate) fn inode_table_blocks(&self) -> usize {
+        (self.superblock.inodes_per_group as usize * self.inode_size()).div_ceil(self.block_size)
+    }
+
+    /// Every block of group `group` holding filesystem metadata rather than
+    /// file data
+    pub(crate) fn group_metadata_blocks(&self, group: usize) -> Vec<u32> {
+        let mut blocks = Vec::new();
+        if self.group_has_superblock(group) {
+            let first = self.group_first_block(group);
+            let count = 1 + self.gdt_blocks() + self.reserved_gdt_blocks();
+            blocks.extend(first..first + count as u32);
+        }
+        let desc = &self.block_groups[group];
+        blocks.push(desc.block_usage_addr);
+        blocks.push(desc.inode_usage_addr);
+        let table = desc.inode_table_block;
+        blocks.extend(table..table + self.inode_table_blocks() as u32);
+        blocks
+    }
+
+    /// Bring the backup superblocks and group descriptor tables up to date
+    /// with the primary. Writes only change the primary as they happen, so
+    /// call this when done with a batch of them, before the image is saved,
+    /// to keep it repairable from the backups by e2fsck and `fsck -b`.
+    pub fn sync(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.write_backups();
+        Ok(())
+    }
+
+    /// Copy the primary superblock and group descriptor table over the
+    /// backups in the groups that carry them, each numbered with its own
+    /// group
+    fn write_backups(&mut self) {
+        let superblock = unsafe {
+            std::slice::from_raw_parts(
+                self.superblock as *const Superblock as *const u8,
+                EXT2_END_OF_SUPERBLOCK - EXT2_START_OF_SUPERBLOCK,
+            )
+        }
+        .to_vec();
+        let gdt_first = self.superblock.first_data_block + 1;
+        let gdt: Vec<u8> = (gdt_first..gdt_first + self.gdt_blocks() as u32)
+            .flat_map(|block| self.block(block).to_vec())
+            .collect();
+        for group in 1..self.block_groups.len() {
+            if !self.group_has_superblock(group) {
+                continue;
+            }
+            // a backup superblock is at the start of its group's first
+            // block, whatever the block size
+            let first = self.group_first_block(group);
+            let copy = self.block_mut(first);
+            copy[..superblock.len()].copy_from_slice(&superblock);
+            let copy = unsafe { &mut *(copy.as_mut_ptr() as *mut Superblock) };
+            copy.block_group = group as u16;
+            for (i, chunk) in gdt.chunks(self.block_size).enumerate() {
+                self.block_mut(first + 1 + i as u32).copy_from_slice(chunk);
+            }
+        }
+    }
+}
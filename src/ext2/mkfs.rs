@@ -0,0 +1,424 @@
+// Laying out a new filesystem on a blank device the way mke2fs does. Every
+// block group starts with a copy of the superblock and group descriptor
+// table (only some groups with sparse_super), then its two bitmaps and its
+// inode table, and the rest of it is free. The root directory and
+// lost+found are then made with the ordinary allocation code.
+// https://www.nongnu.org/ext2-doc/ext2.html#disk-organisation
+
+use crate::ext2::alloc::set_bit;
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::features::{CompatFeatures, Feature, IncompatFeatures, RoCompatFeatures};
+use crate::ext2::file::DIRECT_POINTERS;
+use crate::ext2::layout::sparse_group;
+use crate::ext2::namei::ROOT_INODE;
+use crate::ext2::structs::{
+    BlockGroupDescriptor, Superblock, TypePerm, ERR_IGNORE, ERR_PANIC, ERR_RONLY,
+    FLAGS_SIGNED_HASH, FS_CLEAN,
+};
+use crate::ext2::{Ext2, EXT2_MAGIC, EXT2_START_OF_SUPERBLOCK};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use uuid::Uuid;
+use zerocopy::ByteSliceMut;
+
+/// Inodes below this are reserved for the filesystem itself
+const FIRST_INODE: u32 = 11;
+/// lost+found is made this big (as far as its direct blocks allow), so
+/// e2fsck can reconnect inodes to it without allocating blocks
+const LOST_FOUND_SIZE: usize = 16 * 1024;
+const MIB: usize = 1024 * 1024;
+/// Directory indexes hash with half-MD4 unless they say otherwise, as
+/// mke2fs sets up
+const DEFAULT_HASH_VERSION: u8 = 1;
+
+/// How `Ext2::format` lays out a filesystem
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// 1024, 2048 or 4096 bytes. By default 1024 below 512 MiB, as mke2fs
+    /// chooses, and 4096 from there on.
+    pub block_size: Option<usize>,
+    /// A power of two from 128 up to the block size
+    pub inode_size: usize,
+    /// By default one inode for every 4 KiB of space, or every 16 KiB from
+    /// 512 MiB on. Rounded up to fill whole blocks of the inode table.
+    pub inodes_per_group: Option<u32>,
+    /// Up to 16 bytes
+    pub label: String,
+    /// A random one is generated if not given
+    pub uuid: Option<Uuid>,
+    /// Seed for hashing names in directory indexes, random if not given
+    pub hash_seed: Option<Uuid>,
+    /// Build reproducibly: this is the time of every timestamp, both while
+    /// formatting and later through the returned `Ext2` (see
+    /// `Ext2::set_fixed_time`), and the UUID and hash seed are derived from
+    /// the other options rather than random unless given.
+    pub fixed_time: Option<u32>,
+    /// What to do about corruption found while mounted: `ERR_IGNORE` (the
+    /// default), `ERR_RONLY` or `ERR_PANIC`
+    pub errors: u16,
+    /// Only the features `Ext2::new` supports can be laid out, and of the
+    /// compat ones only `dir_index` and `ext_attr`
+    pub features_opt: CompatFeatures,
+    pub features_req: IncompatFeatures,
+    pub features_ronly: RoCompatFeatures,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            block_size: None,
+            inode_size: 256,
+            inodes_per_group: None,
+            label: String::new(),
+            uuid: None,
+            hash_seed: None,
+            fixed_time: None,
+            errors: ERR_IGNORE,
+            features_opt: CompatFeatures::empty(),
+            features_req: IncompatFeatures::FILETYPE,
+            features_ronly: RoCompatFeatures::SPARSE_SUPER | RoCompatFeatures::LARGE_FILE,
+        }
+    }
+}
+
+impl FormatOptions {
+    /// Turn a feature on or off by its mke2fs name, e.g. `sparse_super`
+    pub fn set_feature(&mut self, name: &str, enabled: bool) -> Result<()> {
+        let feature =
+            Feature::from_name(name).ok_or(Ext2Error::InvalidArgument("unknown feature"))?;
+        match feature {
+            Feature::Compat(flag) => self.features_opt.set(flag, enabled),
+            Feature::Incompat(flag) => self.features_req.set(flag, enabled),
+            Feature::RoCompat(flag) => self.features_ronly.set(flag, enabled),
+        }
+        Ok(())
+    }
+}
+
+/// Where everything goes, worked out before anything is written
+struct Geometry {
+    block_size: usize,
+    inode_size: usize,
+    blocks_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    group_count: usize,
+    inodes_per_group: u32,
+    gdt_blocks: usize,
+    inode_table_blocks: usize,
+    sparse_super: bool,
+}
+
+impl Geometry {
+    fn new(device_len: usize, options: &FormatOptions) -> Result<Geometry> {
+        let block_size =
+            options
+                .block_size
+                .unwrap_or(if device_len < 512 * MIB { 1024 } else { 4096 });
+        if ![1024, 2048, 4096].contains(&block_size) {
+            return Err(Ext2Error::InvalidArgument(
+                "block size must be 1024, 2048 or 4096",
+            ));
+        }
+        let inode_size = options.inode_size;
+        if !inode_size.is_power_of_two() || inode_size < 128 || inode_size > block_size {
+            return Err(Ext2Error::InvalidArgument(
+                "inode size must be a power of two from 128 to the block size",
+            ));
+        }
+        let first_data_block = (block_size == 1024) as u32;
+        let blocks_count = (device_len / block_size).min(u32::MAX as usize) as u32;
+        if blocks_count <= first_data_block {
+            return Err(Ext2Error::InvalidArgument("device is too small"));
+        }
+        let mut geometry = Geometry {
+            block_size,
+            inode_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group: 8 * block_size as u32,
+            group_count: 0,
+            inodes_per_group: 0,
+            gdt_blocks: 0,
+            inode_table_blocks: 0,
+            sparse_super: options
+                .features_ronly
+                .contains(RoCompatFeatures::SPARSE_SUPER),
+        };
+        geometry.count_groups();
+
+        let inodes_per_group = match options.inodes_per_group {
+            Some(inodes_per_group) => inodes_per_group,
+            None => {
+                let bytes_per_inode = if device_len < 512 * MIB { 4096 } else { 16384 };
+                let inodes = blocks_count as u64 * block_size as u64 / bytes_per_inode;
+                inodes.div_ceil(geometry.group_count as u64) as u32
+            }
+        };
+        // whole blocks of the inode table and whole bytes of the bitmap,
+        // and group 0 needs room for the reserved inodes and lost+found
+        let inodes_per_block = (block_size / inode_size) as u32;
+        let inodes_per_group = inodes_per_group
+            .max(FIRST_INODE + 1)
+            .next_multiple_of(inodes_per_block.max(8));
+        if inodes_per_group > 8 * block_size as u32 {
+            return Err(Ext2Error::InvalidArgument(
+                "more inodes per group than an inode bitmap can hold",
+            ));
+        }
+        geometry.inodes_per_group = inodes_per_group;
+        geometry.inode_table_blocks = inodes_per_group as usize * inode_size / block_size;
+
+        // like mke2fs, drop a last group too small to be worth its metadata
+        let last = geometry.group_count - 1;
+        if last > 0 && geometry.blocks_in_group(last) < geometry.overhead(last) + 50 {
+            geometry.blocks_count -= geometry.blocks_in_group(last) as u32;
+            geometry.count_groups();
+        }
+        if inodes_per_group as u64 * geometry.group_count as u64 > u32::MAX as u64 {
+            return Err(Ext2Error::InvalidArgument("too many inodes"));
+        }
+        // room for the root directory and lost+found
+        let needed = geometry.overhead(0) + 1 + LOST_FOUND_SIZE.div_ceil(block_size);
+        if (0..geometry.group_count)
+            .any(|group| geometry.blocks_in_group(group) < geometry.overhead(group))
+            || geometry.blocks_in_group(0) < needed
+        {
+            return Err(Ext2Error::InvalidArgument("device is too small"));
+        }
+        Ok(geometry)
+    }
+
+    fn count_groups(&mut self) {
+        self.group_count =
+            (self.blocks_count - self.first_data_block).div_ceil(self.blocks_per_group) as usize;
+        self.gdt_blocks = (self.group_count * std::mem::size_of::<BlockGroupDescriptor>())
+            .div_ceil(self.block_size);
+    }
+
+    fn group_first_block(&self, group: usize) -> u32 {
+        self.first_data_block + group as u32 * self.blocks_per_group
+    }
+
+    fn blocks_in_group(&self, group: usize) -> usize {
+        (self.blocks_count - self.group_first_block(group)).min(self.blocks_per_group) as usize
+    }
+
+    fn has_superblock(&self, group: usize) -> bool {
+        !self.sparse_super || sparse_group(group)
+    }
+
+    /// First block of the bitmaps and inode table of `group`, which follow
+    /// the superblock and GDT copies if the group has them
+    fn bitmaps_start(&self, group: usize) -> u32 {
+        let mut block = self.group_first_block(group);
+        if self.has_superblock(group) {
+            block += 1 + self.gdt_blocks as u32;
+        }
+        block
+    }
+
+    /// Metadata blocks at the start of `group`
+    fn overhead(&self, group: usize) -> usize {
+        (self.bitmaps_start(group) - self.group_first_block(group)) as usize
+            + 2
+            + self.inode_table_blocks
+    }
+
+    fn free_blocks(&self) -> u32 {
+        (0..self.group_count)
+            .map(|group| (self.blocks_in_group(group) - self.overhead(group)) as u32)
+            .sum()
+    }
+
+    fn write_superblock(&self, device: &mut [u8], options: &FormatOptions) {
+        let superblock =
+            unsafe { &mut *(device[EXT2_START_OF_SUPERBLOCK..].as_mut_ptr() as *mut Superblock) };
+        let inodes_count = self.inodes_per_group * self.group_count as u32;
+        let log_block_size = (self.block_size / 1024).trailing_zeros();
+        superblock.inodes_count = inodes_count;
+        superblock.blocks_count = self.blocks_count;
+        // 5% for root, as mke2fs reserves by default
+        superblock.r_blocks_count = self.blocks_count / 20;
+        superblock.free_blocks_count = self.free_blocks();
+        superblock.free_inodes_count = inodes_count - (FIRST_INODE - 1);
+        superblock.first_data_block = self.first_data_block;
+        superblock.log_block_size = log_block_size;
+        superblock.log_frag_size = log_block_size as i32;
+        superblock.blocks_per_group = self.blocks_per_group;
+        superblock.frags_per_group = self.blocks_per_group;
+        superblock.inodes_per_group = self.inodes_per_group;
+        superblock.max_mnt_count = -1;
+        superblock.magic = EXT2_MAGIC;
+        superblock.state = FS_CLEAN;
+        superblock.errors = options.errors;
+        superblock.rev_major = 1;
+        superblock.first_inode = FIRST_INODE;
+        superblock.inode_size = self.inode_size as u16;
+        superblock.features_opt = options.features_opt;
+        superblock.features_req = options.features_req;
+        superblock.features_ronly = options.features_ronly;
+        let uuid = options
+            .uuid
+            .unwrap_or_else(|| self.default_uuid(options, "uuid"));
+        superblock.fs_id = *uuid.as_bytes();
+        let hash_seed = options
+            .hash_seed
+            .unwrap_or_else(|| self.default_uuid(options, "hash_seed"));
+        for (word, bytes) in superblock
+            .hash_seed
+            .iter_mut()
+            .zip(hash_seed.as_bytes().chunks(4))
+        {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        superblock.def_hash_version = DEFAULT_HASH_VERSION;
+        if options.features_opt.contains(CompatFeatures::DIR_INDEX) {
+            // what mke2fs picks on x86, where char is signed
+            superblock.flags |= FLAGS_SIGNED_HASH;
+        }
+        superblock.volume_name[..options.label.len()].copy_from_slice(options.label.as_bytes());
+    }
+
+    /// Fill in the group descriptors of the primary GDT and the bitmaps
+    /// they point at
+    fn write_groups(&self, device: &mut [u8]) {
+        let gdt_start = (self.first_data_block as usize + 1) * self.block_size;
+        let bitmap_bits = 8 * self.block_size;
+        for group in 0..self.group_count {
+            let start = self.bitmaps_start(group);
+            let overhead = self.overhead(group);
+            let blocks = self.blocks_in_group(group);
+            let reserved_inodes = if group == 0 { FIRST_INODE - 1 } else { 0 };
+
+            let offset = gdt_start + group * std::mem::size_of::<BlockGroupDescriptor>();
+            let desc =
+                unsafe { &mut *(device[offset..].as_mut_ptr() as *mut BlockGroupDescriptor) };
+            desc.block_usage_addr = start;
+            desc.inode_usage_addr = start + 1;
+            desc.inode_table_block = start + 2;
+            desc.free_blocks_count = (blocks - overhead) as u16;
+            desc.free_inodes_count = (self.inodes_per_group - reserved_inodes) as u16;
+
+            // the metadata is in use, and so are the bits past the end of
+            // the group, which have no block or inode behind them
+            let block_bitmap = &mut device[start as usize * self.block_size..][..self.block_size];
+            for bit in (0..overhead).chain(blocks..bitmap_bits) {
+                set_bit(block_bitmap, bit, true);
+            }
+            let inode_bitmap =
+                &mut device[(start as usize + 1) * self.block_size..][..self.block_size];
+            let inodes = self.inodes_per_group as usize;
+            for bit in (0..reserved_inodes as usize).chain(inodes..bitmap_bits) {
+                set_bit(inode_bitmap, bit, true);
+            }
+        }
+    }
+
+    /// A random UUID, or for a reproducible build one that depends only on
+    /// the layout, options and `purpose`
+    fn default_uuid(&self, options: &FormatOptions, purpose: &str) -> Uuid {
+        let Some(time) = options.fixed_time else {
+            return random_uuid();
+        };
+        let description = format!(
+            "{} {} {} {} {} {} {}",
+            purpose,
+            time,
+            self.blocks_count,
+            self.block_size,
+            self.inodes_per_group,
+            self.inode_size,
+            options.label
+        );
+        let mut bytes = [0; 16];
+        for (i, chunk) in bytes.chunks_mut(8).enumerate() {
+            chunk.copy_from_slice(&fnv1a(i as u8, description.as_bytes()).to_le_bytes());
+        }
+        uuid::Builder::from_random_bytes(bytes).into_uuid()
+    }
+}
+
+/// The 64-bit FNV-1a hash of `salt` followed by `data`. Unlike the std
+/// hashers its output is fixed, whatever the Rust version.
+fn fnv1a(salt: u8, data: &[u8]) -> u64 {
+    std::iter::once(&salt)
+        .chain(data)
+        .fold(0xcbf29ce484222325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// A version 4 UUID. The std hasher is seeded randomly for each process,
+/// which saves depending on a random number generator.
+fn random_uuid() -> Uuid {
+    let state = RandomState::new();
+    let mut bytes = [0; 16];
+    for (i, chunk) in bytes.chunks_mut(8).enumerate() {
+        let mut hasher = state.build_hasher();
+        hasher.write_usize(i);
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
+
+impl Ext2 {
+    /// Lay out an empty filesystem over the whole of `device_bytes`, with a
+    /// root directory and lost+found, and open it for writing. A partial
+    /// block at the end of the device is left unused.
+    pub fn format<B: ByteSliceMut + std::fmt::Debug>(
+        mut device_bytes: B,
+        options: &FormatOptions,
+    ) -> Result<Ext2> {
+        if options.label.len() > 16 {
+            return Err(Ext2Error::InvalidArgument(
+                "volume label is longer than 16 bytes",
+            ));
+        }
+        if !(CompatFeatures::DIR_INDEX | CompatFeatures::EXT_ATTR).contains(options.features_opt)
+            || !IncompatFeatures::SUPPORTED.contains(options.features_req)
+            || !RoCompatFeatures::SUPPORTED.contains(options.features_ronly)
+        {
+            return Err(Ext2Error::InvalidArgument("unsupported feature"));
+        }
+        if !matches!(options.errors, ERR_IGNORE | ERR_RONLY | ERR_PANIC) {
+            return Err(Ext2Error::InvalidArgument("unknown errors behavior"));
+        }
+        let geometry = Geometry::new(device_bytes.len(), options)?;
+
+        device_bytes.fill(0);
+        geometry.write_superblock(&mut device_bytes, options);
+        geometry.write_groups(&mut device_bytes);
+        let start_addr = device_bytes.as_ptr() as usize;
+        let mut ext2 = Ext2::new_mut(device_bytes, start_addr)?;
+        ext2.set_fixed_time(options.fixed_time);
+        ext2.make_root()?;
+        ext2.sync()?;
+        Ok(ext2)
+    }
+
+    /// Give a freshly laid out filesystem its root directory and lost+found
+    fn make_root(&mut self) -> Result<()> {
+        let now = self.now();
+        let superblock = self.superblock_mut();
+        superblock.mkfs_time = now;
+        superblock.wtime = now;
+        superblock.lastcheck = now;
+
+        let root = self.inode_mut(ROOT_INODE);
+        root.type_perm = TypePerm::from_bits_truncate(TypePerm::DIRECTORY.bits() | 0o755);
+        root.atime = now;
+        root.ctime = now;
+        root.mtime = now;
+        self.init_dir(ROOT_INODE, ROOT_INODE)?;
+        self.group_mut(0).dirs_count += 1;
+
+        let lost_found = self.mkdir(ROOT_INODE, "lost+found", 0o700)?;
+        while (self.get_inode(lost_found).size_low as usize) < LOST_FOUND_SIZE
+            && (self.get_inode(lost_found).size_low as usize / self.block_size) < DIRECT_POINTERS
+        {
+            self.extend_dir(lost_found)?;
+        }
+        Ok(())
+    }
+}
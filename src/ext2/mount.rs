@@ -0,0 +1,165 @@
+// Mount bookkeeping as the kernel keeps it: a mounted filesystem is marked
+// not clean until it is unmounted cleanly, mounts are counted towards the
+// next forced check, and corruption found while mounted is recorded in the
+// superblock and handled as `errors` says.
+// https://www.nongnu.org/ext2-doc/ext2.html#s-state
+
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::structs::{Superblock, ERR_PANIC, ERR_RONLY, FS_CLEAN, FS_ERR};
+use crate::ext2::{Ext2, EXT2_START_OF_SUPERBLOCK};
+use std::fmt;
+
+/// `max_mnt_count` given to filesystems that have none, as the kernel does
+const DEFAULT_MAX_MNT_COUNT: i16 = 20;
+
+/// Why a filesystem should be checked before it's mounted, from
+/// `Ext2::mount`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountWarning {
+    /// It wasn't unmounted cleanly, or hasn't been checked since
+    NotClean,
+    /// Errors were found in it and not yet repaired
+    HasErrors,
+    /// It has been mounted `max_mnt_count` times since the last check
+    MaxMountCount,
+    /// `checkinterval` seconds have passed since the last check
+    CheckInterval,
+}
+
+impl fmt::Display for MountWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MountWarning::NotClean => {
+                write!(f, "mounting unchecked fs, running e2fsck is recommended")
+            }
+            MountWarning::HasErrors => {
+                write!(f, "mounting fs with errors, running e2fsck is recommended")
+            }
+            MountWarning::MaxMountCount => write!(
+                f,
+                "maximal mount count reached, running e2fsck is recommended"
+            ),
+            MountWarning::CheckInterval => {
+                write!(f, "checktime reached, running e2fsck is recommended")
+            }
+        }
+    }
+}
+
+impl Ext2 {
+    /// Record that the image is being mounted on `mount_point`: count the
+    /// mount, stamp the mount time and path, and mark it not clean until
+    /// `unmount`. Returns the reasons it ought to be checked first, if any.
+    ///
+    /// While mounted, corruption found in the image is handled as the
+    /// superblock's `errors` field says.
+    pub fn mount(&mut self, mount_point: &str) -> Result<Vec<MountWarning>> {
+        self.check_writable()?;
+        if self.mount_state.is_some() {
+            return Err(Ext2Error::InvalidArgument("already mounted"));
+        }
+        let now = self.now();
+        let superblock = self.superblock;
+        let mut warnings = Vec::new();
+        if superblock.state & FS_CLEAN == 0 {
+            warnings.push(MountWarning::NotClean);
+        } else if superblock.state & FS_ERR != 0 {
+            warnings.push(MountWarning::HasErrors);
+        } else if superblock.max_mnt_count >= 0
+            && superblock.mnt_count >= superblock.max_mnt_count as u16
+        {
+            warnings.push(MountWarning::MaxMountCount);
+        } else if superblock.checkinterval != 0
+            && superblock.lastcheck as u64 + superblock.checkinterval as u64 <= now as u64
+        {
+            warnings.push(MountWarning::CheckInterval);
+        }
+        self.mount_state = Some(superblock.state);
+
+        let superblock = self.superblock_mut();
+        superblock.state &= !FS_CLEAN;
+        if superblock.max_mnt_count == 0 {
+            superblock.max_mnt_count = DEFAULT_MAX_MNT_COUNT;
+        }
+        superblock.mnt_count = superblock.mnt_count.wrapping_add(1);
+        superblock.mtime = now;
+        superblock.wtime = now;
+        let path = mount_point.as_bytes();
+        let length = path.len().min(superblock.last_mnt_path.len() - 1);
+        superblock.last_mnt_path.fill(0);
+        superblock.last_mnt_path[..length].copy_from_slice(&path[..length]);
+        Ok(warnings)
+    }
+
+    /// Record a clean unmount: the image goes back to the state it was
+    /// mounted in, clean or not, plus any errors found since, and the
+    /// backups are brought up to date
+    pub fn unmount(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let Some(state) = self.mount_state.take() else {
+            return Err(Ext2Error::InvalidArgument("not mounted"));
+        };
+        let now = self.now();
+        let superblock = self.superblock_mut();
+        superblock.state = state | superblock.state & FS_ERR;
+        superblock.wtime = now;
+        self.sync()
+    }
+
+    /// Record a check that found nothing wrong, as e2fsck does: the image
+    /// is clean again and the mount count starts over
+    pub fn mark_checked(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let now = self.now();
+        let superblock = self.superblock_mut();
+        superblock.state = FS_CLEAN;
+        superblock.mnt_count = 0;
+        superblock.lastcheck = now;
+        superblock.wtime = now;
+        Ok(())
+    }
+
+    /// Report corruption found by `function` in `inode` or `block` (0 if
+    /// none in particular). While mounted, it's recorded in the superblock,
+    /// which is marked as having errors, and then the `errors` policy
+    /// applies: carry on, stop allowing changes, or panic. Otherwise, as
+    /// when `fsck` reads a broken image, it's up to the caller.
+    #[track_caller]
+    pub(crate) fn fs_error(&self, function: &'static str, inode: usize, block: u32) {
+        if self.mount_state.is_none() || self.read_only() {
+            return;
+        }
+        let line = std::panic::Location::caller().line();
+        let now = self.now();
+        // like `superblock_mut`, only sound because the image was opened
+        // with `new_mut` and isn't read-only; this is reached from readers
+        // that only have `&self`
+        let superblock =
+            unsafe { &mut *(self.device.add(EXT2_START_OF_SUPERBLOCK) as *mut Superblock) };
+        superblock.state |= FS_ERR;
+        superblock.error_count = superblock.error_count.saturating_add(1);
+        let mut name = [0u8; 32];
+        let length = function.len().min(name.len());
+        name[..length].copy_from_slice(&function.as_bytes()[..length]);
+        if superblock.first_error_time == 0 {
+            superblock.first_error_time = now;
+            superblock.first_error_func = name;
+            superblock.first_error_line = line;
+            superblock.first_error_ino = inode as u32;
+            superblock.first_error_block = block as u64;
+        }
+        superblock.last_error_time = now;
+        superblock.last_error_func = name;
+        superblock.last_error_line = line;
+        superblock.last_error_ino = inode as u32;
+        superblock.last_error_block = block as u64;
+        match superblock.errors {
+            ERR_PANIC => panic!(
+                "ext2: {}: corruption found (inode {}, block {})",
+                function, inode, block
+            ),
+            ERR_RONLY => self.read_only.set(true),
+            _ => {}
+        }
+    }
+}
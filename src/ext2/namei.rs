@@ -0,0 +1,421 @@
+// Operations on names: resolving paths and moving entries between
+// directories.
+
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::structs::{InodeFlags, TypeIndicator, TypePerm, BLOCK_POINTER_BYTES};
+use crate::ext2::Ext2;
+
+/// Inode number of the root directory
+pub const ROOT_INODE: usize = 2;
+
+impl Ext2 {
+    /// Follow a `/`-separated path from directory `cwd`, or from the root
+    /// if the path is absolute, and return the inode it names
+    pub fn resolve(&self, cwd: usize, path: &str) -> Result<usize> {
+        let mut inode = if path.starts_with('/') { ROOT_INODE } else { cwd };
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            inode = self.lookup(inode, name)?;
+        }
+        Ok(inode)
+    }
+
+    /// Split a path into the inode of the directory holding its last
+    /// component, and that component's name
+    pub fn resolve_parent<'a>(&self, cwd: usize, path: &'a str) -> Result<(usize, &'a str)> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = match path.rsplit_once('/') {
+            Some(("", name)) => (ROOT_INODE, name),
+            Some((parent, name)) => (self.resolve(cwd, parent)?, name),
+            None => (cwd, path),
+        };
+        if !self.get_inode(parent).type_perm.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+        Self::check_name(name)?;
+        Ok((parent, name))
+    }
+
+    /// Whether directory `dir` is `ancestor` or somewhere beneath it
+    fn is_within(&self, ancestor: usize, dir: usize) -> Result<bool> {
+        let mut dir = dir;
+        // bounded so a corrupt `..` loop can't hang us
+        for _ in 0..self.superblock.inodes_count {
+            if dir == ancestor {
+                return Ok(true);
+            }
+            if dir == ROOT_INODE {
+                return Ok(false);
+            }
+            dir = self.lookup(dir, "..")?;
+        }
+        Ok(false)
+    }
+
+    /// Rename `old_name` in `old_parent` to `new_name` in `new_parent`.
+    ///
+    /// Follows `rename(2)`: an existing `new_name` is replaced and unlinked,
+    /// as long as it is a file replaced by a non-directory or an empty
+    /// directory replaced by a directory. The new name is in place before
+    /// the old one is removed, so it never stops existing. A directory
+    /// can't be moved beneath itself; moving one to a new parent rewrites
+    /// its `..` entry and the link counts of both parents. What can fail is
+    /// checked before anything changes.
+    pub fn rename(
+        &mut self,
+        old_parent: usize,
+        old_name: &str,
+        new_parent: usize,
+        new_name: &str,
+    ) -> Result<()> {
+        self.check_writable()?;
+        for name in [old_name, new_name] {
+            if name == "." || name == ".." {
+                return Err(Ext2Error::InvalidArgument("cannot rename . or .."));
+            }
+        }
+        Self::check_name(new_name)?;
+        let inode = self.lookup(old_parent, old_name)?;
+        self.check_mutable(old_parent)?;
+        self.check_mutable(inode)?;
+        self.check_not_immutable(new_parent)?;
+        if !self.get_inode(new_parent).type_perm.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+        if old_parent == new_parent && old_name == new_name {
+            return Ok(());
+        }
+        let type_perm = self.get_inode(inode).type_perm;
+        let is_dir = type_perm.is_dir();
+        if is_dir && self.is_within(inode, new_parent)? {
+            return Err(Ext2Error::InvalidArgument(
+                "cannot move a directory into its own subtree",
+            ));
+        }
+        // a directory moving to a new parent gets its `..` rewritten at the
+        // end; find it now, so a failure can't leave the rename half done
+        if is_dir && old_parent != new_parent {
+            self.lookup(inode, "..")?;
+        }
+        let file_type = TypeIndicator::from_type_perm(type_perm);
+
+        match self.lookup(new_parent, new_name) {
+            // both names are already links to the same inode, which
+            // rename(2) says leaves everything as it is
+            Ok(target) if target == inode => return Ok(()),
+            Ok(target) => {
+                self.check_mutable(new_parent)?;
+                self.check_mutable(target)?;
+                let target_is_dir = self.get_inode(target).type_perm.is_dir();
+                if is_dir && !target_is_dir {
+                    return Err(Ext2Error::NotADirectory);
+                }
+                if !is_dir && target_is_dir {
+                    return Err(Ext2Error::IsADirectory);
+                }
+                if target_is_dir && !self.is_empty_dir(target) {
+                    return Err(Ext2Error::DirectoryNotEmpty);
+                }
+                self.set_dir_entry(new_parent, new_name, inode, file_type)?;
+                if target_is_dir {
+                    // the replaced directory's `..` no longer links to new_parent
+                    self.inode_mut(new_parent).hard_links -= 1;
+                    self.inode_mut(target).hard_links = 0;
+                    self.release_inode(target);
+                } else {
+                    self.drop_link(target);
+                }
+            }
+            Err(Ext2Error::NotFound) => {
+                self.add_dir_entry(new_parent, new_name, inode, file_type)?
+            }
+            Err(err) => return Err(err),
+        }
+        self.remove_dir_entry(old_parent, old_name)?;
+
+        if is_dir && old_parent != new_parent {
+            self.set_dir_entry(inode, "..", new_parent, TypeIndicator::Directory)?;
+            self.inode_mut(old_parent).hard_links -= 1;
+            self.inode_mut(new_parent).hard_links += 1;
+        }
+        let now = self.now();
+        self.inode_mut(inode).ctime = now;
+        Ok(())
+    }
+
+    /// Allocate an inode with the given type and permissions near `parent`
+    /// and link it into `parent` as `name`. The inode inherits some of the
+    /// flags of `parent`, and its default ACL if it has one.
+    pub(crate) fn create_inode(
+        &mut self,
+        parent: usize,
+        name: &str,
+        mode: TypePerm,
+    ) -> Result<usize> {
+        self.check_writable()?;
+        Self::check_name(name)?;
+        self.check_not_immutable(parent)?;
+        match self.lookup(parent, name) {
+            Ok(_) => return Err(Ext2Error::AlreadyExists),
+            Err(Ext2Error::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+        let inode = self.alloc_inode(self.inode_group(parent), mode.is_dir())?;
+        let now = self.now();
+        let flags = InodeFlags::inherited(self.get_inode(parent).flags, mode);
+        let file = self.inode_mut(inode);
+        file.type_perm = mode;
+        file.flags = flags;
+        file.hard_links = 1;
+        file.atime = now;
+        file.ctime = now;
+        file.mtime = now;
+        if let Err(err) =
+            self.add_dir_entry(parent, name, inode, TypeIndicator::from_type_perm(mode))
+        {
+            self.free_inode(inode, mode.is_dir());
+            return Err(err);
+        }
+        if let Err(err) = self.inherit_acl(parent, inode) {
+            self.remove_dir_entry(parent, name)?;
+            self.drop_link(inode);
+            return Err(err);
+        }
+        Ok(inode)
+    }
+
+    /// Create an empty regular file `name` in `parent` with the permission
+    /// bits of `mode`
+    pub fn create(&mut self, parent: usize, name: &str, mode: u16) -> Result<usize> {
+        let mode =
+            TypePerm::from_bits_truncate(TypePerm::FILE.bits() | mode & !TypePerm::TYPE_MASK);
+        self.create_inode(parent, name, mode)
+    }
+
+    /// Create a symbolic link `name` in `parent` pointing at `target`.
+    /// Targets shorter than the block pointers are kept in them (a "fast"
+    /// symlink); longer ones get a data block.
+    pub fn symlink(&mut self, parent: usize, name: &str, target: &str) -> Result<usize> {
+        if target.is_empty() {
+            return Err(Ext2Error::InvalidArgument("empty symlink target"));
+        }
+        if target.len() >= self.block_size {
+            return Err(Ext2Error::NameTooLong);
+        }
+        let mode = TypePerm::from_bits_truncate(TypePerm::SYMLINK.bits() | 0o777);
+        let inode = self.create_inode(parent, name, mode)?;
+        if target.len() < BLOCK_POINTER_BYTES {
+            self.inode_mut(inode).block_pointer_bytes_mut()[..target.len()]
+                .copy_from_slice(target.as_bytes());
+        } else {
+            let block = match self.block_of_alloc(inode, 0) {
+                Ok(block) => block,
+                Err(err) => {
+                    self.remove_dir_entry(parent, name)?;
+                    self.drop_link(inode);
+                    return Err(err);
+                }
+            };
+            self.block_mut(block)[..target.len()].copy_from_slice(target.as_bytes());
+        }
+        self.inode_mut(inode).size_low = target.len() as u32;
+        Ok(inode)
+    }
+
+    /// The target of symbolic link `inode`
+    pub fn readlink(&self, inode: usize) -> Result<Vec<u8>> {
+        let file = self.get_inode(inode);
+        if !file.type_perm.is_symlink() {
+            return Err(Ext2Error::InvalidArgument("not a symlink"));
+        }
+        let length = file.size() as usize;
+        let target = if self.has_data_blocks(file) {
+            match self.block_of(file, 0) {
+                0 => &[][..],
+                block => self.block(block),
+            }
+        } else {
+            file.block_pointer_bytes()
+        };
+        Ok(target[..length.min(target.len())].to_vec())
+    }
+
+    /// Give the existing non-directory `inode` another name, `name` in
+    /// `parent`
+    pub fn link(&mut self, inode: usize, parent: usize, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let file = *self.get_inode(inode);
+        if file.type_perm.is_dir() {
+            return Err(Ext2Error::IsADirectory);
+        }
+        if file.hard_links == u16::MAX {
+            return Err(Ext2Error::InvalidArgument("too many links"));
+        }
+        self.check_mutable(inode)?;
+        self.check_not_immutable(parent)?;
+        match self.lookup(parent, name) {
+            Ok(_) => return Err(Ext2Error::AlreadyExists),
+            Err(Ext2Error::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+        self.add_dir_entry(
+            parent,
+            name,
+            inode,
+            TypeIndicator::from_type_perm(file.type_perm),
+        )?;
+        let now = self.now();
+        let file = self.inode_mut(inode);
+        file.hard_links += 1;
+        file.ctime = now;
+        Ok(())
+    }
+
+    /// Create a FIFO, socket, or character or block device named `name` in
+    /// `parent`. `kind` is the file type, optionally with permission bits;
+    /// `major` and `minor` are only used for devices.
+    pub fn mknod(
+        &mut self,
+        parent: usize,
+        name: &str,
+        kind: TypePerm,
+        major: u32,
+        minor: u32,
+    ) -> Result<usize> {
+        let file_type = kind.file_type();
+        let is_device = match file_type {
+            TypePerm::CHAR_DEVICE | TypePerm::BLOCK_DEVICE => true,
+            TypePerm::FIFO | TypePerm::SOCKET => false,
+            _ => {
+                return Err(Ext2Error::InvalidArgument(
+                    "mknod only creates FIFOs, sockets and devices",
+                ))
+            }
+        };
+        let inode = self.create_inode(parent, name, kind)?;
+        if is_device {
+            let file = self.inode_mut(inode);
+            file.direct_pointer[..2].copy_from_slice(&encode_device(major, minor));
+        }
+        Ok(inode)
+    }
+
+    /// Create an empty directory `name` in `parent` with the permission
+    /// bits of `mode`
+    pub fn mkdir(&mut self, parent: usize, name: &str, mode: u16) -> Result<usize> {
+        let mode =
+            TypePerm::from_bits_truncate(TypePerm::DIRECTORY.bits() | mode & !TypePerm::TYPE_MASK);
+        let dir = self.create_inode(parent, name, mode)?;
+        if let Err(err) = self.init_dir(dir, parent) {
+            self.remove_dir_entry(parent, name)?;
+            self.inode_mut(dir).hard_links = 0;
+            self.release_inode(dir);
+            return Err(err);
+        }
+        // the new directory's `..`
+        self.inode_mut(parent).hard_links += 1;
+        Ok(dir)
+    }
+
+    /// Remove the entry `name` from `parent`. It must not name a
+    /// directory; the inode is released along with its last link.
+    pub fn unlink(&mut self, parent: usize, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let inode = self.lookup(parent, name)?;
+        if self.get_inode(inode).type_perm.is_dir() {
+            return Err(Ext2Error::IsADirectory);
+        }
+        self.check_mutable(parent)?;
+        self.check_mutable(inode)?;
+        self.remove_dir_entry(parent, name)?;
+        self.drop_link(inode);
+        Ok(())
+    }
+
+    /// Remove the empty directory `name` from `parent`
+    pub fn rmdir(&mut self, parent: usize, name: &str) -> Result<()> {
+        self.check_writable()?;
+        if name == "." || name == ".." {
+            return Err(Ext2Error::InvalidArgument("cannot remove . or .."));
+        }
+        let dir = self.lookup(parent, name)?;
+        if !self.get_inode(dir).type_perm.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+        // as on Linux, the flags are checked before whether it's empty
+        self.check_mutable(parent)?;
+        self.check_mutable(dir)?;
+        if !self.is_empty_dir(dir) {
+            return Err(Ext2Error::DirectoryNotEmpty);
+        }
+        self.remove_dir_entry(parent, name)?;
+        // the removed directory's `..`
+        self.inode_mut(parent).hard_links -= 1;
+        self.inode_mut(dir).hard_links = 0;
+        self.release_inode(dir);
+        Ok(())
+    }
+
+    /// The directory `names` leads to from `dir`, creating any that are
+    /// missing with mode 755, like `mkdir -p`
+    pub(crate) fn make_dirs(&mut self, dir: usize, names: &[&str]) -> Result<usize> {
+        let mut parent = dir;
+        for &name in names {
+            parent = match self.lookup(parent, name) {
+                Ok(inode) if self.get_inode(inode).type_perm.is_dir() => inode,
+                Ok(_) => return Err(Ext2Error::NotADirectory),
+                Err(Ext2Error::NotFound) => self.mkdir(parent, name, 0o755)?,
+                Err(err) => return Err(err),
+            };
+        }
+        Ok(parent)
+    }
+
+    /// Remove one link to a non-directory inode, releasing it once no
+    /// directory entries refer to it
+    pub(crate) fn drop_link(&mut self, inode: usize) {
+        let now = self.now();
+        let file = self.inode_mut(inode);
+        file.hard_links = file.hard_links.saturating_sub(1);
+        file.ctime = now;
+        if file.hard_links == 0 {
+            self.release_inode(inode);
+        }
+    }
+
+    /// Free the blocks and the inode itself of an inode with no links
+    pub(crate) fn release_inode(&mut self, inode: usize) {
+        let is_dir = self.get_inode(inode).type_perm.is_dir();
+        self.free_data_blocks(inode);
+        self.release_xattr_block(inode);
+        let now = self.now();
+        let file = self.inode_mut(inode);
+        file.dtime = now;
+        file.size_low = 0;
+        file.size_high = 0;
+        self.free_inode(inode, is_dir);
+    }
+}
+
+/// Store a device number the way Linux does: the old 8-bit major/minor
+/// format in the first block pointer when it fits, otherwise the new
+/// 12-bit major/20-bit minor format in the second
+pub(crate) fn encode_device(major: u32, minor: u32) -> [u32; 2] {
+    if major < 256 && minor < 256 {
+        [major << 8 | minor, 0]
+    } else {
+        [
+            0,
+            (minor & 0xff) | (major & 0xfff) << 8 | (minor & !0xff) << 12,
+        ]
+    }
+}
+
+/// The (major, minor) device number stored by `encode_device`
+pub(crate) fn decode_device(pointers: &[u32]) -> (u32, u32) {
+    if pointers[0] != 0 {
+        ((pointers[0] >> 8) & 0xff, pointers[0] & 0xff)
+    } else {
+        let dev = pointers[1];
+        ((dev & 0xfff00) >> 8, (dev & 0xff) | ((dev >> 12) & 0xfff00))
+    }
+}
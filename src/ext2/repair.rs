@@ -0,0 +1,381 @@
+// Fixing what `Ext2::check` finds, in the order e2fsck does: clear broken
+// inodes and the entries naming them, rebuild the bitmaps from what is in
+// use, give unconnected inodes a home in /lost+found, then correct link
+// counts and free counts. Each step scans the image again, since the
+// earlier ones change what the later ones find.
+
+use crate::ext2::alloc::set_bit;
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::fsck::{Problem, Scan};
+use crate::ext2::namei::ROOT_INODE;
+use crate::ext2::structs::TypeIndicator;
+use crate::ext2::Ext2;
+use bitflags::bitflags;
+use std::collections::BTreeSet;
+use std::fmt;
+
+bitflags! {
+    /// The kinds of repair `Ext2::repair` is allowed to make
+    pub struct Repairs: u32 {
+        /// Clear inodes that point at blocks outside the filesystem or at
+        /// its metadata, and remove directory entries naming unused inodes
+        const BAD_INODES = 0x01;
+        /// Rewrite the block and inode bitmaps from what is in use
+        const BITMAPS = 0x02;
+        /// Link unconnected inodes into /lost+found, creating it if needed,
+        /// and point `..` entries at the directory's actual parent
+        const LOST_FOUND = 0x04;
+        /// Set `hard_links` to the number of entries naming each inode
+        const LINK_COUNTS = 0x08;
+        /// Set the free block, free inode and directory counts of each
+        /// group and of the superblock
+        const FREE_COUNTS = 0x10;
+    }
+}
+
+/// One change made, or planned, by a repair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Repair {
+    ClearInode {
+        inode: usize,
+    },
+    RemoveEntry {
+        dir: usize,
+        name: String,
+        inode: usize,
+    },
+    CreateLostFound,
+    /// The inode is linked into /lost+found as `#inode`
+    Reconnect {
+        inode: usize,
+    },
+    /// The value recorded on disk is replaced by what the checker counted
+    Correct(Problem),
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Repair::ClearInode { inode } => write!(f, "Clear inode {}", inode),
+            Repair::RemoveEntry { dir, name, inode } => write!(
+                f,
+                "Remove entry '{}' (inode {}) from directory inode {}",
+                name, inode, dir
+            ),
+            Repair::CreateLostFound => write!(f, "Create /lost+found"),
+            Repair::Reconnect { inode } => write!(f, "Connect inode {} to /lost+found", inode),
+            Repair::Correct(problem) => write!(f, "Fix: {}", problem),
+        }
+    }
+}
+
+impl Ext2 {
+    /// The changes `repair` would start with, without making any.
+    ///
+    /// The plan is made from the image as it is. Repairs change what the
+    /// later steps find, e.g. clearing an inode frees its blocks in the
+    /// bitmap, so `repair` may end up doing more than this lists.
+    pub fn plan_repairs(&self, repairs: Repairs) -> Vec<Repair> {
+        let scan = self.scan();
+        let mut plan = Vec::new();
+        let mut cleared = BTreeSet::new();
+        for problem in &scan.problems {
+            let category = Self::repaired_by(problem);
+            if category.is_empty() || !repairs.contains(category) {
+                continue;
+            }
+            match problem {
+                Problem::BadBlockPointer { inode, .. }
+                | Problem::MetadataBlockClaimed { inode, .. } => {
+                    if *inode >= self.first_inode() && cleared.insert(*inode) {
+                        plan.push(Repair::ClearInode { inode: *inode });
+                    }
+                }
+                Problem::EntryToUnusedInode { dir, name, inode } => {
+                    if name != "." && name != ".." {
+                        plan.push(Repair::RemoveEntry {
+                            dir: *dir,
+                            name: name.clone(),
+                            inode: *inode,
+                        });
+                    }
+                }
+                // reconnections are planned below, once per orphaned tree
+                Problem::Unconnected { .. } => {}
+                other => plan.push(Repair::Correct(other.clone())),
+            }
+        }
+        if repairs.contains(Repairs::LOST_FOUND) {
+            let orphans = self.orphans(&scan);
+            if !orphans.is_empty() && self.lookup(ROOT_INODE, "lost+found").is_err() {
+                plan.push(Repair::CreateLostFound);
+            }
+            plan.extend(orphans.into_iter().map(|inode| Repair::Reconnect { inode }));
+        }
+        plan
+    }
+
+    /// Fix the problems `check` finds in the selected categories and return
+    /// what was changed. Problems outside them, and ones no category covers
+    /// such as malformed directory records or blocks claimed twice, are
+    /// left for `check` to report.
+    ///
+    /// Reconnecting allocates blocks and inodes, so it refuses to run on
+    /// bitmaps that mark something in use as free unless `BITMAPS` is
+    /// selected too.
+    pub fn repair(&mut self, repairs: Repairs) -> Result<Vec<Repair>> {
+        self.check_writable()?;
+        let mut done = Vec::new();
+        if repairs.contains(Repairs::BAD_INODES) {
+            self.clear_bad_inodes(&mut done);
+        }
+        if repairs.contains(Repairs::BITMAPS) {
+            self.rebuild_bitmaps(&mut done);
+        }
+        if repairs.contains(Repairs::LOST_FOUND) {
+            self.reconnect_orphans(&mut done)?;
+        }
+        if repairs.contains(Repairs::LINK_COUNTS) {
+            for problem in self.scan().problems {
+                if let Problem::LinkCount { inode, counted, .. } = problem {
+                    self.inode_mut(inode).hard_links = counted as u16;
+                    done.push(Repair::Correct(problem));
+                }
+            }
+        }
+        if repairs.contains(Repairs::FREE_COUNTS) {
+            self.fix_free_counts(&mut done);
+        }
+        Ok(done)
+    }
+
+    /// The category of repair that fixes `problem`, or none
+    fn repaired_by(problem: &Problem) -> Repairs {
+        match problem {
+            Problem::BadBlockPointer { .. }
+            | Problem::MetadataBlockClaimed { .. }
+            | Problem::EntryToUnusedInode { .. } => Repairs::BAD_INODES,
+            Problem::BlockBitmap { .. } | Problem::InodeBitmap { .. } => Repairs::BITMAPS,
+            Problem::Unconnected { .. } | Problem::WrongDotDot { .. } => Repairs::LOST_FOUND,
+            Problem::LinkCount { .. } => Repairs::LINK_COUNTS,
+            Problem::GroupFreeBlocks { .. }
+            | Problem::GroupFreeInodes { .. }
+            | Problem::GroupDirs { .. }
+            | Problem::FreeBlocks { .. }
+            | Problem::FreeInodes { .. } => Repairs::FREE_COUNTS,
+            _ => Repairs::empty(),
+        }
+    }
+
+    fn clear_bad_inodes(&mut self, done: &mut Vec<Repair>) {
+        let mut cleared = BTreeSet::new();
+        for problem in self.scan().problems {
+            if let Problem::BadBlockPointer { inode, .. }
+            | Problem::MetadataBlockClaimed { inode, .. } = problem
+            {
+                // reserved inodes, the root among them, can't just go away
+                if inode >= self.first_inode() && cleared.insert(inode) {
+                    self.clear_inode(inode);
+                    done.push(Repair::ClearInode { inode });
+                }
+            }
+        }
+
+        // the entries naming cleared inodes now name unused ones
+        for problem in self.scan().problems {
+            let Problem::EntryToUnusedInode { dir, name, inode } = problem else {
+                continue;
+            };
+            // a bad `..` is rewritten when reconnecting instead
+            if name == "." || name == ".." {
+                continue;
+            }
+            // by record rather than by name, which may not be valid UTF-8
+            let record = self
+                .dir_records(dir)
+                .into_iter()
+                .find(|record| record.inode as usize == inode && record.name != b"..");
+            if let Some(record) = record {
+                self.remove_record(dir, &record);
+                done.push(Repair::RemoveEntry { dir, name, inode });
+            }
+        }
+    }
+
+    /// Mark an inode unused without following its block pointers, which
+    /// can't be trusted. Its blocks stay marked in use until the bitmaps
+    /// are rebuilt.
+    fn clear_inode(&mut self, inode: usize) {
+        let is_dir = self.get_inode(inode).type_perm.is_dir();
+        let now = self.now();
+        let file = self.inode_mut(inode);
+        file.hard_links = 0;
+        file.dtime = now;
+        self.free_inode(inode, is_dir);
+    }
+
+    fn rebuild_bitmaps(&mut self, done: &mut Vec<Repair>) {
+        let first_data_block = self.superblock.first_data_block;
+        let blocks_per_group = self.superblock.blocks_per_group;
+        let inodes_per_group = self.superblock.inodes_per_group as usize;
+        for problem in self.scan().problems {
+            match problem {
+                Problem::BlockBitmap { block, in_use } => {
+                    let group = self.block_group(block);
+                    let bit = ((block - first_data_block) % blocks_per_group) as usize;
+                    let bitmap = self.block_groups[group].block_usage_addr;
+                    set_bit(self.block_mut(bitmap), bit, in_use);
+                }
+                Problem::InodeBitmap { inode, in_use } => {
+                    let group = self.inode_group(inode);
+                    let bit = (inode - 1) % inodes_per_group;
+                    let bitmap = self.block_groups[group].inode_usage_addr;
+                    set_bit(self.block_mut(bitmap), bit, in_use);
+                }
+                _ => continue,
+            }
+            done.push(Repair::Correct(problem));
+        }
+    }
+
+    fn reconnect_orphans(&mut self, done: &mut Vec<Repair>) -> Result<()> {
+        let scan = self.scan();
+        for problem in &scan.problems {
+            if let Problem::WrongDotDot { dir, parent, .. } = problem {
+                self.set_dir_entry(*dir, "..", *parent, TypeIndicator::Directory)?;
+                done.push(Repair::Correct(problem.clone()));
+            }
+        }
+
+        let mut scan = scan;
+        loop {
+            let orphans = self.orphans(&scan);
+            if orphans.is_empty() {
+                return Ok(());
+            }
+            if scan.problems.contains(&Problem::BadRoot) {
+                return Err(Ext2Error::InvalidArgument(
+                    "no root directory to reconnect inodes to",
+                ));
+            }
+            let unsafe_bitmaps = scan.problems.iter().any(|problem| {
+                matches!(
+                    problem,
+                    Problem::BlockBitmap { in_use: true, .. }
+                        | Problem::InodeBitmap { in_use: true, .. }
+                )
+            });
+            if unsafe_bitmaps {
+                return Err(Ext2Error::InvalidArgument(
+                    "the bitmaps must be rebuilt before reconnecting inodes",
+                ));
+            }
+            let lost_found = self.lost_found(done)?;
+            for inode in orphans {
+                self.reconnect(lost_found, inode)?;
+                done.push(Repair::Reconnect { inode });
+            }
+            // a directory whose `..` led to one just reconnected may still
+            // not be named by it, so look again
+            scan = self.scan();
+        }
+    }
+
+    /// The unconnected inodes to link into /lost+found. Only the top of an
+    /// unconnected tree of directories is linked, found by following `..`
+    /// entries; the rest comes along with it.
+    fn orphans(&self, scan: &Scan) -> BTreeSet<usize> {
+        scan.problems
+            .iter()
+            .filter_map(|problem| match problem {
+                Problem::Unconnected { inode } => Some(self.orphan_root(scan, *inode)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The highest unconnected directory above `inode`, or for a loop of
+    /// `..` entries the lowest-numbered directory in the loop
+    fn orphan_root(&self, scan: &Scan, inode: usize) -> usize {
+        if !self.get_inode(inode).type_perm.is_dir() {
+            return inode;
+        }
+        let mut path = vec![inode];
+        loop {
+            let dir = *path.last().unwrap();
+            let up = scan.dot_dot[dir];
+            let unconnected = up >= self.first_inode()
+                && up < scan.in_use.len()
+                && scan.in_use[up]
+                && scan.parent[up] == 0
+                && self.get_inode(up).type_perm.is_dir();
+            if !unconnected {
+                return dir;
+            }
+            if let Some(start) = path.iter().position(|&seen| seen == up) {
+                return *path[start..].iter().min().unwrap();
+            }
+            path.push(up);
+        }
+    }
+
+    /// The inode of /lost+found, which is created if it doesn't exist
+    fn lost_found(&mut self, done: &mut Vec<Repair>) -> Result<usize> {
+        match self.lookup(ROOT_INODE, "lost+found") {
+            Ok(inode) if self.get_inode(inode).type_perm.is_dir() => Ok(inode),
+            Ok(_) => Err(Ext2Error::InvalidArgument("/lost+found is not a directory")),
+            Err(Ext2Error::NotFound) => {
+                let inode = self.mkdir(ROOT_INODE, "lost+found", 0o700)?;
+                done.push(Repair::CreateLostFound);
+                Ok(inode)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Link `inode` into `lost_found` as `#inode`. A directory's `..` is
+    /// pointed at `lost_found`; the link count of its old parent is left
+    /// for the link count repair.
+    fn reconnect(&mut self, lost_found: usize, inode: usize) -> Result<()> {
+        let type_perm = self.get_inode(inode).type_perm;
+        let name = format!("#{}", inode);
+        self.add_dir_entry(
+            lost_found,
+            &name,
+            inode,
+            TypeIndicator::from_type_perm(type_perm),
+        )?;
+        if type_perm.is_dir() {
+            match self.set_dir_entry(inode, "..", lost_found, TypeIndicator::Directory) {
+                Ok(()) | Err(Ext2Error::NotFound) => {}
+                Err(err) => return Err(err),
+            }
+            self.inode_mut(lost_found).hard_links += 1;
+        }
+        Ok(())
+    }
+
+    fn fix_free_counts(&mut self, done: &mut Vec<Repair>) {
+        for problem in self.scan().problems {
+            match problem {
+                Problem::GroupFreeBlocks { group, counted, .. } => {
+                    self.group_mut(group).free_blocks_count = counted as u16
+                }
+                Problem::GroupFreeInodes { group, counted, .. } => {
+                    self.group_mut(group).free_inodes_count = counted as u16
+                }
+                Problem::GroupDirs { group, counted, .. } => {
+                    self.group_mut(group).dirs_count = counted as u16
+                }
+                Problem::FreeBlocks { counted, .. } => {
+                    self.superblock_mut().free_blocks_count = counted
+                }
+                Problem::FreeInodes { counted, .. } => {
+                    self.superblock_mut().free_inodes_count = counted
+                }
+                _ => continue,
+            }
+            done.push(Repair::Correct(problem));
+        }
+    }
+}
@@ -0,0 +1,548 @@
+// A description of a filesystem's superblock and block groups in the style
+// of dumpe2fs, decoded for people rather than the raw struct fields.
+// https://www.nongnu.org/ext2-doc/ext2.html#superblock
+
+use crate::ext2::alloc::get_bit;
+use crate::ext2::features::feature_names;
+use crate::ext2::structs::{
+    ERR_IGNORE, ERR_PANIC, ERR_RONLY, FLAGS_SIGNED_HASH, FLAGS_TEST_FILESYS, FLAGS_UNSIGNED_HASH,
+    FS_CLEAN, FS_ERR, OS_FREEBSD, OS_HURD, OS_LINUX, OS_LITES, OS_MASIX,
+};
+use crate::ext2::Ext2;
+use std::fmt;
+use uuid::Uuid;
+
+/// Names of the directory hash versions
+const HASH_NAMES: [&str; 6] = [
+    "legacy",
+    "half_md4",
+    "tea",
+    "legacy_unsigned",
+    "half_md4_unsigned",
+    "tea_unsigned",
+];
+
+/// Everything `dumpe2fs` prints about a filesystem, from `Ext2::report`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub volume_name: String,
+    /// Directory the filesystem was last mounted on
+    pub last_mounted: String,
+    pub uuid: Uuid,
+    pub magic: u16,
+    pub rev_major: u32,
+    pub rev_minor: u16,
+    /// Names of the features set, with unknown bits as `FEATURE_C12` (for
+    /// bit 12 of the compat field; `I` and `R` for incompat and ro_compat)
+    pub features: Vec<String>,
+    /// `FLAGS_SIGNED_HASH` and friends
+    pub flags: u32,
+    /// Names of the default mount options, with unknown bits as `MNTOPT_12`
+    pub mount_defaults: Vec<String>,
+    /// `FS_CLEAN`, possibly with `FS_ERR`
+    pub state: u16,
+    /// `ERR_IGNORE`, `ERR_RONLY` or `ERR_PANIC`
+    pub errors: u16,
+    /// `OS_LINUX`, `OS_HURD` and so on
+    pub creator_os: u32,
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub r_blocks_count: u32,
+    /// Blocks of metadata overhead, 0 if not recorded
+    pub overhead_clusters: u32,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    pub first_data_block: u32,
+    pub block_size: usize,
+    pub fragment_size: usize,
+    pub reserved_gdt_blocks: u16,
+    pub blocks_per_group: u32,
+    pub frags_per_group: u32,
+    pub inodes_per_group: u32,
+    pub inode_blocks_per_group: usize,
+    /// Times are in POSIX time, 0 for never
+    pub created: Option<u32>,
+    pub mount_time: u32,
+    pub write_time: u32,
+    pub mount_count: u16,
+    pub max_mount_count: i16,
+    pub last_checked: u32,
+    /// Seconds
+    pub check_interval: u32,
+    /// KiB written over the filesystem's lifetime, 0 if not recorded
+    pub kbytes_written: u64,
+    pub reserved_uid: u16,
+    pub reserved_gid: u16,
+    pub first_inode: usize,
+    pub inode_size: usize,
+    pub min_extra_isize: u16,
+    pub want_extra_isize: u16,
+    pub hash_version: u8,
+    pub hash_seed: Uuid,
+    /// Corruption found while mounted, and the first and most recent time
+    pub error_count: u32,
+    pub first_error: Option<ErrorReport>,
+    pub last_error: Option<ErrorReport>,
+    pub groups: Vec<GroupReport>,
+}
+
+/// Where corruption was found while the filesystem was mounted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorReport {
+    pub time: u32,
+    pub function: String,
+    pub line: u32,
+    /// 0 if no inode in particular
+    pub inode: u32,
+    /// 0 if no block in particular
+    pub block: u64,
+}
+
+/// Where one block group keeps its metadata, and what it has free
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupReport {
+    pub group: usize,
+    /// First and last block of the group
+    pub blocks: (u32, u32),
+    /// Block of the primary superblock (group 0) or a backup of it
+    pub superblock: Option<u32>,
+    /// First and last block of the group descriptor table copy
+    pub gdt: Option<(u32, u32)>,
+    /// First and last block kept for growing the group descriptor table
+    pub reserved_gdt: Option<(u32, u32)>,
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    /// First and last block of the inode table
+    pub inode_table: (u32, u32),
+    pub free_blocks_count: u16,
+    pub free_inodes_count: u16,
+    pub dirs_count: u16,
+    /// Runs of free blocks according to the block bitmap, first and last
+    pub free_blocks: Vec<(u32, u32)>,
+    /// Runs of free inodes according to the inode bitmap, first and last
+    pub free_inodes: Vec<(u32, u32)>,
+}
+
+impl Ext2 {
+    /// Decode the superblock and group descriptors for `dumpe2fs`
+    pub fn report(&self) -> Report {
+        let superblock = self.superblock;
+        let mut hash_seed = [0u8; 16];
+        for (bytes, word) in hash_seed.chunks_mut(4).zip(superblock.hash_seed) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        Report {
+            volume_name: c_string(&superblock.volume_name),
+            last_mounted: c_string(&superblock.last_mnt_path),
+            uuid: self.uuid,
+            magic: superblock.magic,
+            rev_major: superblock.rev_major,
+            rev_minor: superblock.rev_minor,
+            features: feature_names(
+                superblock.compat(),
+                superblock.incompat(),
+                superblock.ro_compat(),
+            ),
+            flags: superblock.flags,
+            mount_defaults: superblock.mount_defaults().names(),
+            state: superblock.state,
+            errors: superblock.errors,
+            creator_os: superblock.creator_os,
+            inodes_count: superblock.inodes_count,
+            blocks_count: superblock.blocks_count,
+            r_blocks_count: superblock.r_blocks_count,
+            overhead_clusters: superblock.overhead_clusters,
+            free_blocks_count: superblock.free_blocks_count,
+            free_inodes_count: superblock.free_inodes_count,
+            first_data_block: superblock.first_data_block,
+            block_size: self.block_size,
+            fragment_size: 1024 << superblock.log_frag_size,
+            reserved_gdt_blocks: self.reserved_gdt_blocks() as u16,
+            blocks_per_group: superblock.blocks_per_group,
+            frags_per_group: superblock.frags_per_group,
+            inodes_per_group: superblock.inodes_per_group,
+            inode_blocks_per_group: self.inode_table_blocks(),
+            created: superblock.created(),
+            mount_time: superblock.mtime,
+            write_time: superblock.wtime,
+            mount_count: superblock.mnt_count,
+            max_mount_count: superblock.max_mnt_count,
+            last_checked: superblock.lastcheck,
+            check_interval: superblock.checkinterval,
+            kbytes_written: superblock.kbytes_written,
+            reserved_uid: superblock.block_uid,
+            reserved_gid: superblock.block_gid,
+            first_inode: self.first_inode(),
+            inode_size: self.inode_size(),
+            min_extra_isize: superblock.min_extra_isize,
+            want_extra_isize: superblock.want_extra_isize,
+            hash_version: superblock.def_hash_version,
+            hash_seed: Uuid::from_bytes(hash_seed),
+            error_count: superblock.error_count,
+            first_error: (superblock.first_error_time != 0).then(|| ErrorReport {
+                time: superblock.first_error_time,
+                function: c_string(&superblock.first_error_func),
+                line: superblock.first_error_line,
+                inode: superblock.first_error_ino,
+                block: superblock.first_error_block,
+            }),
+            last_error: (superblock.last_error_time != 0).then(|| ErrorReport {
+                time: superblock.last_error_time,
+                function: c_string(&superblock.last_error_func),
+                line: superblock.last_error_line,
+                inode: superblock.last_error_ino,
+                block: superblock.last_error_block,
+            }),
+            groups: (0..self.block_groups.len())
+                .map(|group| self.group_report(group))
+                .collect(),
+        }
+    }
+
+    fn group_report(&self, group: usize) -> GroupReport {
+        let desc = &self.block_groups[group];
+        let first = self.group_first_block(group);
+        let block_count = self.blocks_in_group(group);
+        let (superblock, gdt, reserved_gdt) = if self.group_has_superblock(group) {
+            let gdt_end = first + self.gdt_blocks() as u32;
+            let reserved = self.reserved_gdt_blocks() as u32;
+            (
+                Some(first),
+                Some((first + 1, gdt_end)),
+                (reserved > 0).then_some((gdt_end + 1, gdt_end + reserved)),
+            )
+        } else {
+            (None, None, None)
+        };
+        let block_bitmap = self.block(desc.block_usage_addr);
+        let free_blocks = free_runs(block_bitmap, block_count, first);
+        let inode_bitmap = self.block(desc.inode_usage_addr);
+        let first_inode = (group * self.superblock.inodes_per_group as usize + 1) as u32;
+        let free_inodes = free_runs(
+            inode_bitmap,
+            self.superblock.inodes_per_group as usize,
+            first_inode,
+        );
+        GroupReport {
+            group,
+            blocks: (first, first + block_count as u32 - 1),
+            superblock,
+            gdt,
+            reserved_gdt,
+            block_bitmap: desc.block_usage_addr,
+            inode_bitmap: desc.inode_usage_addr,
+            inode_table: (
+                desc.inode_table_block,
+                desc.inode_table_block + self.inode_table_blocks() as u32 - 1,
+            ),
+            free_blocks_count: desc.free_blocks_count,
+            free_inodes_count: desc.free_inodes_count,
+            dirs_count: desc.dirs_count,
+            free_blocks,
+            free_inodes,
+        }
+    }
+}
+
+/// The runs of clear bits among the first `count` of `bitmap`, numbered
+/// from `first`
+fn free_runs(bitmap: &[u8], count: usize, first: u32) -> Vec<(u32, u32)> {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for bit in 0..count.min(bitmap.len() * 8) {
+        if get_bit(bitmap, bit) {
+            continue;
+        }
+        let number = first + bit as u32;
+        match runs.last_mut() {
+            Some(run) if run.1 + 1 == number => run.1 = number,
+            _ => runs.push((number, number)),
+        }
+    }
+    runs
+}
+
+/// The text of a NUL-padded string field
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// A POSIX time as `ctime(3)` prints it in UTC, e.g.
+/// `Thu Jan  1 00:00:00 1970`
+fn date(time: u32) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let days = time as i64 / 86400;
+    let seconds = time as i64 % 86400;
+    // civil_from_days, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02} {}",
+        DAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1],
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        year
+    )
+}
+
+/// A size in KiB in the biggest unit that leaves at least 8192 of them,
+/// rounded to nearest
+fn kbytes(kbytes: u64) -> String {
+    let units = ["kB", "MB", "GB", "TB"];
+    for (shift, unit) in units.iter().enumerate() {
+        let shift = 10 * shift as u32;
+        if kbytes < 1 << (shift + 13) {
+            let rounded = if shift == 0 {
+                kbytes
+            } else {
+                (kbytes + (1 << (shift - 1))) >> shift
+            };
+            return format!("{} {}", rounded, unit);
+        }
+    }
+    format!("{} PB", (kbytes + (1 << 39)) >> 40)
+}
+
+/// `first-last`, or just `first` for a single block or inode
+fn range((first, last): (u32, u32)) -> String {
+    if first == last {
+        first.to_string()
+    } else {
+        format!("{}-{}", first, last)
+    }
+}
+
+/// Where `block` is relative to the start of `group`, if it is in it
+fn offset(block: u32, group: &GroupReport) -> String {
+    if (group.blocks.0..=group.blocks.1).contains(&block) {
+        format!(" (+{})", block - group.blocks.0)
+    } else {
+        String::new()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut line = |label: &str, value: &dyn fmt::Display| {
+            writeln!(f, "{:<26}{}", format!("{}:", label), value)
+        };
+        let or = |value: &str, none: &str| {
+            if value.is_empty() {
+                none.to_string()
+            } else {
+                value.to_string()
+            }
+        };
+        line("Filesystem volume name", &or(&self.volume_name, "<none>"))?;
+        line(
+            "Last mounted on",
+            &or(&self.last_mounted, "<not available>"),
+        )?;
+        line("Filesystem UUID", &self.uuid)?;
+        line("Filesystem magic number", &format!("{:#06X}", self.magic))?;
+        let revision = match self.rev_major {
+            0 => "(original)",
+            1 => "(dynamic)",
+            _ => "(unknown)",
+        };
+        line(
+            "Filesystem revision #",
+            &format!("{} {}", self.rev_major, revision),
+        )?;
+        let features = if self.features.is_empty() {
+            "(none)".to_string()
+        } else {
+            self.features.join(" ")
+        };
+        line("Filesystem features", &features)?;
+        if self.flags != 0 {
+            let names = [
+                (FLAGS_SIGNED_HASH, "signed_directory_hash "),
+                (FLAGS_UNSIGNED_HASH, "unsigned_directory_hash "),
+                (FLAGS_TEST_FILESYS, "test_filesystem "),
+            ];
+            let flags: String = names
+                .iter()
+                .filter(|(flag, _)| self.flags & flag != 0)
+                .map(|(_, name)| *name)
+                .collect();
+            line("Filesystem flags", &or(&flags, "(none)"))?;
+        }
+        let mount_defaults = if self.mount_defaults.is_empty() {
+            "(none)".to_string()
+        } else {
+            self.mount_defaults.join(" ")
+        };
+        line("Default mount options", &mount_defaults)?;
+        let mut state = match self.state & FS_CLEAN {
+            0 => "not clean".to_string(),
+            _ => "clean".to_string(),
+        };
+        if self.state & FS_ERR != 0 {
+            state.push_str(" with errors");
+        }
+        line("Filesystem state", &state)?;
+        let errors = match self.errors {
+            ERR_IGNORE => "Continue".to_string(),
+            ERR_RONLY => "Remount read-only".to_string(),
+            ERR_PANIC => "Panic".to_string(),
+            other => format!("Unknown ({})", other),
+        };
+        line("Errors behavior", &errors)?;
+        let os = match self.creator_os {
+            OS_LINUX => "Linux".to_string(),
+            OS_HURD => "GNU/Hurd".to_string(),
+            OS_MASIX => "Masix".to_string(),
+            OS_FREEBSD => "FreeBSD".to_string(),
+            OS_LITES => "Lites".to_string(),
+            other => format!("(unknown os {})", other),
+        };
+        line("Filesystem OS type", &os)?;
+        line("Inode count", &self.inodes_count)?;
+        line("Block count", &self.blocks_count)?;
+        line("Reserved block count", &self.r_blocks_count)?;
+        if self.overhead_clusters > 0 {
+            line("Overhead clusters", &self.overhead_clusters)?;
+        }
+        line("Free blocks", &self.free_blocks_count)?;
+        line("Free inodes", &self.free_inodes_count)?;
+        line("First block", &self.first_data_block)?;
+        line("Block size", &self.block_size)?;
+        line("Fragment size", &self.fragment_size)?;
+        if self.reserved_gdt_blocks > 0 {
+            line("Reserved GDT blocks", &self.reserved_gdt_blocks)?;
+        }
+        line("Blocks per group", &self.blocks_per_group)?;
+        line("Fragments per group", &self.frags_per_group)?;
+        line("Inodes per group", &self.inodes_per_group)?;
+        line("Inode blocks per group", &self.inode_blocks_per_group)?;
+        if let Some(created) = self.created {
+            line("Filesystem created", &date(created))?;
+        }
+        let mount_time = match self.mount_time {
+            0 => "n/a".to_string(),
+            time => date(time),
+        };
+        line("Last mount time", &mount_time)?;
+        line("Last write time", &date(self.write_time))?;
+        line("Mount count", &self.mount_count)?;
+        line("Maximum mount count", &self.max_mount_count)?;
+        line("Last checked", &date(self.last_checked))?;
+        let interval = match self.check_interval {
+            0 => "0 (<none>)".to_string(),
+            seconds => format!("{} ({} days)", seconds, seconds / 86400),
+        };
+        line("Check interval", &interval)?;
+        if self.kbytes_written > 0 {
+            line("Lifetime writes", &kbytes(self.kbytes_written))?;
+        }
+        line("Reserved blocks uid", &self.reserved_uid)?;
+        line("Reserved blocks gid", &self.reserved_gid)?;
+        if self.rev_major >= 1 {
+            line("First inode", &self.first_inode)?;
+            line("Inode size", &self.inode_size)?;
+            if self.min_extra_isize > 0 {
+                line("Required extra isize", &self.min_extra_isize)?;
+            }
+            if self.want_extra_isize > 0 {
+                line("Desired extra isize", &self.want_extra_isize)?;
+            }
+            let hash = match HASH_NAMES.get(self.hash_version as usize) {
+                Some(name) => name.to_string(),
+                None => format!("unknown ({})", self.hash_version),
+            };
+            line("Default directory hash", &hash)?;
+            line("Directory Hash Seed", &self.hash_seed)?;
+        }
+        if self.error_count > 0 {
+            line("FS Error count", &self.error_count)?;
+        }
+        for (which, error) in [("First", &self.first_error), ("Last", &self.last_error)] {
+            let Some(error) = error else {
+                continue;
+            };
+            line(&format!("{} error time", which), &date(error.time))?;
+            line(&format!("{} error function", which), &error.function)?;
+            line(&format!("{} error line #", which), &error.line)?;
+            if error.inode != 0 {
+                line(&format!("{} error inode #", which), &error.inode)?;
+            }
+            if error.block != 0 {
+                line(&format!("{} error block #", which), &error.block)?;
+            }
+        }
+        if !self.groups.is_empty() {
+            writeln!(f)?;
+        }
+        for group in &self.groups {
+            write!(f, "{}", group)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for GroupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Group {}: (Blocks {})", self.group, range(self.blocks))?;
+        if let (Some(superblock), Some(gdt)) = (self.superblock, self.gdt) {
+            let kind = if self.group == 0 { "Primary" } else { "Backup" };
+            writeln!(
+                f,
+                "  {} superblock at {}, Group descriptors at {}-{}",
+                kind, superblock, gdt.0, gdt.1
+            )?;
+        }
+        if let Some(reserved) = self.reserved_gdt {
+            writeln!(f, "  Reserved GDT blocks at {}", range(reserved))?;
+        }
+        writeln!(
+            f,
+            "  Block bitmap at {}{}",
+            self.block_bitmap,
+            offset(self.block_bitmap, self)
+        )?;
+        writeln!(
+            f,
+            "  Inode bitmap at {}{}",
+            self.inode_bitmap,
+            offset(self.inode_bitmap, self)
+        )?;
+        writeln!(
+            f,
+            "  Inode table at {}{}",
+            range(self.inode_table),
+            offset(self.inode_table.0, self)
+        )?;
+        writeln!(
+            f,
+            "  {} free blocks, {} free inodes, {} directories",
+            self.free_blocks_count, self.free_inodes_count, self.dirs_count
+        )?;
+        let runs = |runs: &[(u32, u32)]| {
+            runs.iter()
+                .map(|&run| range(run))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        writeln!(f, "  Free blocks: {}", runs(&self.free_blocks))?;
+        writeln!(f, "  Free inodes: {}", runs(&self.free_inodes))
+    }
+}
@@ -0,0 +1,699 @@
+// Populating an image from a declarative spec, for test fixtures with shapes
+// that are tedious to make by hand: files with holes or reaching the triply
+// indirect pointer, directories spanning many blocks, deep trees and full
+// block groups.
+//
+// A spec is a subset of TOML: `[[kind]]` sections of `key = value` lines,
+// with strings, integers (`0o755` style octal too) and arrays.
+// Sections are applied in order, so hard links come after their targets,
+// and missing parent directories are created. For example
+//
+//     [[file]]
+//     path = "big/triple"
+//     size = "70M"
+//     holes = [[0, "69M"]]
+//     pattern = "offsets"
+//
+// fixtures/stress.toml uses every kind of section.
+
+use crate::ext2::error::Ext2Error;
+use crate::ext2::structs::TypePerm;
+use crate::ext2::Ext2;
+use std::fmt::Display;
+use std::io::{self, ErrorKind};
+
+/// Keys every section may have, setting the attributes of what it makes
+const ATTRIBUTES: [&str; 5] = ["path", "mode", "uid", "gid", "mtime"];
+
+#[derive(Debug, Clone)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Array(Vec<Value>),
+}
+
+/// One `[[kind]]` section and its keys, in the order given
+#[derive(Debug)]
+struct Section {
+    kind: String,
+    /// Line of the section header, for error messages
+    line: usize,
+    keys: Vec<(String, Value)>,
+}
+
+/// Contents of the data of a file
+#[derive(Debug, Clone)]
+enum Pattern {
+    Zeros,
+    /// Lines of 16 bytes, each the offset of its first byte in hex, so any
+    /// misplaced block shows where it came from
+    Offsets,
+    /// Pseudo-random bytes from a seed, the same on every build
+    Random(u64),
+    /// The given bytes, repeated
+    Content(Vec<u8>),
+}
+
+/// A byte range of a file, `start..end`
+type Range = (u64, u64);
+
+impl Ext2 {
+    /// Create everything `spec` describes below directory `dir`.
+    ///
+    /// Sections are `[[file]]` (`size`, `pattern` of `"offsets"`,
+    /// `"zeros"` or `"random"` with a `seed`, or literal `content`, and
+    /// `holes` as `[offset, length]` pairs), `[[dir]]` (with `files` empty
+    /// files named by zero-padded numbers `name_length` long), `[[tree]]`
+    /// (`depth` levels of `fanout` directories with `files` files of
+    /// `file_size` each), `[[symlink]]` and `[[link]]` (`target`),
+    /// `[[node]]` (`kind` of `"char"`, `"block"`, `"fifo"` or `"socket"`,
+    /// `major` and `minor`) and `[[fill]]`, a file that grows until only
+    /// `free` bytes are left, filling the groups in turn. Each needs a
+    /// `path` and may set `mode`, `uid`, `gid` and `mtime`, which are
+    /// applied once everything is made. Sizes are bytes, or strings such as
+    /// `"64M"`.
+    pub fn populate(&mut self, dir: usize, spec: &str) -> io::Result<()> {
+        let sections = parse_spec(spec)?;
+        let mut made = Vec::new();
+        for section in &sections {
+            let inode = self
+                .make_section(dir, section)
+                .map_err(|err| section.error(err))?;
+            made.push((inode, section));
+        }
+        for (inode, section) in made {
+            self.set_section_attributes(inode, section)
+                .map_err(|err| section.error(err))?;
+        }
+        Ok(())
+    }
+
+    fn make_section(&mut self, dir: usize, section: &Section) -> io::Result<usize> {
+        let path = section
+            .string("path")?
+            .ok_or_else(|| invalid("missing path"))?;
+        let mut names = path_names(path)?;
+        let name = names.pop().ok_or_else(|| invalid("empty path"))?;
+        let parent = self.make_dirs(dir, &names)?;
+
+        let inode = match section.kind.as_str() {
+            "file" => {
+                section.check_keys(&["size", "pattern", "content", "seed", "holes"])?;
+                let pattern = section.pattern()?;
+                let size = match (section.size("size")?, &pattern) {
+                    (Some(size), _) => size,
+                    (None, Pattern::Content(content)) => content.len() as u64,
+                    (None, _) => 0,
+                };
+                let holes = section.holes()?;
+                let inode = self.create(parent, name, 0o644)?;
+                self.write_pattern(inode, size, &pattern, &holes)?;
+                inode
+            }
+            "dir" => {
+                section.check_keys(&["files", "name_length"])?;
+                let files = section.count("files")?.unwrap_or(0);
+                let width = section.count("name_length")?.unwrap_or(8);
+                if width > 255 || width < files.saturating_sub(1).to_string().len() {
+                    return Err(invalid("name_length is too long or too short"));
+                }
+                let inode = self.make_dirs(parent, &[name])?;
+                for i in 0..files {
+                    self.create(inode, &format!("{:0width$}", i, width = width), 0o644)?;
+                }
+                inode
+            }
+            "tree" => {
+                section.check_keys(&[
+                    "depth",
+                    "fanout",
+                    "files",
+                    "file_size",
+                    "pattern",
+                    "content",
+                    "seed",
+                ])?;
+                let shape = (
+                    section.count("depth")?.unwrap_or(1),
+                    section.count("fanout")?.unwrap_or(1),
+                    section.count("files")?.unwrap_or(0),
+                    section.size("file_size")?.unwrap_or(0),
+                );
+                let inode = self.make_dirs(parent, &[name])?;
+                self.make_tree(inode, shape, &section.pattern()?)?;
+                inode
+            }
+            "symlink" => {
+                section.check_keys(&["target"])?;
+                let target = section
+                    .string("target")?
+                    .ok_or_else(|| invalid("missing target"))?;
+                self.symlink(parent, name, target)?
+            }
+            "link" => {
+                section.check_keys(&["target"])?;
+                let target = section
+                    .string("target")?
+                    .ok_or_else(|| invalid("missing target"))?;
+                let mut inode = dir;
+                for name in path_names(target)? {
+                    inode = self.lookup(inode, name)?;
+                }
+                self.link(inode, parent, name)?;
+                inode
+            }
+            "node" => {
+                section.check_keys(&["kind", "major", "minor"])?;
+                let kind = match section.string("kind")? {
+                    Some("char") => TypePerm::CHAR_DEVICE,
+                    Some("block") => TypePerm::BLOCK_DEVICE,
+                    Some("fifo") => TypePerm::FIFO,
+                    Some("socket") => TypePerm::SOCKET,
+                    _ => return Err(invalid("kind must be char, block, fifo or socket")),
+                };
+                let major = section.count_u32("major")?.unwrap_or(0);
+                let minor = section.count_u32("minor")?.unwrap_or(0);
+                let kind = TypePerm::from_bits_truncate(kind.bits() | 0o644);
+                self.mknod(parent, name, kind, major, minor)?
+            }
+            "fill" => {
+                section.check_keys(&["free", "pattern", "content", "seed"])?;
+                let free = section.size("free")?.unwrap_or(0);
+                let inode = self.create(parent, name, 0o644)?;
+                self.fill_until(inode, free, &section.pattern()?);
+                inode
+            }
+            other => return Err(invalid(format!("unknown section [[{}]]", other))),
+        };
+        Ok(inode)
+    }
+
+    /// Fill directory `dir` with `files` files and `fanout` subdirectories,
+    /// and those subdirectories likewise, `depth` levels down
+    fn make_tree(
+        &mut self,
+        dir: usize,
+        (depth, fanout, files, file_size): (usize, usize, usize, u64),
+        pattern: &Pattern,
+    ) -> io::Result<()> {
+        for i in 0..files {
+            let file = self.create(dir, &format!("f{}", i), 0o644)?;
+            self.write_pattern(file, file_size, pattern, &[])?;
+        }
+        if depth == 0 {
+            return Ok(());
+        }
+        for i in 0..fanout {
+            let subdir = self.mkdir(dir, &format!("d{}", i), 0o755)?;
+            self.make_tree(subdir, (depth - 1, fanout, files, file_size), pattern)?;
+        }
+        Ok(())
+    }
+
+    /// Fill empty regular file `inode` with `size` bytes of `pattern`,
+    /// leaving out the blocks that lie wholly inside `holes`
+    fn write_pattern(
+        &mut self,
+        inode: usize,
+        size: u64,
+        pattern: &Pattern,
+        holes: &[Range],
+    ) -> io::Result<()> {
+        let mut buffer = vec![0; self.block_size];
+        let mut offset = 0;
+        while offset < size {
+            let length = (self.block_size as u64).min(size - offset);
+            let end = offset + length;
+            if !holes
+                .iter()
+                .any(|&(start, stop)| start <= offset && end <= stop)
+            {
+                let chunk = &mut buffer[..length as usize];
+                pattern.fill(offset, chunk);
+                // a hole may still cover part of the block
+                for &(start, stop) in holes {
+                    let from = start.clamp(offset, end) - offset;
+                    let to = stop.clamp(offset, end) - offset;
+                    chunk[from as usize..to as usize].fill(0);
+                }
+                if self.write(inode, offset, chunk)? < chunk.len() {
+                    return Err(Ext2Error::NoSpace.into());
+                }
+            }
+            offset = end;
+        }
+        // covers a hole at the end
+        self.truncate(inode, size)?;
+        Ok(())
+    }
+
+    /// Append blocks of `pattern` to `inode` until no more than `free`
+    /// bytes of the filesystem are left free, or it is full
+    fn fill_until(&mut self, inode: usize, free: u64, pattern: &Pattern) {
+        let free_blocks = free.div_ceil(self.block_size as u64);
+        let mut buffer = vec![0; self.block_size];
+        let mut offset = 0;
+        while self.superblock.free_blocks_count as u64 > free_blocks {
+            pattern.fill(offset, &mut buffer);
+            match self.write(inode, offset, &buffer) {
+                Ok(written) if written == buffer.len() => offset += written as u64,
+                _ => break,
+            }
+        }
+    }
+
+    fn set_section_attributes(&mut self, inode: usize, section: &Section) -> io::Result<()> {
+        if let Some(mode) = section.count("mode")? {
+            if mode > 0o7777 {
+                return Err(invalid("mode is more than 0o7777"));
+            }
+            if !self.get_inode(inode).type_perm.is_symlink() {
+                self.chmod(inode, mode as u16)?;
+            }
+        }
+        let uid = section.count_u32("uid")?;
+        let gid = section.count_u32("gid")?;
+        if uid.is_some() || gid.is_some() {
+            self.chown(inode, uid, gid)?;
+        }
+        if let Some(mtime) = section.count_u32("mtime")? {
+            self.utimes(inode, mtime, mtime)?;
+        }
+        Ok(())
+    }
+}
+
+impl Pattern {
+    /// Fill `buffer` with the bytes of the pattern that belong at byte
+    /// `offset` of the file and on
+    fn fill(&self, offset: u64, buffer: &mut [u8]) {
+        match self {
+            Pattern::Zeros => buffer.fill(0),
+            Pattern::Offsets => {
+                let mut line = [0; 16];
+                let mut line_start = None;
+                for (position, byte) in (offset..).zip(buffer.iter_mut()) {
+                    let start = position - position % 16;
+                    if line_start != Some(start) {
+                        line.copy_from_slice(format!("{:015x}\n", start).as_bytes());
+                        line_start = Some(start);
+                    }
+                    *byte = line[(position % 16) as usize];
+                }
+            }
+            Pattern::Random(seed) => {
+                for (position, byte) in (offset..).zip(buffer.iter_mut()) {
+                    let word = splitmix64(seed.wrapping_add(position / 8));
+                    *byte = word.to_le_bytes()[(position % 8) as usize];
+                }
+            }
+            Pattern::Content(content) => {
+                for (position, byte) in (offset..).zip(buffer.iter_mut()) {
+                    *byte = content[(position % content.len() as u64) as usize];
+                }
+            }
+        }
+    }
+}
+
+/// One step of the SplitMix64 generator, a good hash of a 64-bit counter
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+fn invalid(message: impl Display) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// The components of a path in the spec; it may not climb out with `..`
+fn path_names(path: &str) -> io::Result<Vec<&str>> {
+    let names: Vec<&str> = path
+        .split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .collect();
+    if names.contains(&"..") {
+        return Err(invalid("paths can't contain .."));
+    }
+    Ok(names)
+}
+
+/// A size: bytes, or a string such as `"512K"`, `"64M"` or `"1G"`
+fn parse_size(value: &Value) -> Option<u64> {
+    match value {
+        Value::Integer(bytes) => u64::try_from(*bytes).ok(),
+        Value::String(size) => {
+            let (number, shift) = match size.char_indices().last()? {
+                (i, 'K' | 'k') => (&size[..i], 10),
+                (i, 'M' | 'm') => (&size[..i], 20),
+                (i, 'G' | 'g') => (&size[..i], 30),
+                _ => (size.as_str(), 0),
+            };
+            number.parse::<u64>().ok()?.checked_mul(1 << shift)
+        }
+        _ => None,
+    }
+}
+
+impl Section {
+    /// `err` with the section it is about
+    fn error(&self, err: io::Error) -> io::Error {
+        io::Error::new(
+            err.kind(),
+            format!("spec line {} ([[{}]]): {}", self.line, self.kind, err),
+        )
+    }
+
+    fn get(&self, key: &str) -> Option<&Value> {
+        self.keys
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Refuse keys other than `allowed` and the attributes, which are
+    /// most likely typos
+    fn check_keys(&self, allowed: &[&str]) -> io::Result<()> {
+        for (key, _) in &self.keys {
+            if !allowed.contains(&key.as_str()) && !ATTRIBUTES.contains(&key.as_str()) {
+                return Err(invalid(format!("unknown key {}", key)));
+            }
+        }
+        Ok(())
+    }
+
+    fn string(&self, key: &str) -> io::Result<Option<&str>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(_) => Err(invalid(format!("{} must be a string", key))),
+        }
+    }
+
+    /// A non-negative integer
+    fn count(&self, key: &str) -> io::Result<Option<usize>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(value)) if *value >= 0 => Ok(Some(*value as usize)),
+            Some(_) => Err(invalid(format!("{} must be a non-negative integer", key))),
+        }
+    }
+
+    /// A non-negative integer that fits in the 32 bits that IDs, device
+    /// numbers and times have on disk
+    fn count_u32(&self, key: &str) -> io::Result<Option<u32>> {
+        self.count(key)?
+            .map(|value| {
+                u32::try_from(value)
+                    .map_err(|_| invalid(format!("{} is more than {}", key, u32::MAX)))
+            })
+            .transpose()
+    }
+
+    fn size(&self, key: &str) -> io::Result<Option<u64>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(value) => parse_size(value)
+                .map(Some)
+                .ok_or_else(|| invalid(format!("{} must be a size", key))),
+        }
+    }
+
+    fn pattern(&self) -> io::Result<Pattern> {
+        let seed = self.count("seed")?.unwrap_or(0) as u64;
+        match (self.string("pattern")?, self.get("content")) {
+            (None, Some(Value::String(content))) if !content.is_empty() => {
+                Ok(Pattern::Content(content.as_bytes().to_vec()))
+            }
+            (None, Some(_)) => Err(invalid("content must be a non-empty string")),
+            (None | Some("offsets"), None) => Ok(Pattern::Offsets),
+            (Some("zeros"), None) => Ok(Pattern::Zeros),
+            (Some("random"), None) => Ok(Pattern::Random(seed)),
+            (Some(_), Some(_)) => Err(invalid("give either pattern or content")),
+            (Some(other), None) => Err(invalid(format!("unknown pattern {}", other))),
+        }
+    }
+
+    /// `holes = [[offset, length], ...]` as ranges
+    fn holes(&self) -> io::Result<Vec<Range>> {
+        let bad = || invalid("holes must be a list of [offset, length] pairs");
+        let Some(holes) = self.get("holes") else {
+            return Ok(Vec::new());
+        };
+        let Value::Array(holes) = holes else {
+            return Err(bad());
+        };
+        holes
+            .iter()
+            .map(|hole| match hole {
+                Value::Array(pair) if pair.len() == 2 => {
+                    let start = parse_size(&pair[0]).ok_or_else(bad)?;
+                    let length = parse_size(&pair[1]).ok_or_else(bad)?;
+                    Ok((start, start.saturating_add(length)))
+                }
+                _ => Err(bad()),
+            })
+            .collect()
+    }
+}
+
+/// Split the TOML subset described at the top of this file into sections
+fn parse_spec(spec: &str) -> io::Result<Vec<Section>> {
+    let mut parser = Parser {
+        input: spec.as_bytes(),
+        position: 0,
+        line: 1,
+    };
+    let mut sections: Vec<Section> = Vec::new();
+    loop {
+        parser.skip_blank();
+        match parser.peek() {
+            None => break,
+            Some(b'[') => {
+                parser.expect(b"[[", "only [[section]] headers are supported")?;
+                let line = parser.line;
+                parser.skip_space();
+                let kind = parser.key()?;
+                parser.skip_space();
+                parser.expect(b"]]", "expected ]]")?;
+                parser.end_of_line()?;
+                sections.push(Section {
+                    kind,
+                    line,
+                    keys: Vec::new(),
+                });
+            }
+            Some(_) => {
+                let key = parser.key()?;
+                parser.skip_space();
+                parser.expect(b"=", "expected =")?;
+                parser.skip_space();
+                let value = parser.value()?;
+                parser.end_of_line()?;
+                let Some(section) = sections.last_mut() else {
+                    return Err(parser.error("keys must be in a [[section]]"));
+                };
+                if section.get(&key).is_some() {
+                    return Err(parser.error(format!("{} is given twice", key)));
+                }
+                section.keys.push((key, value));
+            }
+        }
+    }
+    Ok(sections)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: impl Display) -> io::Error {
+        invalid(format!("spec line {}: {}", self.line, message))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.position += 1;
+        if byte == b'\n' {
+            self.line += 1;
+        }
+        Some(byte)
+    }
+
+    fn expect(&mut self, expected: &[u8], message: &str) -> io::Result<()> {
+        if !self.input[self.position..].starts_with(expected) {
+            return Err(self.error(message));
+        }
+        self.position += expected.len();
+        Ok(())
+    }
+
+    fn skip_space(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t')) {
+            self.next();
+        }
+    }
+
+    /// Skip spaces and a comment, but not the end of the line
+    fn skip_comment(&mut self) {
+        self.skip_space();
+        if self.peek() == Some(b'#') {
+            while !matches!(self.peek(), None | Some(b'\n')) {
+                self.next();
+            }
+        }
+    }
+
+    /// Skip spaces, comments and line ends
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_comment();
+            match self.peek() {
+                Some(b'\n' | b'\r') => {
+                    self.next();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> io::Result<()> {
+        self.skip_comment();
+        if self.peek() == Some(b'\r') {
+            self.next();
+        }
+        match self.next() {
+            None | Some(b'\n') => Ok(()),
+            Some(_) => Err(self.error("expected the end of the line")),
+        }
+    }
+
+    fn key(&mut self) -> io::Result<String> {
+        let start = self.position;
+        while matches!(
+            self.peek(),
+            Some(b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'_' | b'-')
+        ) {
+            self.next();
+        }
+        if start == self.position {
+            return Err(self.error("expected a key"));
+        }
+        Ok(String::from_utf8_lossy(&self.input[start..self.position]).into_owned())
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        match self.peek() {
+            Some(b'"') => self.basic_string().map(Value::String),
+            Some(b'\'') => self.literal_string().map(Value::String),
+            Some(b'[') => self.array(),
+            _ => self.integer(),
+        }
+    }
+
+    fn array(&mut self) -> io::Result<Value> {
+        self.next();
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(b']') {
+                self.next();
+                break;
+            }
+            values.push(self.value()?);
+            self.skip_blank();
+            match self.next() {
+                Some(b',') => {}
+                Some(b']') => break,
+                _ => return Err(self.error("expected , or ] in array")),
+            }
+        }
+        Ok(Value::Array(values))
+    }
+
+    fn integer(&mut self) -> io::Result<Value> {
+        let start = self.position;
+        while matches!(
+            self.peek(),
+            Some(b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'_' | b'+' | b'-')
+        ) {
+            self.next();
+        }
+        let text = String::from_utf8_lossy(&self.input[start..self.position]).replace('_', "");
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text.strip_prefix('+').unwrap_or(&text)),
+        };
+        let (radix, digits) = match digits.get(..2) {
+            Some("0x") => (16, &digits[2..]),
+            Some("0o") => (8, &digits[2..]),
+            Some("0b") => (2, &digits[2..]),
+            _ => (10, digits),
+        };
+        let value = i64::from_str_radix(digits, radix)
+            .map_err(|_| self.error(format!("expected a value, found {:?}", text)))?;
+        Ok(Value::Integer(if negative { -value } else { value }))
+    }
+
+    fn literal_string(&mut self) -> io::Result<String> {
+        self.next();
+        let start = self.position;
+        loop {
+            match self.next() {
+                Some(b'\'') => break,
+                None | Some(b'\n') => return Err(self.error("unterminated string")),
+                Some(_) => {}
+            }
+        }
+        String::from_utf8(self.input[start..self.position - 1].to_vec())
+            .map_err(|_| self.error("string is not UTF-8"))
+    }
+
+    fn basic_string(&mut self) -> io::Result<String> {
+        self.next();
+        let mut bytes = Vec::new();
+        loop {
+            match self.next() {
+                Some(b'"') => break,
+                None | Some(b'\n') => return Err(self.error("unterminated string")),
+                Some(b'\\') => {
+                    let escaped = match self.next() {
+                        Some(b'n') => '\n',
+                        Some(b't') => '\t',
+                        Some(b'r') => '\r',
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'u') => self.unicode_escape(4)?,
+                        Some(b'U') => self.unicode_escape(8)?,
+                        _ => return Err(self.error("unknown escape in string")),
+                    };
+                    let mut utf8 = [0; 4];
+                    bytes.extend_from_slice(escaped.encode_utf8(&mut utf8).as_bytes());
+                }
+                Some(byte) => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("string is not UTF-8"))
+    }
+
+    fn unicode_escape(&mut self, digits: usize) -> io::Result<char> {
+        let hex = self
+            .input
+            .get(self.position..self.position + digits)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error("bad unicode escape in string"))?;
+        self.position += digits;
+        Ok(hex)
+    }
+}
@@ -1,7 +1,46 @@
+use crate::ext2::features::{CompatFeatures, IncompatFeatures, RoCompatFeatures};
 use bitflags::bitflags;
 use null_terminated::NulStr;
+use std::mem::offset_of;
 
-#[repr(C)]
+/// Size of the direct, singly, doubly and triply indirect pointers together
+pub const BLOCK_POINTER_BYTES: usize = 15 * 4;
+
+/// `Superblock::state`: unmounted cleanly
+pub const FS_CLEAN: u16 = 1;
+/// `Superblock::state`: errors were detected
+pub const FS_ERR: u16 = 2;
+
+/// `Superblock::errors`: carry on as if nothing happened
+pub const ERR_IGNORE: u16 = 1;
+/// `Superblock::errors`: remount read-only
+pub const ERR_RONLY: u16 = 2;
+/// `Superblock::errors`: panic
+pub const ERR_PANIC: u16 = 3;
+
+/// `Superblock::creator_os` values
+pub const OS_LINUX: u32 = 0;
+pub const OS_HURD: u32 = 1;
+pub const OS_MASIX: u32 = 2;
+pub const OS_FREEBSD: u32 = 3;
+pub const OS_LITES: u32 = 4;
+
+/// `Superblock::flags`: directory hashes treat names as signed chars
+pub const FLAGS_SIGNED_HASH: u32 = 0x0001;
+/// `Superblock::flags`: directory hashes treat names as unsigned chars
+pub const FLAGS_UNSIGNED_HASH: u32 = 0x0002;
+/// `Superblock::flags`: a development version of the code may use this
+/// filesystem
+pub const FLAGS_TEST_FILESYS: u32 = 0x0004;
+
+/// `Superblock::jnl_backup_type`: `jnl_blocks` holds the journal inode's
+/// block pointers and size
+pub const JNL_BACKUP_BLOCKS: u8 = 1;
+
+// Packed to 4 bytes so the few 64-bit fields don't make the struct need more
+// alignment than the image buffers it's cast out of are guaranteed to have.
+// Read those fields by value; references to them aren't allowed.
+#[repr(C, packed(4))]
 #[derive(Debug)]
 // https://wiki.osdev.org/Ext2
 pub struct Superblock {
@@ -75,13 +114,13 @@ pub struct Superblock {
     pub block_group: u16,
     /// Optional features present (features that are not required to read
     /// or write, but usually result in a performance increase)
-    pub features_opt: u32,
+    pub features_opt: CompatFeatures,
     /// Required features present (features that are required to be
     /// supported to read or write)
-    pub features_req: u32,
+    pub features_req: IncompatFeatures,
     /// Features that if not supported, the volume must be mounted
     /// read-only)
-    pub features_ronly: u32,
+    pub features_ronly: RoCompatFeatures,
     /// File system ID (what is output by blkid)
     pub fs_id: [u8; 16],
     /// Volume name (C-style string: characters terminated by a 0 byte)
@@ -95,8 +134,9 @@ pub struct Superblock {
     pub prealloc_blocks_files: u8,
     /// Number of blocks to preallocate for directories
     pub prealloc_blocks_dirs: u8,
-    #[doc(hidden)]
-    _unused: [u8; 2],
+    /// Number of blocks reserved after the group descriptor table for
+    /// growing it when the filesystem is resized
+    pub reserved_gdt_blocks: u16,
     /// Journal ID (same style as the File system ID above)
     pub journal_id: [u8; 16],
     /// Journal inode
@@ -105,6 +145,253 @@ pub struct Superblock {
     pub journal_dev: u32,
     /// Head of orphan inode list
     pub journal_orphan_head: u32,
+    /// Seed for the hash of names in directory indexes
+    pub hash_seed: [u32; 4],
+    /// Hash used by default in directory indexes
+    pub def_hash_version: u8,
+    /// Whether `jnl_blocks` holds a copy of the journal inode's block
+    /// pointers (`JNL_BACKUP_BLOCKS`)
+    pub jnl_backup_type: u8,
+    /// Size of a group descriptor with the 64bit feature
+    pub desc_size: u16,
+    /// Mount options to use when none are given
+    pub default_mount_opts: DefaultMountOpts,
+    /// First block group of the first meta block group, with the meta_bg
+    /// feature
+    pub first_meta_bg: u32,
+    /// When the filesystem was created (in POSIX time)
+    pub mkfs_time: u32,
+    /// Copy of the journal inode's 15 block pointers, then the upper and
+    /// lower 32 bits of its size
+    pub jnl_blocks: [u32; 17],
+
+    // ext4 from here on: none of it applies to the features this
+    // implementation supports, but it's decoded so it can be reported
+    /// Upper 32 bits of `blocks_count`, with the 64bit feature
+    pub blocks_count_hi: u32,
+    /// Upper 32 bits of `r_blocks_count`
+    pub r_blocks_count_hi: u32,
+    /// Upper 32 bits of `free_blocks_count`
+    pub free_blocks_count_hi: u32,
+    /// Bytes of extra inode fields every inode has
+    pub min_extra_isize: u16,
+    /// Bytes of extra inode fields new inodes should have
+    pub want_extra_isize: u16,
+    /// Miscellaneous flags (see `FLAGS_SIGNED_HASH` and friends)
+    pub flags: u32,
+    /// RAID stride, in blocks
+    pub raid_stride: u16,
+    /// Seconds between multi-mount protection updates
+    pub mmp_update_interval: u16,
+    /// Block holding the multi-mount protection data
+    pub mmp_block: u64,
+    /// RAID stripe width, in blocks
+    pub raid_stripe_width: u32,
+    /// log2 of the number of groups in a flex_bg group
+    pub log_groups_per_flex: u8,
+    /// Metadata checksum algorithm (1 for crc32c)
+    pub checksum_type: u8,
+    /// Versioning level for encryption
+    pub encryption_level: u8,
+    _reserved_pad: u8,
+    /// KiB written over the filesystem's lifetime
+    pub kbytes_written: u64,
+    /// Inode of the active snapshot
+    pub snapshot_inum: u32,
+    /// Sequential ID of the active snapshot
+    pub snapshot_id: u32,
+    /// Blocks reserved for the active snapshot's future use
+    pub snapshot_r_blocks_count: u64,
+    /// Head of the on-disk snapshot list
+    pub snapshot_list: u32,
+    /// Number of errors seen
+    pub error_count: u32,
+    /// When the first error happened (in POSIX time)
+    pub first_error_time: u32,
+    /// Inode involved in the first error
+    pub first_error_ino: u32,
+    /// Block involved in the first error
+    pub first_error_block: u64,
+    /// Function where the first error happened (C-style string)
+    pub first_error_func: [u8; 32],
+    /// Line number where the first error happened
+    pub first_error_line: u32,
+    /// When the most recent error happened (in POSIX time)
+    pub last_error_time: u32,
+    /// Inode involved in the most recent error
+    pub last_error_ino: u32,
+    /// Line number where the most recent error happened
+    pub last_error_line: u32,
+    /// Block involved in the most recent error
+    pub last_error_block: u64,
+    /// Function where the most recent error happened (C-style string)
+    pub last_error_func: [u8; 32],
+    /// Mount options (C-style string)
+    pub mount_opts: [u8; 64],
+    /// Inode of the user quota file
+    pub usr_quota_inum: u32,
+    /// Inode of the group quota file
+    pub grp_quota_inum: u32,
+    /// Blocks of metadata overhead, 0 to have it computed
+    pub overhead_clusters: u32,
+    /// The only groups with superblock backups, with the sparse_super2
+    /// feature
+    pub backup_bgs: [u32; 2],
+    /// Encryption algorithms in use
+    pub encrypt_algos: [u8; 4],
+    /// Salt for the string2key algorithm of encryption
+    pub encrypt_pw_salt: [u8; 16],
+    /// Inode of lost+found
+    pub lpf_ino: u32,
+    /// Inode of the project quota file
+    pub prj_quota_inum: u32,
+    /// Seed for metadata checksums, with the metadata_csum_seed feature
+    pub checksum_seed: u32,
+    /// Upper 8 bits of `wtime`
+    pub wtime_hi: u8,
+    /// Upper 8 bits of `mtime`
+    pub mtime_hi: u8,
+    /// Upper 8 bits of `mkfs_time`
+    pub mkfs_time_hi: u8,
+    /// Upper 8 bits of `lastcheck`
+    pub lastcheck_hi: u8,
+    /// Upper 8 bits of `first_error_time`
+    pub first_error_time_hi: u8,
+    /// Upper 8 bits of `last_error_time`
+    pub last_error_time_hi: u8,
+    /// Error code of the first error
+    pub first_error_errcode: u8,
+    /// Error code of the most recent error
+    pub last_error_errcode: u8,
+    /// Filename charset encoding, with the casefold feature
+    pub encoding: u16,
+    /// Filename charset encoding flags
+    pub encoding_flags: u16,
+    /// Inode of the orphan file
+    pub orphan_file_inum: u32,
+    _reserved: [u32; 94],
+    /// Checksum of the superblock, with the metadata_csum feature
+    pub checksum: u32,
+}
+
+const _: () = assert!(std::mem::size_of::<Superblock>() == 1024);
+
+impl Superblock {
+    /// Revision 1 ("dynamic") superblocks define everything from
+    /// `first_inode` on. Revision 0 ones end at `block_gid`, and the bytes
+    /// after it mean nothing even if they aren't zero.
+    pub fn is_dynamic(&self) -> bool {
+        self.rev_major >= 1
+    }
+
+    /// `features_opt`, or none for revision 0
+    pub fn compat(&self) -> CompatFeatures {
+        if self.is_dynamic() {
+            self.features_opt
+        } else {
+            CompatFeatures::empty()
+        }
+    }
+
+    /// `features_req`, or none for revision 0
+    pub fn incompat(&self) -> IncompatFeatures {
+        if self.is_dynamic() {
+            self.features_req
+        } else {
+            IncompatFeatures::empty()
+        }
+    }
+
+    /// `features_ronly`, or none for revision 0
+    pub fn ro_compat(&self) -> RoCompatFeatures {
+        if self.is_dynamic() {
+            self.features_ronly
+        } else {
+            RoCompatFeatures::empty()
+        }
+    }
+
+    /// `default_mount_opts`, or none for revision 0
+    pub fn mount_defaults(&self) -> DefaultMountOpts {
+        if self.is_dynamic() {
+            self.default_mount_opts
+        } else {
+            DefaultMountOpts::empty()
+        }
+    }
+
+    /// `mkfs_time`, if the filesystem records when it was created
+    pub fn created(&self) -> Option<u32> {
+        if self.is_dynamic() && self.mkfs_time != 0 {
+            Some(self.mkfs_time)
+        } else {
+            None
+        }
+    }
+}
+
+bitflags! {
+    /// `Superblock::default_mount_opts`
+    #[repr(transparent)]
+    pub struct DefaultMountOpts: u32 {
+        const DEBUG = 0x0001;
+        const BSDGROUPS = 0x0002;
+        const XATTR_USER = 0x0004;
+        const ACL = 0x0008;
+        const UID16 = 0x0010;
+        /// Journalling mode: one of `JMODE_DATA`, `JMODE_ORDERED` and
+        /// `JMODE_WBACK`
+        const JMODE = 0x0060;
+        const JMODE_DATA = 0x0020;
+        const JMODE_ORDERED = 0x0040;
+        const JMODE_WBACK = 0x0060;
+        const NOBARRIER = 0x0100;
+        const BLOCK_VALIDITY = 0x0200;
+        const DISCARD = 0x0400;
+        const NODELALLOC = 0x0800;
+    }
+}
+
+/// Names of the default mount options as mount and tune2fs take them,
+/// journalling mode first
+pub const MOUNT_OPTS: [(&str, DefaultMountOpts); 12] = [
+    ("journal_data", DefaultMountOpts::JMODE_DATA),
+    ("journal_data_ordered", DefaultMountOpts::JMODE_ORDERED),
+    ("journal_data_writeback", DefaultMountOpts::JMODE_WBACK),
+    ("debug", DefaultMountOpts::DEBUG),
+    ("bsdgroups", DefaultMountOpts::BSDGROUPS),
+    ("user_xattr", DefaultMountOpts::XATTR_USER),
+    ("acl", DefaultMountOpts::ACL),
+    ("uid16", DefaultMountOpts::UID16),
+    ("nobarrier", DefaultMountOpts::NOBARRIER),
+    ("block_validity", DefaultMountOpts::BLOCK_VALIDITY),
+    ("discard", DefaultMountOpts::DISCARD),
+    ("nodelalloc", DefaultMountOpts::NODELALLOC),
+];
+
+impl DefaultMountOpts {
+    /// Names of the options set, as dumpe2fs lists them, with bits no
+    /// option is known for as `MNTOPT_12` for bit 12
+    pub fn names(self) -> Vec<String> {
+        let jmode = self.bits() & DefaultMountOpts::JMODE.bits();
+        let mut names: Vec<String> = MOUNT_OPTS[..3]
+            .iter()
+            .filter(|(_, opt)| opt.bits() == jmode)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        for bit in
+            (0..32).filter(|bit| self.bits() & !DefaultMountOpts::JMODE.bits() & 1 << bit != 0)
+        {
+            match MOUNT_OPTS[3..]
+                .iter()
+                .find(|(_, opt)| opt.bits() == 1 << bit)
+            {
+                Some((name, _)) => names.push(name.to_string()),
+                None => names.push(format!("MNTOPT_{}", bit)),
+            }
+        }
+        names
+    }
 }
 
 #[repr(C)]
@@ -127,6 +414,7 @@ pub struct BlockGroupDescriptor {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct Inode {
     /// Type and Permissions (see below)
     pub type_perm: TypePerm,
@@ -152,7 +440,7 @@ pub struct Inode {
     /// to the inode.
     pub sectors_count: u32,
     /// Flags
-    pub flags: u32,
+    pub flags: InodeFlags,
     /// Operating System Specific value #1
     pub _os_specific_1: [u8; 4],
     /// Direct block pointers
@@ -182,6 +470,70 @@ pub struct Inode {
     _padding: [u8; 128], // TODO: handle inode sizes != 128 according to superblock
 }
 
+impl Inode {
+    /// Size in bytes. `size_high` only holds the upper half of the size for
+    /// regular files; for directories it is the directory ACL.
+    pub fn size(&self) -> u64 {
+        if self.type_perm.is_file() {
+            (self.size_high as u64) << 32 | self.size_low as u64
+        } else {
+            self.size_low as u64
+        }
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        self.size_low = size as u32;
+        if self.type_perm.is_file() {
+            self.size_high = (size >> 32) as u32;
+        }
+    }
+
+    // On Linux, bytes 4..6 and 6..8 of the second OS-specific value hold the
+    // upper 16 bits of the user and group IDs.
+
+    /// User ID, including the high 16 bits
+    pub fn uid(&self) -> u32 {
+        let high = u16::from_le_bytes([self._os_specific_2[4], self._os_specific_2[5]]);
+        (high as u32) << 16 | self.uid as u32
+    }
+
+    /// Group ID, including the high 16 bits
+    pub fn gid(&self) -> u32 {
+        let high = u16::from_le_bytes([self._os_specific_2[6], self._os_specific_2[7]]);
+        (high as u32) << 16 | self.gid as u32
+    }
+
+    pub fn set_uid(&mut self, uid: u32) {
+        self.uid = uid as u16;
+        self._os_specific_2[4..6].copy_from_slice(&((uid >> 16) as u16).to_le_bytes());
+    }
+
+    pub fn set_gid(&mut self, gid: u32) {
+        self.gid = gid as u16;
+        self._os_specific_2[6..8].copy_from_slice(&((gid >> 16) as u16).to_le_bytes());
+    }
+
+    /// The 15 block pointers as raw bytes. A fast symlink keeps its target
+    /// here instead of pointing at a block.
+    pub fn block_pointer_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                (self as *const Inode as *const u8).add(offset_of!(Inode, direct_pointer)),
+                BLOCK_POINTER_BYTES,
+            )
+        }
+    }
+
+    pub fn block_pointer_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                (self as *mut Inode as *mut u8).add(offset_of!(Inode, direct_pointer)),
+                BLOCK_POINTER_BYTES,
+            )
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct DirectoryEntry {
@@ -198,16 +550,33 @@ pub struct DirectoryEntry {
     pub name: NulStr,
 }
 
-#[derive(Debug)]
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TypeIndicator {
-    Unknown,
-    Regular,
-    Directory,
-    Character,
-    Block,
-    Fifo,
-    Socket,
-    Symlink,
+    Unknown = 0,
+    Regular = 1,
+    Directory = 2,
+    Character = 3,
+    Block = 4,
+    Fifo = 5,
+    Socket = 6,
+    Symlink = 7,
+}
+
+impl TypeIndicator {
+    /// The directory entry type matching the file-type nibble of `type_perm`
+    pub fn from_type_perm(type_perm: TypePerm) -> TypeIndicator {
+        match type_perm.file_type() {
+            TypePerm::FILE => TypeIndicator::Regular,
+            TypePerm::DIRECTORY => TypeIndicator::Directory,
+            TypePerm::CHAR_DEVICE => TypeIndicator::Character,
+            TypePerm::BLOCK_DEVICE => TypeIndicator::Block,
+            TypePerm::FIFO => TypeIndicator::Fifo,
+            TypePerm::SOCKET => TypeIndicator::Socket,
+            TypePerm::SYMLINK => TypeIndicator::Symlink,
+            _ => TypeIndicator::Unknown,
+        }
+    }
 }
 
 bitflags! {
@@ -252,3 +621,133 @@ bitflags! {
         const SET_UID = 0x800;
     }
 }
+
+impl TypePerm {
+    /// Mask covering the file-type nibble; the type constants above are
+    /// values within this nibble rather than independent bits
+    pub const TYPE_MASK: u16 = 0xF000;
+
+    /// Just the file-type nibble, e.g. `TypePerm::DIRECTORY`
+    pub fn file_type(&self) -> TypePerm {
+        TypePerm::from_bits_truncate(self.bits() & Self::TYPE_MASK)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == TypePerm::DIRECTORY
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type() == TypePerm::FILE
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type() == TypePerm::SYMLINK
+    }
+}
+
+bitflags! {
+    /// `Inode::flags`
+    #[repr(transparent)]
+    pub struct InodeFlags: u32 {
+        /// Zero the blocks of the file when they are freed
+        const SECURE_DELETE = 0x0000_0001;
+        /// Keep the contents around to be undeleted (no implementation
+        /// does)
+        const UNDELETE = 0x0000_0002;
+        /// Compress the file (no implementation does)
+        const COMPRESS = 0x0000_0004;
+        /// Write changes to disk synchronously
+        const SYNC = 0x0000_0008;
+        /// Nothing may change the inode or its contents, or link or
+        /// unlink it
+        const IMMUTABLE = 0x0000_0010;
+        /// The file may only be appended to, and not otherwise changed,
+        /// linked or unlinked
+        const APPEND = 0x0000_0020;
+        /// dump(8) skips the file
+        const NODUMP = 0x0000_0040;
+        /// Don't update the access time
+        const NOATIME = 0x0000_0080;
+        const DIRTY = 0x0000_0100;
+        const COMPRESSED_BLOCKS = 0x0000_0200;
+        const NOCOMPRESS = 0x0000_0400;
+        const ENCRYPT = 0x0000_0800;
+        /// The directory is indexed by name hashes (htree)
+        const INDEX = 0x0000_1000;
+        const IMAGIC = 0x0000_2000;
+        const JOURNAL_DATA = 0x0000_4000;
+        const NOTAIL = 0x0000_8000;
+        /// Write changes to the directory to disk synchronously
+        const DIRSYNC = 0x0001_0000;
+        /// The directory is the top of a hierarchy, which the Orlov
+        /// allocator spreads out
+        const TOPDIR = 0x0002_0000;
+        const HUGE_FILE = 0x0004_0000;
+        const EXTENTS = 0x0008_0000;
+        const VERITY = 0x0010_0000;
+        const EA_INODE = 0x0020_0000;
+        const NOCOW = 0x0080_0000;
+        const DAX = 0x0200_0000;
+        const INLINE_DATA = 0x1000_0000;
+        const PROJINHERIT = 0x2000_0000;
+        const CASEFOLD = 0x4000_0000;
+    }
+}
+
+impl InodeFlags {
+    /// The flags chattr may change; the rest describe how the inode is
+    /// stored. The same as the Linux ext2 driver allows.
+    pub const USER_MODIFIABLE: InodeFlags = InodeFlags::from_bits_truncate(0x0003_80ff);
+    /// The flags a new inode inherits from its directory, as Linux has
+    /// them
+    pub const INHERITED: InodeFlags = InodeFlags::from_bits_truncate(0x0001_c6cf);
+
+    /// The flags a new inode of type `type_perm` inherits from flags
+    /// `parent` of its directory. Only directories inherit the flags
+    /// about directories, and only regular files those about data.
+    pub fn inherited(parent: InodeFlags, type_perm: TypePerm) -> InodeFlags {
+        let flags = parent & InodeFlags::INHERITED;
+        if type_perm.is_dir() {
+            flags
+        } else if type_perm.is_file() {
+            flags - InodeFlags::DIRSYNC - InodeFlags::TOPDIR
+        } else {
+            flags & (InodeFlags::NODUMP | InodeFlags::NOATIME)
+        }
+    }
+
+    /// The flags as lsattr shows them: a letter for each that is set, in
+    /// the order of `INODE_FLAG_LETTERS`, and `-` for each that isn't
+    pub fn letters(&self) -> String {
+        INODE_FLAG_LETTERS
+            .iter()
+            .map(|&(letter, flag)| if self.contains(flag) { letter } else { '-' })
+            .collect()
+    }
+}
+
+/// Letters lsattr and chattr use for inode flags, in lsattr's order
+pub const INODE_FLAG_LETTERS: [(char, InodeFlags); 22] = [
+    ('s', InodeFlags::SECURE_DELETE),
+    ('u', InodeFlags::UNDELETE),
+    ('S', InodeFlags::SYNC),
+    ('D', InodeFlags::DIRSYNC),
+    ('i', InodeFlags::IMMUTABLE),
+    ('a', InodeFlags::APPEND),
+    ('d', InodeFlags::NODUMP),
+    ('A', InodeFlags::NOATIME),
+    ('c', InodeFlags::COMPRESS),
+    ('E', InodeFlags::ENCRYPT),
+    ('j', InodeFlags::JOURNAL_DATA),
+    ('I', InodeFlags::INDEX),
+    ('t', InodeFlags::NOTAIL),
+    ('T', InodeFlags::TOPDIR),
+    ('e', InodeFlags::EXTENTS),
+    ('C', InodeFlags::NOCOW),
+    ('x', InodeFlags::DAX),
+    ('F', InodeFlags::CASEFOLD),
+    ('N', InodeFlags::INLINE_DATA),
+    ('P', InodeFlags::PROJINHERIT),
+    ('V', InodeFlags::VERITY),
+    ('m', InodeFlags::NOCOMPRESS),
+];
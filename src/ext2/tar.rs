@@ -0,0 +1,608 @@
+// Streaming part of an image out as a tar archive and populating an image
+// from one, so tarballs and images can be converted without mounting.
+// Archives are POSIX ustar, with pax extended headers for the names, IDs and
+// sizes that don't fit in a ustar header.
+// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/pax.html#tag_20_92_13
+
+use crate::ext2::error::Ext2Error;
+use crate::ext2::structs::TypePerm;
+use crate::ext2::Ext2;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Archives are made of 512-byte blocks
+const BLOCK: usize = 512;
+/// Largest value of an 8-byte numeric header field (7 octal digits)
+const MAX_ID: u64 = 0o7777777;
+/// Largest value of a 12-byte numeric header field (11 octal digits)
+const MAX_SIZE: u64 = 0o77777777777;
+/// Pax records and GNU long names bigger than this are refused rather
+/// than read into memory
+const MAX_METADATA: u64 = 1 << 20;
+
+// header type flags
+const REGULAR: u8 = b'0';
+/// Regular file as written by pre-POSIX tars
+const OLD_REGULAR: u8 = 0;
+const HARD_LINK: u8 = b'1';
+const SYMLINK: u8 = b'2';
+const CHAR_DEVICE: u8 = b'3';
+const BLOCK_DEVICE: u8 = b'4';
+const DIRECTORY: u8 = b'5';
+const FIFO: u8 = b'6';
+/// Regular file, in theory stored contiguously
+const CONTIGUOUS: u8 = b'7';
+/// Pax records for the next member
+const PAX: u8 = b'x';
+/// Pax records for every later member
+const PAX_GLOBAL: u8 = b'g';
+/// GNU: the data is the name of the next member
+const GNU_LONG_NAME: u8 = b'L';
+/// GNU: the data is the link target of the next member
+const GNU_LONG_LINK: u8 = b'K';
+
+/// One archive member, from its header and any pax records before it
+#[derive(Debug, Clone, Default)]
+struct Member {
+    path: Vec<u8>,
+    /// Target of a symlink or hard link
+    link: Vec<u8>,
+    type_flag: u8,
+    /// Permission, set-ID and sticky bits
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u32,
+    atime: Option<u32>,
+    /// (major, minor) of a device
+    device: (u32, u32),
+}
+
+/// Image paths already in the archive of inodes with several links
+type Written = HashMap<usize, Vec<u8>>;
+
+impl Ext2 {
+    /// Write `inode` and everything below it to `out` as a tar archive.
+    ///
+    /// Members are named relative to `inode`, which is `./` itself. Owners,
+    /// permissions and modification times are kept; inodes with several
+    /// links are stored once and then as hard links to that first name.
+    /// Holes are written out as zeros. Sockets are left out, as tar has no
+    /// type for them.
+    pub fn write_tar<W: Write>(&self, inode: usize, mut out: W) -> io::Result<()> {
+        let mut written = Written::new();
+        self.write_member(inode, b".".to_vec(), &mut out, &mut written)?;
+        // the end of an archive is marked by two blocks of zeros
+        out.write_all(&[0; 2 * BLOCK])?;
+        out.flush()
+    }
+
+    fn write_member<W: Write>(
+        &self,
+        inode: usize,
+        path: Vec<u8>,
+        out: &mut W,
+        written: &mut Written,
+    ) -> io::Result<()> {
+        let metadata = self.metadata(inode);
+        let file_type = metadata.type_perm.file_type();
+        let mut member = Member {
+            path,
+            mode: metadata.type_perm.bits() & !TypePerm::TYPE_MASK,
+            uid: metadata.uid,
+            gid: metadata.gid,
+            mtime: metadata.mtime,
+            ..Member::default()
+        };
+        if file_type != TypePerm::DIRECTORY && metadata.hard_links > 1 {
+            if let Some(first) = written.get(&inode) {
+                member.type_flag = HARD_LINK;
+                member.link = first.clone();
+                return write_header(out, &member);
+            }
+        }
+
+        match file_type {
+            TypePerm::DIRECTORY => {
+                member.type_flag = DIRECTORY;
+                let path = member.path.clone();
+                member.path.push(b'/');
+                write_header(out, &member)?;
+                for record in self.dir_records(inode) {
+                    if record.inode == 0 || record.name == b"." || record.name == b".." {
+                        continue;
+                    }
+                    let mut child = path.clone();
+                    child.push(b'/');
+                    child.extend_from_slice(&record.name);
+                    self.write_member(record.inode as usize, child, out, written)?;
+                }
+                return Ok(());
+            }
+            TypePerm::FILE => {
+                member.type_flag = REGULAR;
+                member.size = metadata.size;
+                write_header(out, &member)?;
+                let mut buffer = vec![0; self.block_size];
+                let mut offset = 0;
+                while offset < metadata.size {
+                    let length = self.read(inode, offset, &mut buffer)?;
+                    out.write_all(&buffer[..length])?;
+                    offset += length as u64;
+                }
+                write_padding(out, metadata.size)?;
+            }
+            TypePerm::SYMLINK => {
+                member.type_flag = SYMLINK;
+                member.link = self.readlink(inode)?;
+                write_header(out, &member)?;
+            }
+            TypePerm::CHAR_DEVICE | TypePerm::BLOCK_DEVICE | TypePerm::FIFO => {
+                member.type_flag = match file_type {
+                    TypePerm::CHAR_DEVICE => CHAR_DEVICE,
+                    TypePerm::BLOCK_DEVICE => BLOCK_DEVICE,
+                    _ => FIFO,
+                };
+                member.device = metadata.rdev.unwrap_or((0, 0));
+                write_header(out, &member)?;
+            }
+            _ => return Ok(()),
+        }
+        if metadata.hard_links > 1 {
+            written.insert(inode, member.path);
+        }
+        Ok(())
+    }
+
+    /// Add the members of the tar archive read from `input` to directory
+    /// `dir`, which the archive's `./` stands for.
+    ///
+    /// Reads ustar archives, with pax extended headers or GNU long names.
+    /// Directories missing from the archive are created; existing
+    /// directories are merged into and other existing files replaced, as
+    /// tar would. Member paths can't lead outside `dir`. Directory
+    /// attributes are set once everything is in, so adding their entries
+    /// doesn't change their times.
+    pub fn read_tar<R: Read>(&mut self, dir: usize, mut input: R) -> io::Result<()> {
+        let mut pax = HashMap::new();
+        let mut global_pax = HashMap::new();
+        let mut dirs = Vec::new();
+        let mut header = [0; BLOCK];
+        while read_header(&mut input, &mut header)? {
+            // the first of the two zero blocks ending the archive
+            if header.iter().all(|&byte| byte == 0) {
+                break;
+            }
+            let mut member = parse_header(&header)?;
+            match member.type_flag {
+                PAX => {
+                    parse_pax(&read_metadata(&mut input, member.size)?, &mut pax)?;
+                    continue;
+                }
+                PAX_GLOBAL => {
+                    parse_pax(&read_metadata(&mut input, member.size)?, &mut global_pax)?;
+                    continue;
+                }
+                GNU_LONG_NAME | GNU_LONG_LINK => {
+                    let mut name = read_metadata(&mut input, member.size)?;
+                    if let Some(end) = name.iter().position(|&byte| byte == 0) {
+                        name.truncate(end);
+                    }
+                    let key = if member.type_flag == GNU_LONG_NAME {
+                        "path"
+                    } else {
+                        "linkpath"
+                    };
+                    pax.insert(key.to_string(), name);
+                    continue;
+                }
+                _ => {}
+            }
+            apply_pax(&mut member, &global_pax)?;
+            apply_pax(&mut member, &pax)?;
+            pax.clear();
+
+            let mut data = (&mut input).take(member.size);
+            self.read_member(dir, &member, &mut data, &mut dirs)
+                .map_err(|err| {
+                    let path = String::from_utf8_lossy(&member.path);
+                    io::Error::new(err.kind(), format!("{}: {}", path, err))
+                })?;
+            // whatever of the member's data wasn't used, e.g. for a hard
+            // link that stores some anyway
+            io::copy(&mut data, &mut io::sink())?;
+            if data.limit() != 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            skip(&mut input, padding(member.size))?;
+        }
+        // innermost directories first, though only the times depend on it
+        for (inode, member) in dirs.iter().rev() {
+            self.set_member_attributes(*inode, member)?;
+        }
+        Ok(())
+    }
+
+    fn read_member(
+        &mut self,
+        dir: usize,
+        member: &Member,
+        data: &mut impl Read,
+        dirs: &mut Vec<(usize, Member)>,
+    ) -> io::Result<()> {
+        let mut type_flag = member.type_flag;
+        // pre-POSIX tars mark directories with a trailing slash only
+        if type_flag == OLD_REGULAR && member.path.ends_with(b"/") {
+            type_flag = DIRECTORY;
+        }
+        let mut names = member_names(&member.path)?;
+        let Some(name) = names.pop() else {
+            // the archive's `./`
+            if type_flag == DIRECTORY {
+                dirs.push((dir, member.clone()));
+            }
+            return Ok(());
+        };
+        let parent = self.make_dirs(dir, &names)?;
+
+        match self.lookup(parent, name) {
+            Ok(existing) if self.get_inode(existing).type_perm.is_dir() => {
+                if type_flag == DIRECTORY {
+                    dirs.push((existing, member.clone()));
+                    return Ok(());
+                }
+                return Err(Ext2Error::AlreadyExists.into());
+            }
+            // a later member replaces an earlier file of the same name
+            Ok(existing) => {
+                self.remove_dir_entry(parent, name)?;
+                self.drop_link(existing);
+            }
+            Err(Ext2Error::NotFound) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let inode = match type_flag {
+            REGULAR | OLD_REGULAR | CONTIGUOUS => {
+                let inode = self.create(parent, name, 0o644)?;
+                self.import_data(inode, data)?;
+                inode
+            }
+            HARD_LINK => {
+                // the target is named from the archive's `./` too
+                let mut target = dir;
+                for name in member_names(&member.link)? {
+                    target = self.lookup(target, name)?;
+                }
+                // the link shares the attributes of its target
+                return Ok(self.link(target, parent, name)?);
+            }
+            SYMLINK => {
+                let target = std::str::from_utf8(&member.link).map_err(|_| {
+                    io::Error::new(ErrorKind::InvalidData, "symlink target is not UTF-8")
+                })?;
+                self.symlink(parent, name, target)?
+            }
+            CHAR_DEVICE | BLOCK_DEVICE | FIFO => {
+                let kind = match type_flag {
+                    CHAR_DEVICE => TypePerm::CHAR_DEVICE,
+                    BLOCK_DEVICE => TypePerm::BLOCK_DEVICE,
+                    _ => TypePerm::FIFO,
+                };
+                let (major, minor) = member.device;
+                self.mknod(parent, name, kind, major, minor)?
+            }
+            DIRECTORY => {
+                let inode = self.mkdir(parent, name, 0o755)?;
+                dirs.push((inode, member.clone()));
+                return Ok(());
+            }
+            other => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported member type {:?}", other as char),
+                ))
+            }
+        };
+        self.set_member_attributes(inode, member)
+    }
+
+    fn set_member_attributes(&mut self, inode: usize, member: &Member) -> io::Result<()> {
+        if !self.get_inode(inode).type_perm.is_symlink() {
+            self.chmod(inode, member.mode)?;
+        }
+        self.chown(inode, Some(member.uid), Some(member.gid))?;
+        let mtime = self.clamp_time(member.mtime);
+        let atime = self.clamp_time(member.atime.unwrap_or(member.mtime));
+        self.utimes(inode, atime, mtime)?;
+        Ok(())
+    }
+}
+
+/// The components of a member path, without `.` components or a leading
+/// `/`; paths with `..` components are refused
+fn member_names(path: &[u8]) -> io::Result<Vec<&str>> {
+    let path = std::str::from_utf8(path)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "name is not UTF-8"))?;
+    let names: Vec<&str> = path
+        .split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .collect();
+    if names.contains(&"..") {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "path leads outside the archive",
+        ));
+    }
+    Ok(names)
+}
+
+/// Write the header of `member`, preceded by a pax header if some of it
+/// doesn't fit
+fn write_header<W: Write>(out: &mut W, member: &Member) -> io::Result<()> {
+    let mut header = [0; BLOCK];
+    let mut pax = Vec::new();
+    match split_path(&member.path) {
+        Some((prefix, name)) => {
+            header[345..345 + prefix.len()].copy_from_slice(prefix);
+            header[..name.len()].copy_from_slice(name);
+        }
+        None => {
+            pax_record(&mut pax, "path", &member.path);
+            header[..100].copy_from_slice(&member.path[..100]);
+        }
+    }
+    if member.link.len() > 100 {
+        pax_record(&mut pax, "linkpath", &member.link);
+    }
+    let link = &member.link[..member.link.len().min(100)];
+    header[157..157 + link.len()].copy_from_slice(link);
+
+    write_number(&mut header[100..108], member.mode as u64);
+    for (key, value, range, max) in [
+        ("uid", member.uid as u64, 108..116, MAX_ID),
+        ("gid", member.gid as u64, 116..124, MAX_ID),
+        ("size", member.size, 124..136, MAX_SIZE),
+    ] {
+        if value > max {
+            pax_record(&mut pax, key, value.to_string().as_bytes());
+            write_number(&mut header[range], 0);
+        } else {
+            write_number(&mut header[range], value);
+        }
+    }
+    write_number(&mut header[136..148], member.mtime as u64);
+    header[156] = member.type_flag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    if matches!(member.type_flag, CHAR_DEVICE | BLOCK_DEVICE) {
+        write_number(&mut header[329..337], member.device.0 as u64);
+        write_number(&mut header[337..345], member.device.1 as u64);
+    }
+
+    if !pax.is_empty() {
+        let pax_member = Member {
+            path: b"././@PaxHeader".to_vec(),
+            type_flag: PAX,
+            mode: 0o644,
+            size: pax.len() as u64,
+            mtime: member.mtime,
+            ..Member::default()
+        };
+        write_header(out, &pax_member)?;
+        out.write_all(&pax)?;
+        write_padding(out, pax.len() as u64)?;
+    }
+    set_checksum(&mut header);
+    out.write_all(&header)
+}
+
+/// Split a path into the ustar prefix and name fields, if it fits
+fn split_path(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    if path.len() <= 100 {
+        return Some((&[], path));
+    }
+    // the prefix ends at a slash, which isn't stored
+    path.iter()
+        .enumerate()
+        .filter(|&(i, &byte)| byte == b'/' && i <= 155)
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(_, name)| !name.is_empty() && name.len() <= 100)
+}
+
+/// Append a pax record, `"<length> <key>=<value>\n"`, where the length
+/// counts the whole record including its own digits
+fn pax_record(pax: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + 1;
+    while length != rest + length.to_string().len() {
+        length = rest + length.to_string().len();
+    }
+    pax.extend_from_slice(format!("{} {}=", length, key).as_bytes());
+    pax.extend_from_slice(value);
+    pax.push(b'\n');
+}
+
+/// Octal digits filling all but the last byte of `field`, then a NUL
+fn write_number(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// Fill in the checksum, the sum of the header's bytes with the checksum
+/// field counted as spaces
+fn set_checksum(header: &mut [u8; BLOCK]) {
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+}
+
+/// Zeros needed after `size` bytes of data to end on a block boundary
+fn padding(size: u64) -> u64 {
+    size.next_multiple_of(BLOCK as u64) - size
+}
+
+fn write_padding<W: Write>(out: &mut W, size: u64) -> io::Result<()> {
+    out.write_all(&[0; BLOCK][..padding(size) as usize])
+}
+
+/// Read the next header block, or return false at the end of `input`
+fn read_header<R: Read>(input: &mut R, header: &mut [u8; BLOCK]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < BLOCK {
+        match input.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+fn parse_header(header: &[u8; BLOCK]) -> io::Result<Member> {
+    let corrupt = || {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            "not a tar archive, or a corrupt header",
+        )
+    };
+    let checksum = parse_number(&header[148..156]).map_err(|_| corrupt())?;
+    let mut blank = *header;
+    blank[148..156].fill(b' ');
+    let unsigned: u64 = blank.iter().map(|&byte| byte as u64).sum();
+    // some old tars summed signed bytes
+    let signed: i64 = blank.iter().map(|&byte| byte as i8 as i64).sum();
+    if checksum != unsigned && checksum as i64 != signed {
+        return Err(corrupt());
+    }
+
+    let mut path = field_bytes(&header[..100]).to_vec();
+    let prefix = field_bytes(&header[345..500]);
+    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+        path = [prefix, b"/", &path].concat();
+    }
+    Ok(Member {
+        path,
+        link: field_bytes(&header[157..257]).to_vec(),
+        type_flag: header[156],
+        mode: (parse_number(&header[100..108])? & 0o7777) as u16,
+        uid: parse_number(&header[108..116])? as u32,
+        gid: parse_number(&header[116..124])? as u32,
+        size: parse_number(&header[124..136])?,
+        mtime: parse_number(&header[136..148])?.min(u32::MAX as u64) as u32,
+        atime: None,
+        device: (
+            parse_number(&header[329..337])? as u32,
+            parse_number(&header[337..345])? as u32,
+        ),
+    })
+}
+
+/// A string field, up to its first NUL
+fn field_bytes(field: &[u8]) -> &[u8] {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    &field[..end]
+}
+
+/// A numeric field: octal digits padded with spaces or NULs, or a
+/// big-endian binary number if the top bit of the first byte is set
+fn parse_number(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        let value = field[1..]
+            .iter()
+            .fold(0u64, |value, &byte| value << 8 | byte as u64);
+        return Ok(value);
+    }
+    let digits = std::str::from_utf8(field_bytes(field))
+        .map(|digits| digits.trim_matches(' '))
+        .unwrap_or("-");
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "bad number in tar header"))
+}
+
+/// The data of a pax header or GNU long name, and its padding
+fn read_metadata<R: Read>(input: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    if size > MAX_METADATA {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "extended header too large",
+        ));
+    }
+    let mut data = vec![0; size as usize];
+    input.read_exact(&mut data)?;
+    skip(input, padding(size))?;
+    Ok(data)
+}
+
+fn skip<R: Read>(input: &mut R, bytes: u64) -> io::Result<()> {
+    if io::copy(&mut input.take(bytes), &mut io::sink())? < bytes {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Collect the records of a pax header into `pax`
+fn parse_pax(mut data: &[u8], pax: &mut HashMap<String, Vec<u8>>) -> io::Result<()> {
+    let bad = || io::Error::new(ErrorKind::InvalidData, "bad pax record");
+    while !data.is_empty() {
+        let space = data.iter().position(|&byte| byte == b' ').ok_or_else(bad)?;
+        let length: usize = std::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|length| length.parse().ok())
+            .ok_or_else(bad)?;
+        if length <= space + 1 || length > data.len() || data[length - 1] != b'\n' {
+            return Err(bad());
+        }
+        let record = &data[space + 1..length - 1];
+        let equals = record
+            .iter()
+            .position(|&byte| byte == b'=')
+            .ok_or_else(bad)?;
+        let key = String::from_utf8_lossy(&record[..equals]).into_owned();
+        pax.insert(key, record[equals + 1..].to_vec());
+        data = &data[length..];
+    }
+    Ok(())
+}
+
+/// Let the pax records that we understand override the header fields
+fn apply_pax(member: &mut Member, pax: &HashMap<String, Vec<u8>>) -> io::Result<()> {
+    let number = |value: &[u8]| -> io::Result<u64> {
+        std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "bad number in pax record"))
+    };
+    // times may have a fraction and may be negative; we keep whole
+    // seconds since 1970
+    let time = |value: &[u8]| -> io::Result<u32> {
+        let seconds = value.split(|&byte| byte == b'.').next().unwrap_or(value);
+        if seconds.starts_with(b"-") {
+            return Ok(0);
+        }
+        Ok(number(seconds)?.min(u32::MAX as u64) as u32)
+    };
+    for (key, value) in pax {
+        match key.as_str() {
+            "path" => member.path = value.clone(),
+            "linkpath" => member.link = value.clone(),
+            "size" => member.size = number(value)?,
+            "uid" => member.uid = number(value)? as u32,
+            "gid" => member.gid = number(value)? as u32,
+            "mtime" => member.mtime = time(value)?,
+            "atime" => member.atime = Some(time(value)?),
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,469 @@
+// Extended attributes: named values attached to an inode besides its fixed
+// fields. They live after the fixed part of inodes bigger than 128 bytes
+// and in a block the inode points at (`ext_attribute_block`), which inodes
+// with identical attributes may share, counting their references in its
+// header. Each is stored as an entry (name, hash and where the value is)
+// with the values packed at the other end of the space.
+// https://www.kernel.org/doc/html/latest/filesystems/ext4/attributes.html
+
+use crate::ext2::error::{Ext2Error, Result};
+use crate::ext2::features::CompatFeatures;
+use crate::ext2::Ext2;
+
+/// First word of an attribute block, and of the attributes in an inode
+const XATTR_MAGIC: u32 = 0xea020000;
+/// Size of an attribute block's header, before its first entry
+const BLOCK_HEADER: usize = 32;
+/// Size of an entry's fixed part, before the name
+const ENTRY_HEADER: usize = 16;
+/// Size of the fields every inode has; `extra_isize` bytes of newer fields
+/// follow in bigger inodes, then the attributes
+const GOOD_OLD_INODE_SIZE: usize = 128;
+/// `extra_isize` given to inodes that get attributes without having any
+/// newer fields yet: all of those the kernel knows
+const DEFAULT_EXTRA_ISIZE: u16 = 32;
+/// Longest attribute name, without its namespace prefix
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Namespaces by the index entries record them with. The two ACLs are
+/// whole names; the rest are prefixes of the names in them.
+const NAMESPACES: [(u8, &str); 6] = [
+    (1, "user."),
+    (2, "system.posix_acl_access"),
+    (3, "system.posix_acl_default"),
+    (4, "trusted."),
+    (6, "security."),
+    (7, "system."),
+];
+
+/// One attribute as stored: the namespace index, the name within that
+/// namespace, and the value
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Xattr {
+    index: u8,
+    name: Vec<u8>,
+    value: Vec<u8>,
+}
+
+/// Bytes `size` takes rounded up to 4, as entries and values are
+fn padded(size: usize) -> usize {
+    (size + 3) & !3
+}
+
+impl Xattr {
+    /// The full name, prefix included, if the namespace is one we know
+    fn full_name(&self) -> Option<String> {
+        let (_, prefix) = NAMESPACES.iter().find(|(index, _)| *index == self.index)?;
+        Some(format!("{}{}", prefix, String::from_utf8_lossy(&self.name)))
+    }
+
+    fn entry_size(&self) -> usize {
+        padded(ENTRY_HEADER + self.name.len())
+    }
+
+    /// The hash the kernel and e2fsck expect in the entry
+    fn hash(&self) -> u32 {
+        let mut hash = 0u32;
+        for &byte in &self.name {
+            hash = (hash << 5) ^ (hash >> 27) ^ byte as u32;
+        }
+        for chunk in self.value.chunks(4) {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            hash = (hash << 16) ^ (hash >> 16) ^ u32::from_le_bytes(word);
+        }
+        hash
+    }
+}
+
+/// Split a full attribute name into its namespace index and the rest
+fn parse_name(name: &str) -> Result<(u8, &[u8])> {
+    let (index, rest) = match name {
+        "system.posix_acl_access" => (2, ""),
+        "system.posix_acl_default" => (3, ""),
+        _ => {
+            let (index, prefix) = NAMESPACES
+                .iter()
+                .filter(|(index, _)| !matches!(index, 2 | 3))
+                .find(|(_, prefix)| name.starts_with(prefix))
+                .ok_or(Ext2Error::Unsupported("unknown attribute namespace"))?;
+            let rest = &name[prefix.len()..];
+            if rest.is_empty() {
+                return Err(Ext2Error::InvalidArgument("empty attribute name"));
+            }
+            (*index, rest)
+        }
+    };
+    if rest.len() > MAX_NAME_LENGTH {
+        return Err(Ext2Error::NameTooLong);
+    }
+    Ok((index, rest.as_bytes()))
+}
+
+/// The entries starting at `first` in `area`, with their values at offsets
+/// from the start of `area`, or `None` if they don't fit in it
+fn read_entries(area: &[u8], first: usize) -> Option<Vec<Xattr>> {
+    let mut attrs = Vec::new();
+    let mut offset = first;
+    loop {
+        if area.get(offset..offset + 4)? == [0; 4] {
+            return Some(attrs);
+        }
+        let header = area.get(offset..offset + ENTRY_HEADER)?;
+        let name_length = header[0] as usize;
+        let index = header[1];
+        let value_offset = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let value_inode = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let value_size = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        // values in inodes of their own need the ea_inode feature
+        if value_inode != 0 {
+            return None;
+        }
+        let name = area.get(offset + ENTRY_HEADER..offset + ENTRY_HEADER + name_length)?;
+        let value = if value_size == 0 {
+            &[][..]
+        } else {
+            area.get(value_offset..value_offset + value_size)?
+        };
+        let attr = Xattr {
+            index,
+            name: name.to_vec(),
+            value: value.to_vec(),
+        };
+        offset += attr.entry_size();
+        attrs.push(attr);
+    }
+}
+
+/// Whether `attrs` fit in `length` bytes with the first entry at `first`,
+/// leaving room for the word of zeros that ends the entries
+fn entries_fit(length: usize, first: usize, attrs: &[Xattr]) -> bool {
+    let needed: usize = attrs
+        .iter()
+        .map(|attr| attr.entry_size() + padded(attr.value.len()))
+        .sum();
+    first + needed + 4 <= length
+}
+
+/// Write `attrs` into `area` from `first` on, values packed at the end, and
+/// return the hashes of their entries. `entries_fit` must hold.
+fn write_entries(area: &mut [u8], first: usize, attrs: &[Xattr]) -> Vec<u32> {
+    area[first..].fill(0);
+    let mut offset = first;
+    let mut values_start = area.len();
+    let mut hashes = Vec::new();
+    for attr in attrs {
+        let value_offset = if attr.value.is_empty() {
+            0
+        } else {
+            values_start -= padded(attr.value.len());
+            area[values_start..values_start + attr.value.len()].copy_from_slice(&attr.value);
+            values_start
+        };
+        let hash = attr.hash();
+        let entry = &mut area[offset..offset + attr.entry_size()];
+        entry[0] = attr.name.len() as u8;
+        entry[1] = attr.index;
+        entry[2..4].copy_from_slice(&(value_offset as u16).to_le_bytes());
+        entry[8..12].copy_from_slice(&(attr.value.len() as u32).to_le_bytes());
+        entry[12..16].copy_from_slice(&hash.to_le_bytes());
+        entry[ENTRY_HEADER..ENTRY_HEADER + attr.name.len()].copy_from_slice(&attr.name);
+        offset += attr.entry_size();
+        hashes.push(hash);
+    }
+    hashes
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Ext2 {
+    /// The whole on-disk inode, fields beyond our `Inode` struct included
+    fn inode_bytes(&self, inode: usize) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.inode_ptr(inode) as *const u8, self.inode_size()) }
+    }
+
+    fn inode_bytes_mut(&mut self, inode: usize) -> &mut [u8] {
+        unsafe {
+            std::slice::from_raw_parts_mut(self.inode_ptr(inode) as *mut u8, self.inode_size())
+        }
+    }
+
+    /// Offset in the inode of where its attributes start, past its newer
+    /// fields, if it has room for any. `extra_isize` 0 means the inode
+    /// doesn't use the space yet, so it holds none.
+    fn ibody_start(&self, inode: usize) -> Option<usize> {
+        let size = self.inode_size();
+        if size <= GOOD_OLD_INODE_SIZE {
+            return None;
+        }
+        let bytes = self.inode_bytes(inode);
+        let extra_isize = u16::from_le_bytes([bytes[128], bytes[129]]) as usize;
+        let start = GOOD_OLD_INODE_SIZE + extra_isize;
+        if extra_isize == 0 || !extra_isize.is_multiple_of(4) || start + 4 > size {
+            return None;
+        }
+        Some(start)
+    }
+
+    /// The attributes kept in the inode itself and in its attribute block
+    fn xattrs(&self, inode: usize) -> Result<(Vec<Xattr>, Vec<Xattr>)> {
+        let mut in_inode = Vec::new();
+        if let Some(start) = self.ibody_start(inode) {
+            let bytes = self.inode_bytes(inode);
+            if read_u32(bytes, start) == XATTR_MAGIC {
+                in_inode = match read_entries(&bytes[start + 4..], 0) {
+                    Some(attrs) => attrs,
+                    None => {
+                        self.fs_error("xattrs", inode, 0);
+                        return Err(Ext2Error::Corrupt("bad extended attributes in inode"));
+                    }
+                };
+            }
+        }
+
+        let block = self.get_inode(inode).ext_attribute_block;
+        if block == 0 {
+            return Ok((in_inode, Vec::new()));
+        }
+        let in_block = if self.valid_block(block) {
+            let bytes = self.block(block);
+            if read_u32(bytes, 0) == XATTR_MAGIC && read_u32(bytes, 8) == 1 {
+                read_entries(bytes, BLOCK_HEADER)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        match in_block {
+            Some(in_block) => Ok((in_inode, in_block)),
+            None => {
+                self.fs_error("xattrs", inode, block);
+                Err(Ext2Error::Corrupt("bad extended attribute block"))
+            }
+        }
+    }
+
+    /// The full names of the extended attributes of `inode`, those in the
+    /// inode first. Attributes in namespaces we don't know are left out.
+    pub fn list_xattrs(&self, inode: usize) -> Result<Vec<String>> {
+        let (in_inode, in_block) = self.xattrs(inode)?;
+        Ok(in_inode
+            .iter()
+            .chain(&in_block)
+            .filter_map(Xattr::full_name)
+            .collect())
+    }
+
+    /// The value of the extended attribute `name` (e.g. `user.comment`) of
+    /// `inode`
+    pub fn get_xattr(&self, inode: usize, name: &str) -> Result<Vec<u8>> {
+        let (index, name) = parse_name(name)?;
+        let (in_inode, in_block) = self.xattrs(inode)?;
+        in_inode
+            .into_iter()
+            .chain(in_block)
+            .find(|attr| attr.index == index && attr.name == name)
+            .map(|attr| attr.value)
+            .ok_or(Ext2Error::NoAttribute)
+    }
+
+    /// Set the extended attribute `name` of `inode` to `value`, creating it
+    /// if it doesn't exist.
+    ///
+    /// An attribute stays where it is if it still fits there. A new one
+    /// goes in the inode if there is room, and in the attribute block
+    /// otherwise; a block shared with other inodes is copied first.
+    pub fn set_xattr(&mut self, inode: usize, name: &str, value: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        self.check_mutable(inode)?;
+        let (index, name) = parse_name(name)?;
+        if !self.superblock.is_dynamic() {
+            return Err(Ext2Error::Unsupported(
+                "extended attributes need a revision 1 filesystem",
+            ));
+        }
+        let (mut in_inode, mut in_block) = self.xattrs(inode)?;
+        let is_it = |attr: &Xattr| attr.index == index && attr.name == name;
+        let was_in_block = in_block.iter().any(is_it);
+        in_inode.retain(|attr| !is_it(attr));
+        in_block.retain(|attr| !is_it(attr));
+
+        let attr = Xattr {
+            index,
+            name: name.to_vec(),
+            value: value.to_vec(),
+        };
+        let mut placed = false;
+        for try_block in [was_in_block, !was_in_block] {
+            let attrs = if try_block {
+                &mut in_block
+            } else {
+                &mut in_inode
+            };
+            attrs.push(attr.clone());
+            let fits = if try_block {
+                entries_fit(self.block_size, BLOCK_HEADER, attrs)
+            } else {
+                self.ibody_fits(inode, attrs)
+            };
+            if fits {
+                placed = true;
+                break;
+            }
+            attrs.pop();
+        }
+        if !placed {
+            return Err(Ext2Error::NoSpace);
+        }
+
+        self.write_xattrs(inode, &in_inode, in_block)?;
+        let superblock = self.superblock_mut();
+        superblock.features_opt |= CompatFeatures::EXT_ATTR;
+        Ok(())
+    }
+
+    /// Remove the extended attribute `name` from `inode`
+    pub fn remove_xattr(&mut self, inode: usize, name: &str) -> Result<()> {
+        self.check_writable()?;
+        self.check_mutable(inode)?;
+        let (index, name) = parse_name(name)?;
+        let (mut in_inode, mut in_block) = self.xattrs(inode)?;
+        let count = in_inode.len() + in_block.len();
+        in_inode.retain(|attr| !(attr.index == index && attr.name == name));
+        in_block.retain(|attr| !(attr.index == index && attr.name == name));
+        if in_inode.len() + in_block.len() == count {
+            return Err(Ext2Error::NoAttribute);
+        }
+        self.write_xattrs(inode, &in_inode, in_block)
+    }
+
+    /// Whether `attrs` fit in the inode, once it has room for newer fields
+    fn ibody_fits(&self, inode: usize, attrs: &[Xattr]) -> bool {
+        if attrs.is_empty() {
+            return true;
+        }
+        let size = self.inode_size();
+        let start = match self.ibody_start(inode) {
+            Some(start) => start,
+            None => GOOD_OLD_INODE_SIZE + self.default_extra_isize() as usize,
+        };
+        size > start + 4 && entries_fit(size - start - 4, 0, attrs)
+    }
+
+    fn default_extra_isize(&self) -> u16 {
+        match self.superblock.want_extra_isize {
+            0 => DEFAULT_EXTRA_ISIZE,
+            want => want,
+        }
+    }
+
+    /// Store `in_inode` in the inode and `in_block` in its attribute block,
+    /// where `ibody_fits` and `entries_fit` say they fit
+    fn write_xattrs(
+        &mut self,
+        inode: usize,
+        in_inode: &[Xattr],
+        mut in_block: Vec<Xattr>,
+    ) -> Result<()> {
+        // the kernel looks entries in a block up in this order
+        in_block.sort_by(|a, b| {
+            (a.index, a.name.len(), &a.name).cmp(&(b.index, b.name.len(), &b.name))
+        });
+        let (old_inode, old_block) = self.xattrs(inode)?;
+        if old_block != in_block {
+            self.write_xattr_block(inode, &in_block)?;
+        }
+        if old_inode != in_inode {
+            self.write_ibody(inode, in_inode);
+        }
+        let now = self.now();
+        self.inode_mut(inode).ctime = now;
+        Ok(())
+    }
+
+    fn write_ibody(&mut self, inode: usize, attrs: &[Xattr]) {
+        let start = match self.ibody_start(inode) {
+            Some(start) => start,
+            None if attrs.is_empty() => return,
+            None => {
+                // claim the space for the newer fields, which are all zero
+                let extra_isize = self.default_extra_isize();
+                let bytes = self.inode_bytes_mut(inode);
+                bytes[GOOD_OLD_INODE_SIZE..].fill(0);
+                bytes[128..130].copy_from_slice(&extra_isize.to_le_bytes());
+                GOOD_OLD_INODE_SIZE + extra_isize as usize
+            }
+        };
+        let bytes = self.inode_bytes_mut(inode);
+        if attrs.is_empty() {
+            bytes[start..].fill(0);
+            return;
+        }
+        bytes[start..start + 4].copy_from_slice(&XATTR_MAGIC.to_le_bytes());
+        write_entries(&mut bytes[start + 4..], 0, attrs);
+    }
+
+    /// Give `inode` an attribute block holding `attrs`, reusing the one it
+    /// has unless other inodes share it, or drop its block if there are
+    /// none
+    fn write_xattr_block(&mut self, inode: usize, attrs: &[Xattr]) -> Result<()> {
+        let old = self.get_inode(inode).ext_attribute_block;
+        if attrs.is_empty() {
+            self.release_xattr_block(inode);
+            return Ok(());
+        }
+        let block = if old != 0 && read_u32(self.block(old), 4) == 1 {
+            old
+        } else {
+            let block = self.alloc_block(self.inode_group(inode))?;
+            self.release_xattr_block(inode);
+            let sectors = (self.block_size / 512) as u32;
+            let file = self.inode_mut(inode);
+            file.ext_attribute_block = block;
+            file.sectors_count += sectors;
+            block
+        };
+        let bytes = self.block_mut(block);
+        let hashes = write_entries(bytes, BLOCK_HEADER, attrs);
+        // the block's hash combines its entries', or is 0 if one of them is
+        let mut hash = 0u32;
+        for entry_hash in hashes {
+            if entry_hash == 0 {
+                hash = 0;
+                break;
+            }
+            hash = (hash << 16) ^ (hash >> 16) ^ entry_hash;
+        }
+        bytes[..BLOCK_HEADER].fill(0);
+        bytes[0..4].copy_from_slice(&XATTR_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&1u32.to_le_bytes());
+        bytes[8..12].copy_from_slice(&1u32.to_le_bytes());
+        bytes[12..16].copy_from_slice(&hash.to_le_bytes());
+        Ok(())
+    }
+
+    /// Drop the reference `inode` holds to its attribute block, freeing the
+    /// block if it was the last
+    pub(crate) fn release_xattr_block(&mut self, inode: usize) {
+        let block = self.get_inode(inode).ext_attribute_block;
+        if block == 0 {
+            return;
+        }
+        if self.valid_block(block) {
+            let bytes = self.block_mut(block);
+            let refcount = read_u32(bytes, 4);
+            if refcount > 1 {
+                bytes[4..8].copy_from_slice(&(refcount - 1).to_le_bytes());
+            } else {
+                self.free_block(block);
+            }
+        }
+        let sectors = (self.block_size / 512) as u32;
+        let file = self.inode_mut(inode);
+        file.ext_attribute_block = 0;
+        file.sectors_count = file.sectors_count.saturating_sub(sectors);
+    }
+}
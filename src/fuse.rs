@@ -0,0 +1,1022 @@
+// Serving an image over FUSE, so that it can be mounted and used with
+// ordinary tools. This speaks the kernel protocol of <linux/fuse.h> on
+// /dev/fuse directly; requests are handled one at a time.
+// https://www.kernel.org/doc/html/latest/filesystems/fuse.html
+
+use ext2::ext2::{Acl, AclKind, Ext2, Ext2Error, Metadata, TypePerm, ROOT_INODE};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Protocol version we speak; 7.12 is the oldest whose request layouts
+/// match the structs below
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+const FUSE_MIN_MINOR_VERSION: u32 = 12;
+/// The kernel's node ID for the root, whatever its inode number
+const FUSE_ROOT_ID: u64 = 1;
+
+/// Largest write the kernel may send us in one request
+const MAX_WRITE: usize = 128 * 1024;
+/// Room for a request header and the largest write
+const BUFFER_SIZE: usize = MAX_WRITE + 4096;
+/// How long the kernel may cache names and attributes. Nothing else
+/// changes the image while it is mounted, so this only bounds staleness
+/// after our own replies.
+const TTL_SECONDS: u64 = 1;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_SETATTR: u32 = 4;
+const FUSE_READLINK: u32 = 5;
+const FUSE_SYMLINK: u32 = 6;
+const FUSE_MKNOD: u32 = 8;
+const FUSE_MKDIR: u32 = 9;
+const FUSE_UNLINK: u32 = 10;
+const FUSE_RMDIR: u32 = 11;
+const FUSE_RENAME: u32 = 12;
+const FUSE_LINK: u32 = 13;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_WRITE: u32 = 16;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FSYNC: u32 = 20;
+const FUSE_SETXATTR: u32 = 21;
+const FUSE_GETXATTR: u32 = 22;
+const FUSE_LISTXATTR: u32 = 23;
+const FUSE_REMOVEXATTR: u32 = 24;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_FSYNCDIR: u32 = 30;
+const FUSE_CREATE: u32 = 35;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+/// INIT flag: writes may be larger than a page
+const FUSE_BIG_WRITES: u32 = 1 << 5;
+/// INIT flag: the kernel leaves applying the umask to us
+const FUSE_DONT_MASK: u32 = 1 << 6;
+/// INIT flag: the kernel checks ACLs, which it gets with GETXATTR
+const FUSE_POSIX_ACL: u32 = 1 << 20;
+
+// which fields of a SETATTR request are set
+const FATTR_MODE: u32 = 1 << 0;
+const FATTR_UID: u32 = 1 << 1;
+const FATTR_GID: u32 = 1 << 2;
+const FATTR_SIZE: u32 = 1 << 3;
+const FATTR_ATIME: u32 = 1 << 4;
+const FATTR_MTIME: u32 = 1 << 5;
+const FATTR_ATIME_NOW: u32 = 1 << 7;
+const FATTR_MTIME_NOW: u32 = 1 << 8;
+
+// SETXATTR flags, as for setxattr(2)
+const XATTR_CREATE: u32 = 1;
+const XATTR_REPLACE: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct InHeader {
+    len: u32,
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    total_extlen: u16,
+    padding: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct OutHeader {
+    len: u32,
+    error: i32,
+    unique: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct InitIn {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct InitOut {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+    max_background: u16,
+    congestion_threshold: u16,
+    max_write: u32,
+    time_gran: u32,
+    max_pages: u16,
+    map_alignment: u16,
+    flags2: u32,
+    unused: [u32; 7],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Attr {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    atimensec: u32,
+    mtimensec: u32,
+    ctimensec: u32,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct EntryOut {
+    nodeid: u64,
+    generation: u64,
+    entry_valid: u64,
+    attr_valid: u64,
+    entry_valid_nsec: u32,
+    attr_valid_nsec: u32,
+    attr: Attr,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct AttrOut {
+    attr_valid: u64,
+    attr_valid_nsec: u32,
+    dummy: u32,
+    attr: Attr,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct SetattrIn {
+    valid: u32,
+    padding: u32,
+    fh: u64,
+    size: u64,
+    lock_owner: u64,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    atimensec: u32,
+    mtimensec: u32,
+    ctimensec: u32,
+    mode: u32,
+    unused4: u32,
+    uid: u32,
+    gid: u32,
+    unused5: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct MknodIn {
+    mode: u32,
+    rdev: u32,
+    umask: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct MkdirIn {
+    mode: u32,
+    umask: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RenameIn {
+    newdir: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct LinkIn {
+    oldnodeid: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct CreateIn {
+    flags: u32,
+    mode: u32,
+    umask: u32,
+    open_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct OpenOut {
+    fh: u64,
+    open_flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ReadIn {
+    fh: u64,
+    offset: u64,
+    size: u32,
+    read_flags: u32,
+    lock_owner: u64,
+    flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct WriteIn {
+    fh: u64,
+    offset: u64,
+    size: u32,
+    write_flags: u32,
+    lock_owner: u64,
+    flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct WriteOut {
+    size: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct StatfsOut {
+    blocks: u64,
+    bfree: u64,
+    bavail: u64,
+    files: u64,
+    ffree: u64,
+    bsize: u32,
+    namelen: u32,
+    frsize: u32,
+    padding: u32,
+    spare: [u32; 6],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct SetxattrIn {
+    size: u32,
+    flags: u32,
+}
+
+/// Also the request of LISTXATTR
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct GetxattrIn {
+    size: u32,
+    padding: u32,
+}
+
+/// The reply to GETXATTR and LISTXATTR when asked only for the size
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct GetxattrOut {
+    size: u32,
+    padding: u32,
+}
+
+/// Fixed part of each entry in a READDIR reply, before the name
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Dirent {
+    ino: u64,
+    off: u64,
+    namelen: u32,
+    kind: u32,
+}
+
+/// Set from the signal handler to ask the main loop to unmount
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+/// The bytes of a `#[repr(C)]` struct, for sending to the kernel
+fn bytes_of<T: Copy>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// A `#[repr(C)]` struct from the start of `bytes`, which the kernel
+/// doesn't align
+fn read_struct<T: Copy>(bytes: &[u8]) -> Result<T, Errno> {
+    if bytes.len() < mem::size_of::<T>() {
+        return Err(Errno(libc::EINVAL));
+    }
+    Ok(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// A failed request, as the errno to reply with
+struct Errno(i32);
+
+impl From<Ext2Error> for Errno {
+    fn from(err: Ext2Error) -> Errno {
+        Errno(err.errno())
+    }
+}
+
+/// The payload of a successful reply
+type Reply = Result<Vec<u8>, Errno>;
+
+/// The first NUL-terminated name in `bytes`, and what follows it
+fn split_name(bytes: &[u8]) -> Result<(&str, &[u8]), Errno> {
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .ok_or(Errno(libc::EINVAL))?;
+    let name = std::str::from_utf8(&bytes[..end]).map_err(|_| Errno(libc::EINVAL))?;
+    Ok((name, &bytes[end + 1..]))
+}
+
+/// Reply to GETXATTR or LISTXATTR with `data`, or with its size if the
+/// caller asked for that by passing a size of 0
+fn xattr_reply(data: Vec<u8>, size: u32) -> Reply {
+    if size == 0 {
+        let out = GetxattrOut {
+            size: data.len() as u32,
+            padding: 0,
+        };
+        return Ok(bytes_of(&out).to_vec());
+    }
+    if data.len() > size as usize {
+        return Err(Errno(libc::ERANGE));
+    }
+    Ok(data)
+}
+
+/// Convert a time from the kernel to the unsigned 32-bit seconds an inode
+/// holds
+fn inode_time(seconds: u64) -> u32 {
+    (seconds as i64).clamp(0, u32::MAX as i64) as u32
+}
+
+/// An image mapped into memory, with changes going straight to the file
+struct Mapping {
+    bytes: &'static mut [u8],
+}
+
+impl Mapping {
+    fn new(path: &str, writable: bool) -> io::Result<Mapping> {
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty image"));
+        }
+        let protection = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let address = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                protection,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let bytes = unsafe { std::slice::from_raw_parts_mut(address as *mut u8, len) };
+        Ok(Mapping { bytes })
+    }
+
+    /// Write changes made so far back to the image file
+    fn sync(&self) -> io::Result<()> {
+        let result = unsafe {
+            libc::msync(
+                self.bytes.as_ptr() as *mut libc::c_void,
+                self.bytes.len(),
+                libc::MS_SYNC,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Mount a FUSE file system on `mountpoint` and return the connection to
+/// the kernel. Root can call mount(2) itself; anyone else needs the
+/// setuid fusermount helper to do it for them.
+fn mount(image: &str, mountpoint: &str, writable: bool) -> io::Result<File> {
+    let device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    let source = CString::new(image)?;
+    let target = CString::new(mountpoint)?;
+    let options = CString::new(format!(
+        "fd={},rootmode={:o},user_id={},group_id={},default_permissions",
+        device.as_raw_fd(),
+        TypePerm::DIRECTORY.bits(),
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() },
+    ))?;
+    let mut flags = libc::MS_NOSUID | libc::MS_NODEV;
+    if !writable {
+        flags |= libc::MS_RDONLY;
+    }
+    let result = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            c"fuse.ext2".as_ptr(),
+            flags,
+            options.as_ptr() as *const libc::c_void,
+        )
+    };
+    if result == 0 {
+        return Ok(device);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EPERM) {
+        return Err(err);
+    }
+    drop(device);
+    fusermount(image, mountpoint, writable)
+}
+
+/// Have fusermount open /dev/fuse and mount it, and pass us the open file
+/// over a socket
+fn fusermount(image: &str, mountpoint: &str, writable: bool) -> io::Result<File> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (theirs, ours) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    let options = format!(
+        "default_permissions,fsname={},subtype=ext2,{}",
+        image,
+        if writable { "rw" } else { "ro" }
+    );
+    let mut status = Err(io::Error::from(io::ErrorKind::NotFound));
+    for helper in ["fusermount3", "fusermount"] {
+        status = Command::new(helper)
+            .args(["-o", &options, "--", mountpoint])
+            .env("_FUSERMOUNT_COMMFD", theirs.as_raw_fd().to_string())
+            .status();
+        if !matches!(&status, Err(err) if err.kind() == io::ErrorKind::NotFound) {
+            break;
+        }
+    }
+    if !status?.success() {
+        return Err(io::Error::other("fusermount failed"));
+    }
+    drop(theirs);
+
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: byte.len(),
+    };
+    // room for one cmsghdr carrying one file descriptor
+    let mut control = [0u64; 4];
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = mem::size_of_val(&control) as _;
+    if unsafe { libc::recvmsg(ours.as_raw_fd(), &mut message, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let header = unsafe { libc::CMSG_FIRSTHDR(&message) };
+    if header.is_null()
+        || unsafe {
+            (*header).cmsg_level != libc::SOL_SOCKET || (*header).cmsg_type != libc::SCM_RIGHTS
+        }
+    {
+        return Err(io::Error::other("fusermount did not pass back /dev/fuse"));
+    }
+    let fd = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(header) as *const libc::c_int) };
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Detach the mount, which ends the session once nothing is using it
+fn unmount(mountpoint: &str) -> io::Result<()> {
+    let target = CString::new(mountpoint)?;
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } == 0 {
+        return Ok(());
+    }
+    for helper in ["fusermount3", "fusermount"] {
+        match Command::new(helper)
+            .args(["-u", "-z", "--", mountpoint])
+            .status()
+        {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(io::Error::other("could not unmount"))
+}
+
+/// Unmount instead of dying on ^C or `kill`, so the image is left synced.
+/// Without SA_RESTART the blocking read of the next request fails with
+/// EINTR, which is when the main loop notices.
+fn catch_signals() {
+    let mut action: libc::sigaction = unsafe { mem::zeroed() };
+    action.sa_sigaction = request_stop as *const () as libc::sighandler_t;
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) };
+    }
+}
+
+/// Mount the image at `image` on `mountpoint` and serve requests until it
+/// is unmounted. Changes are only allowed if `writable`, and are written
+/// to the image file as they are made.
+pub fn serve(image: &str, mountpoint: &str, writable: bool) -> io::Result<()> {
+    let mapping = Mapping::new(image, writable)?;
+    let start_addr = mapping.bytes.as_ptr() as usize;
+    let mut ext2 = if writable {
+        Ext2::new_mut(&mut mapping.bytes[..], start_addr)?
+    } else {
+        Ext2::new(&mapping.bytes[..], start_addr)?
+    };
+    if writable && ext2.read_only() {
+        eprintln!(
+            "fuse: {}: features this can't write are in use, mounting read-only",
+            image
+        );
+    }
+    let writable = writable && !ext2.read_only();
+    let device = mount(image, mountpoint, writable)?;
+    if writable {
+        for warning in ext2.mount(mountpoint)? {
+            eprintln!("fuse: {}: warning: {}", image, warning);
+        }
+    }
+    catch_signals();
+    let mut session = Session {
+        ext2,
+        device,
+        mapping: &mapping,
+    };
+    let result = session.run(mountpoint);
+    // not if corruption was found and the errors policy made it
+    // read-only: it stays marked as not cleanly unmounted
+    if writable && !session.ext2.read_only() {
+        session.ext2.unmount()?;
+    }
+    session.sync()?;
+    result
+}
+
+struct Session<'a> {
+    ext2: Ext2,
+    device: File,
+    mapping: &'a Mapping,
+}
+
+impl Session<'_> {
+    /// Update the backup superblocks and descriptor tables, then flush the
+    /// image to its file
+    fn sync(&mut self) -> io::Result<()> {
+        if !self.ext2.read_only() {
+            self.ext2.sync()?;
+        }
+        self.mapping.sync()
+    }
+
+    fn run(&mut self, mountpoint: &str) -> io::Result<()> {
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        loop {
+            let length = match self.device.read(&mut buffer) {
+                Ok(length) => length,
+                Err(err) => match err.raw_os_error() {
+                    // the request was interrupted before we read it
+                    Some(libc::ENOENT) | Some(libc::EAGAIN) => continue,
+                    Some(libc::EINTR) => {
+                        if STOP.swap(false, Ordering::SeqCst) {
+                            unmount(mountpoint)?;
+                        }
+                        continue;
+                    }
+                    // unmounted
+                    Some(libc::ENODEV) => return Ok(()),
+                    _ => return Err(err),
+                },
+            };
+            let request = &buffer[..length];
+            let Ok(header) = read_struct::<InHeader>(request) else {
+                continue;
+            };
+            let body = &request[mem::size_of::<InHeader>()..];
+            match header.opcode {
+                // these get no reply
+                FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => continue,
+                _ => {}
+            }
+            let reply = self.dispatch(&header, body);
+            self.reply(header.unique, reply);
+        }
+    }
+
+    fn reply(&mut self, unique: u64, reply: Reply) {
+        let (error, payload) = match reply {
+            Ok(payload) => (0, payload),
+            Err(Errno(errno)) => (-errno, Vec::new()),
+        };
+        let header = OutHeader {
+            len: (mem::size_of::<OutHeader>() + payload.len()) as u32,
+            error,
+            unique,
+        };
+        let mut message = bytes_of(&header).to_vec();
+        message.extend_from_slice(&payload);
+        // fails with ENOENT if the request was interrupted meanwhile, which
+        // leaves nothing to do
+        let _ = self.device.write(&message);
+    }
+
+    fn dispatch(&mut self, header: &InHeader, body: &[u8]) -> Reply {
+        let inode = inode_of(header.nodeid);
+        match header.opcode {
+            FUSE_INIT => self.init(body),
+            FUSE_DESTROY => Ok(Vec::new()),
+            FUSE_LOOKUP => {
+                let (name, _) = split_name(body)?;
+                self.entry(self.ext2.lookup(inode, name)?)
+            }
+            FUSE_GETATTR => self.attr(inode),
+            FUSE_SETATTR => self.setattr(inode, read_struct(body)?),
+            FUSE_READLINK => Ok(self.ext2.readlink(inode)?),
+            FUSE_SYMLINK => {
+                let (name, rest) = split_name(body)?;
+                let (target, _) = split_name(rest)?;
+                let link = self.ext2.symlink(inode, name, target)?;
+                self.new_entry(header, inode, link)
+            }
+            FUSE_MKNOD => {
+                let request: MknodIn = read_struct(body)?;
+                let (name, _) = split_name(&body[mem::size_of::<MknodIn>()..])?;
+                let mode = self.create_mode(inode, request.mode, request.umask)?;
+                let kind = TypePerm::from_bits_truncate(mode);
+                let node = if kind.file_type() == TypePerm::FILE {
+                    self.ext2.create(inode, name, mode)?
+                } else {
+                    let (major, minor) = decode_rdev(request.rdev);
+                    self.ext2.mknod(inode, name, kind, major, minor)?
+                };
+                self.new_entry(header, inode, node)
+            }
+            FUSE_MKDIR => {
+                let request: MkdirIn = read_struct(body)?;
+                let (name, _) = split_name(&body[mem::size_of::<MkdirIn>()..])?;
+                let mode = self.create_mode(inode, request.mode, request.umask)?;
+                let dir = self.ext2.mkdir(inode, name, mode)?;
+                self.new_entry(header, inode, dir)
+            }
+            FUSE_UNLINK => {
+                let (name, _) = split_name(body)?;
+                self.ext2.unlink(inode, name)?;
+                Ok(Vec::new())
+            }
+            FUSE_RMDIR => {
+                let (name, _) = split_name(body)?;
+                self.ext2.rmdir(inode, name)?;
+                Ok(Vec::new())
+            }
+            FUSE_RENAME => {
+                let request: RenameIn = read_struct(body)?;
+                let (old_name, rest) = split_name(&body[mem::size_of::<RenameIn>()..])?;
+                let (new_name, _) = split_name(rest)?;
+                self.ext2
+                    .rename(inode, old_name, inode_of(request.newdir), new_name)?;
+                Ok(Vec::new())
+            }
+            FUSE_LINK => {
+                let request: LinkIn = read_struct(body)?;
+                let (name, _) = split_name(&body[mem::size_of::<LinkIn>()..])?;
+                let target = inode_of(request.oldnodeid);
+                self.ext2.link(target, inode, name)?;
+                self.entry(target)
+            }
+            FUSE_OPEN | FUSE_OPENDIR => Ok(bytes_of(&OpenOut::default()).to_vec()),
+            FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH => Ok(Vec::new()),
+            FUSE_FSYNC | FUSE_FSYNCDIR => match self.sync() {
+                Ok(()) => Ok(Vec::new()),
+                Err(err) => Err(Errno(err.raw_os_error().unwrap_or(libc::EIO))),
+            },
+            FUSE_READ => {
+                let request: ReadIn = read_struct(body)?;
+                let mut data = vec![0u8; request.size as usize];
+                let length = self.ext2.read(inode, request.offset, &mut data)?;
+                data.truncate(length);
+                Ok(data)
+            }
+            FUSE_WRITE => {
+                let request: WriteIn = read_struct(body)?;
+                let data = &body[mem::size_of::<WriteIn>()..];
+                let data = &data[..(request.size as usize).min(data.len())];
+                let written = self.ext2.write(inode, request.offset, data)?;
+                let out = WriteOut {
+                    size: written as u32,
+                    padding: 0,
+                };
+                Ok(bytes_of(&out).to_vec())
+            }
+            FUSE_READDIR => self.readdir(inode, read_struct(body)?),
+            FUSE_CREATE => {
+                let request: CreateIn = read_struct(body)?;
+                let (name, _) = split_name(&body[mem::size_of::<CreateIn>()..])?;
+                let mode = self.create_mode(inode, request.mode, request.umask)?;
+                let file = self.ext2.create(inode, name, mode)?;
+                let mut reply = self.new_entry(header, inode, file)?;
+                reply.extend_from_slice(bytes_of(&OpenOut::default()));
+                Ok(reply)
+            }
+            FUSE_STATFS => Ok(self.statfs()),
+            FUSE_SETXATTR => {
+                let request: SetxattrIn = read_struct(body)?;
+                let (name, value) = split_name(&body[mem::size_of::<SetxattrIn>()..])?;
+                let value = value
+                    .get(..request.size as usize)
+                    .ok_or(Errno(libc::EINVAL))?;
+                if request.flags & (XATTR_CREATE | XATTR_REPLACE) != 0 {
+                    let exists = match self.ext2.get_xattr(inode, name) {
+                        Ok(_) => true,
+                        Err(Ext2Error::NoAttribute) => false,
+                        Err(err) => return Err(err.into()),
+                    };
+                    if exists && request.flags & XATTR_CREATE != 0 {
+                        return Err(Errno(libc::EEXIST));
+                    }
+                    if !exists && request.flags & XATTR_REPLACE != 0 {
+                        return Err(Errno(libc::ENODATA));
+                    }
+                }
+                match AclKind::from_xattr_name(name) {
+                    Some(kind) => self
+                        .ext2
+                        .set_acl(inode, kind, Some(&Acl::from_xattr(value)?))?,
+                    None => self.ext2.set_xattr(inode, name, value)?,
+                }
+                Ok(Vec::new())
+            }
+            FUSE_GETXATTR => {
+                let request: GetxattrIn = read_struct(body)?;
+                let (name, _) = split_name(&body[mem::size_of::<GetxattrIn>()..])?;
+                // ACLs are stored in a format of their own, not the one
+                // the kernel and programs use
+                let value = match AclKind::from_xattr_name(name) {
+                    Some(kind) => match self.ext2.get_acl(inode, kind)? {
+                        Some(acl) => acl.to_xattr(),
+                        None => return Err(Errno(libc::ENODATA)),
+                    },
+                    None => self.ext2.get_xattr(inode, name)?,
+                };
+                xattr_reply(value, request.size)
+            }
+            FUSE_LISTXATTR => {
+                let request: GetxattrIn = read_struct(body)?;
+                let mut names = Vec::new();
+                for name in self.ext2.list_xattrs(inode)? {
+                    names.extend_from_slice(name.as_bytes());
+                    names.push(0);
+                }
+                xattr_reply(names, request.size)
+            }
+            FUSE_REMOVEXATTR => {
+                let (name, _) = split_name(body)?;
+                self.ext2.remove_xattr(inode, name)?;
+                Ok(Vec::new())
+            }
+            _ => Err(Errno(libc::ENOSYS)),
+        }
+    }
+
+    fn init(&mut self, body: &[u8]) -> Reply {
+        let request: InitIn = read_struct(body)?;
+        if request.major != FUSE_KERNEL_VERSION || request.minor < FUSE_MIN_MINOR_VERSION {
+            return Err(Errno(libc::EPROTO));
+        }
+        let out = InitOut {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION.min(request.minor),
+            max_readahead: request.max_readahead,
+            flags: request.flags & (FUSE_BIG_WRITES | FUSE_DONT_MASK | FUSE_POSIX_ACL),
+            max_write: MAX_WRITE as u32,
+            time_gran: 1_000_000_000,
+            ..Default::default()
+        };
+        Ok(bytes_of(&out).to_vec())
+    }
+
+    /// The mode to create an inode in `parent` with: the umask applies
+    /// unless `parent` has a default ACL, which takes its place
+    fn create_mode(&self, parent: usize, mode: u32, umask: u32) -> Result<u16, Errno> {
+        match self.ext2.get_acl(parent, AclKind::Default)? {
+            Some(_) => Ok(mode as u16),
+            None => Ok((mode & !umask) as u16),
+        }
+    }
+
+    fn fuse_attr(&self, inode: usize) -> Attr {
+        let metadata: Metadata = self.ext2.metadata(inode);
+        Attr {
+            ino: inode as u64,
+            size: metadata.size,
+            blocks: metadata.sectors as u64,
+            atime: metadata.atime as u64,
+            mtime: metadata.mtime as u64,
+            ctime: metadata.ctime as u64,
+            mode: metadata.type_perm.bits() as u32,
+            nlink: metadata.hard_links as u32,
+            uid: metadata.uid,
+            gid: metadata.gid,
+            rdev: metadata
+                .rdev
+                .map_or(0, |(major, minor)| encode_rdev(major, minor)),
+            blksize: self.ext2.block_size as u32,
+            ..Default::default()
+        }
+    }
+
+    fn attr(&self, inode: usize) -> Reply {
+        let out = AttrOut {
+            attr_valid: TTL_SECONDS,
+            attr: self.fuse_attr(inode),
+            ..Default::default()
+        };
+        Ok(bytes_of(&out).to_vec())
+    }
+
+    fn entry(&self, inode: usize) -> Reply {
+        let out = EntryOut {
+            nodeid: nodeid_of(inode),
+            generation: self.ext2.get_inode(inode).gen_number as u64,
+            entry_valid: TTL_SECONDS,
+            attr_valid: TTL_SECONDS,
+            attr: self.fuse_attr(inode),
+            ..Default::default()
+        };
+        Ok(bytes_of(&out).to_vec())
+    }
+
+    /// Reply to a request that created `inode` in `parent`, which should
+    /// belong to whoever made the request. In a setgid directory it gets
+    /// the directory's group instead, and a new directory is setgid too.
+    fn new_entry(&mut self, header: &InHeader, parent: usize, inode: usize) -> Reply {
+        let dir = self.ext2.metadata(parent);
+        let mut gid = header.gid;
+        if dir.type_perm.contains(TypePerm::SET_GID) {
+            gid = dir.gid;
+            let type_perm = self.ext2.metadata(inode).type_perm;
+            if type_perm.is_dir() {
+                self.ext2
+                    .chmod(inode, (type_perm | TypePerm::SET_GID).bits())?;
+            }
+        }
+        self.ext2.chown(inode, Some(header.uid), Some(gid))?;
+        self.entry(inode)
+    }
+
+    fn setattr(&mut self, inode: usize, request: SetattrIn) -> Reply {
+        let valid = request.valid;
+        if valid & FATTR_MODE != 0 {
+            self.ext2.chmod(inode, request.mode as u16)?;
+        }
+        if valid & (FATTR_UID | FATTR_GID) != 0 {
+            let uid = (valid & FATTR_UID != 0).then_some(request.uid);
+            let gid = (valid & FATTR_GID != 0).then_some(request.gid);
+            self.ext2.chown(inode, uid, gid)?;
+        }
+        if valid & FATTR_SIZE != 0 {
+            self.ext2.truncate(inode, request.size)?;
+        }
+        // times given explicitly can't be set on append-only inodes, but
+        // they can be set to now, as on Linux
+        let given = |set, now| valid & set != 0 && valid & now == 0;
+        if given(FATTR_ATIME, FATTR_ATIME_NOW) || given(FATTR_MTIME, FATTR_MTIME_NOW) {
+            let metadata = self.ext2.metadata(inode);
+            let now = self.ext2.now();
+            let atime = match valid {
+                _ if valid & FATTR_ATIME_NOW != 0 => now,
+                _ if valid & FATTR_ATIME != 0 => inode_time(request.atime),
+                _ => metadata.atime,
+            };
+            let mtime = match valid {
+                _ if valid & FATTR_MTIME_NOW != 0 => now,
+                _ if valid & FATTR_MTIME != 0 => inode_time(request.mtime),
+                _ => metadata.mtime,
+            };
+            self.ext2.utimes(inode, atime, mtime)?;
+        } else if valid & (FATTR_ATIME | FATTR_MTIME) != 0 {
+            self.ext2
+                .touch(inode, valid & FATTR_ATIME != 0, valid & FATTR_MTIME != 0)?;
+        }
+        self.attr(inode)
+    }
+
+    /// Entries of `dir` from the offset in the request on, as many as fit.
+    /// An entry's offset is its index plus one, which is where to carry on
+    /// from after it.
+    fn readdir(&self, dir: usize, request: ReadIn) -> Reply {
+        let mut reply = Vec::new();
+        let entries = self.ext2.entries(dir)?;
+        for (index, (inode, name)) in entries.iter().enumerate().skip(request.offset as usize) {
+            let dirent = Dirent {
+                ino: *inode as u64,
+                off: index as u64 + 1,
+                namelen: name.len() as u32,
+                kind: (self.ext2.get_inode(*inode).type_perm.bits() >> 12) as u32,
+            };
+            let size = (mem::size_of::<Dirent>() + name.len()).next_multiple_of(8);
+            if reply.len() + size > request.size as usize {
+                break;
+            }
+            reply.extend_from_slice(bytes_of(&dirent));
+            reply.extend_from_slice(name);
+            reply.resize(reply.len().next_multiple_of(8), 0);
+        }
+        Ok(reply)
+    }
+
+    fn statfs(&self) -> Vec<u8> {
+        let superblock = self.ext2.superblock;
+        let out = StatfsOut {
+            blocks: superblock.blocks_count as u64,
+            bfree: superblock.free_blocks_count as u64,
+            bavail: superblock
+                .free_blocks_count
+                .saturating_sub(superblock.r_blocks_count) as u64,
+            files: superblock.inodes_count as u64,
+            ffree: superblock.free_inodes_count as u64,
+            bsize: self.ext2.block_size as u32,
+            namelen: 255,
+            frsize: self.ext2.block_size as u32,
+            ..Default::default()
+        };
+        bytes_of(&out).to_vec()
+    }
+}
+
+/// The inode a FUSE node ID refers to. Node IDs are inode numbers, except
+/// that the kernel calls the root 1.
+fn inode_of(nodeid: u64) -> usize {
+    if nodeid == FUSE_ROOT_ID {
+        ROOT_INODE
+    } else {
+        nodeid as usize
+    }
+}
+
+fn nodeid_of(inode: usize) -> u64 {
+    if inode == ROOT_INODE {
+        FUSE_ROOT_ID
+    } else {
+        inode as u64
+    }
+}
+
+/// Split a device number in the kernel's 32-bit encoding, 12 bits of
+/// major and 20 of minor
+fn decode_rdev(rdev: u32) -> (u32, u32) {
+    (
+        (rdev >> 8) & 0xfff,
+        (rdev & 0xff) | ((rdev >> 12) & 0xfff00),
+    )
+}
+
+fn encode_rdev(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+}
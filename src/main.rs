@@ -1,12 +1,28 @@
-use ext2::ext2::structs::TypePerm;
-use ext2::ext2::Ext2;
+use ext2::ext2::structs::{InodeFlags, TypePerm, INODE_FLAG_LETTERS};
+use ext2::ext2::{Ext2, Ext2Error};
 use rustyline::{DefaultEditor, Result};
 use std::f32::consts::E;
 
+mod commands;
+mod fuse;
+
 fn main() -> Result<()> {
-    let disk = include_bytes!("../myfs.ext2");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(commands::run(&args));
+    }
+
+    // copy the image out of the (read-only) binary so that it can be modified
+    let disk: &'static mut [u8] =
+        Box::leak(include_bytes!("../myfs.ext2").to_vec().into_boxed_slice());
     let start_addr: usize = disk.as_ptr() as usize;
-    let ext2 = Ext2::new(&disk[..], start_addr);
+    let mut ext2 = match Ext2::new_mut(&mut disk[..], start_addr) {
+        Ok(ext2) => ext2,
+        Err(err) => {
+            println!("unable to open the built-in image: {}", err);
+            return Ok(());
+        }
+    };
 
     let mut current_working_inode: usize = 2;
 
@@ -23,7 +39,28 @@ fn main() -> Result<()> {
 
         let buffer = rl.readline(":> ");
         if let Ok(line) = buffer {
-            if line.starts_with("ls") {
+            if line.starts_with("lsattr") {
+                // `lsattr [target]`
+                // print the flags of target, or of each entry of the cwd
+                let elts: Vec<&str> = line.split(' ').collect();
+                if elts.len() > 2 {
+                    println!("usage: lsattr [target]");
+                } else if elts.len() == 2 {
+                    match ext2.resolve(current_working_inode, elts[1]) {
+                        Ok(inode) => {
+                            println!("{} {}", ext2.get_inode(inode).flags.letters(), elts[1])
+                        }
+                        Err(err) => println!("lsattr: {}", err),
+                    }
+                } else {
+                    for dir in &dirs {
+                        let name = dir.1.to_string();
+                        if name != "." && name != ".." {
+                            println!("{} {}", ext2.get_inode(dir.0).flags.letters(), name);
+                        }
+                    }
+                }
+            } else if line.starts_with("ls") {
                 // `ls` prints our cwd's children
                 // TODO: support arguments to ls (print that directory's children instead)
                 let elts: Vec<&str> = line.split(' ').collect();
@@ -304,7 +341,95 @@ fn main() -> Result<()> {
             } else if line.starts_with("rm") {
                 // `rm target`
                 // unlink a file or empty directory
-                println!("rm not yet implemented");
+                let elts: Vec<&str> = line.split(' ').collect();
+                if elts.len() != 2 {
+                    println!("usage: rm target");
+                } else if let Err(err) = rm(&mut ext2, current_working_inode, elts[1]) {
+                    println!("rm: {}", err);
+                }
+            } else if line.starts_with("mv") {
+                // `mv source target`
+                // rename source to target, replacing target if it exists;
+                // if target is a directory, move source into it instead
+                let elts: Vec<&str> = line.split(' ').collect();
+                if elts.len() != 3 {
+                    println!("usage: mv source target");
+                } else if let Err(err) = mv(&mut ext2, current_working_inode, elts[1], elts[2]) {
+                    println!("mv: {}", err);
+                }
+            } else if line.starts_with("chmod") {
+                // `chmod mode target`
+                // set the permission bits of target to the octal mode
+                let elts: Vec<&str> = line.split(' ').collect();
+                if elts.len() != 3 {
+                    println!("usage: chmod mode target");
+                } else {
+                    match u16::from_str_radix(elts[1], 8) {
+                        Ok(mode) if mode <= 0o7777 => {
+                            let result = ext2
+                                .resolve(current_working_inode, elts[2])
+                                .and_then(|inode| ext2.chmod(inode, mode));
+                            if let Err(err) = result {
+                                println!("chmod: {}", err);
+                            }
+                        }
+                        _ => println!("chmod: invalid mode {}", elts[1]),
+                    }
+                }
+            } else if line.starts_with("chattr") {
+                // `chattr [+-=]flags target`
+                // add, remove or set the flags of target, by lsattr's letters
+                let elts: Vec<&str> = line.split(' ').collect();
+                if elts.len() != 3 {
+                    println!("usage: chattr [+-=]flags target");
+                } else if let Err(err) = chattr(&mut ext2, current_working_inode, elts[1], elts[2])
+                {
+                    println!("chattr: {}", err);
+                }
+            } else if line.starts_with("chown") {
+                // `chown uid[:gid] target` or `chown :gid target`
+                // change the numeric owner and/or group of target
+                let elts: Vec<&str> = line.split(' ').collect();
+                if elts.len() != 3 {
+                    println!("usage: chown uid[:gid] target");
+                } else {
+                    let (uid, gid) = match elts[1].split_once(':') {
+                        Some((uid, gid)) => (uid, gid),
+                        None => (elts[1], ""),
+                    };
+                    let parse = |id: &str| -> std::result::Result<Option<u32>, ()> {
+                        if id.is_empty() {
+                            Ok(None)
+                        } else {
+                            id.parse().map(Some).map_err(|_| ())
+                        }
+                    };
+                    match (parse(uid), parse(gid)) {
+                        (Ok(uid), Ok(gid)) => {
+                            let result = ext2
+                                .resolve(current_working_inode, elts[2])
+                                .and_then(|inode| ext2.chown(inode, uid, gid));
+                            if let Err(err) = result {
+                                println!("chown: {}", err);
+                            }
+                        }
+                        _ => println!("chown: invalid owner {}", elts[1]),
+                    }
+                }
+            } else if line.starts_with("touch") {
+                // `touch target`
+                // set the access and modification times of target to now
+                let elts: Vec<&str> = line.split(' ').collect();
+                if elts.len() != 2 {
+                    println!("usage: touch target");
+                } else {
+                    let result = ext2
+                        .resolve(current_working_inode, elts[1])
+                        .and_then(|inode| ext2.touch(inode, true, true));
+                    if let Err(err) = result {
+                        println!("touch: {}", err);
+                    }
+                }
             } else if line.starts_with("mount") {
                 // `mount host_filename mountpoint`
                 // mount an ext2 filesystem over an existing empty directory
@@ -325,3 +450,62 @@ fn main() -> Result<()> {
     }
     Ok(())
 }
+
+fn mv(ext2: &mut Ext2, cwd: usize, source: &str, target: &str) -> std::result::Result<(), Ext2Error> {
+    let (old_parent, old_name) = ext2.resolve_parent(cwd, source)?;
+    let inode = ext2.lookup(old_parent, old_name)?;
+    match ext2.resolve(cwd, target) {
+        Ok(dir) if dir != inode && ext2.get_inode(dir).type_perm.is_dir() => {
+            ext2.rename(old_parent, old_name, dir, old_name)
+        }
+        _ => {
+            let (new_parent, new_name) = ext2.resolve_parent(cwd, target)?;
+            ext2.rename(old_parent, old_name, new_parent, new_name)
+        }
+    }
+}
+
+fn rm(ext2: &mut Ext2, cwd: usize, target: &str) -> std::result::Result<(), Ext2Error> {
+    let (parent, name) = ext2.resolve_parent(cwd, target)?;
+    let inode = ext2.lookup(parent, name)?;
+    if ext2.get_inode(inode).type_perm.is_dir() {
+        ext2.rmdir(parent, name)
+    } else {
+        ext2.unlink(parent, name)
+    }
+}
+
+fn chattr(
+    ext2: &mut Ext2,
+    cwd: usize,
+    spec: &str,
+    target: &str,
+) -> std::result::Result<(), Ext2Error> {
+    let inode = ext2.resolve(cwd, target)?;
+    let mut chars = spec.chars();
+    let op = chars.next();
+    let letters = chars.as_str();
+    let mut changed = InodeFlags::empty();
+    for letter in letters.chars() {
+        match INODE_FLAG_LETTERS
+            .iter()
+            .find(|(known, _)| *known == letter)
+        {
+            Some((_, flag)) => changed |= *flag,
+            None => return Err(Ext2Error::InvalidArgument("unknown flag")),
+        }
+    }
+    let flags = ext2.get_inode(inode).flags;
+    let flags = match op {
+        Some('+') => flags | changed,
+        Some('-') => flags - changed,
+        // `=` leaves the flags chattr can't change as they are
+        Some('=') => (flags - InodeFlags::USER_MODIFIABLE) | changed,
+        _ => {
+            return Err(Ext2Error::InvalidArgument(
+                "flags must start with +, - or =",
+            ))
+        }
+    };
+    ext2.set_flags(inode, flags)
+}
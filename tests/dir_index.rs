@@ -0,0 +1,49 @@
+// Directories with an htree index, made on a freshly formatted image held
+// in memory.
+
+use ext2::ext2::{CompatFeatures, Ext2, FormatOptions, InodeFlags, ROOT_INODE};
+
+/// An image with the dir_index feature and a directory /bigd big enough to
+/// be indexed
+fn indexed_image(device: &mut [u8]) -> (Ext2, usize) {
+    let options = FormatOptions {
+        features_opt: CompatFeatures::DIR_INDEX,
+        fixed_time: Some(0),
+        ..Default::default()
+    };
+    let mut ext2 = Ext2::format(device, &options).unwrap();
+    let bigd = ext2.mkdir(ROOT_INODE, "bigd", 0o755).unwrap();
+    for i in 0..500 {
+        ext2.create(bigd, &format!("file{}", i), 0o644).unwrap();
+    }
+    ext2.index_dir(bigd).unwrap();
+    assert!(ext2.get_inode(bigd).flags.contains(InodeFlags::INDEX));
+    (ext2, bigd)
+}
+
+#[test]
+fn lookup_dot_entries_in_indexed_dir() {
+    let mut device = vec![0u8; 8 << 20];
+    let (ext2, bigd) = indexed_image(&mut device);
+    assert_eq!(ext2.lookup(bigd, "."), Ok(bigd));
+    assert_eq!(ext2.lookup(bigd, ".."), Ok(ROOT_INODE));
+    assert!(ext2.lookup(bigd, "file250").is_ok());
+}
+
+#[test]
+fn rename_indexed_dir_to_new_parent() {
+    let mut device = vec![0u8; 8 << 20];
+    let (mut ext2, bigd) = indexed_image(&mut device);
+    let other = ext2.mkdir(ROOT_INODE, "other", 0o755).unwrap();
+    let root_links = ext2.get_inode(ROOT_INODE).hard_links;
+
+    ext2.rename(ROOT_INODE, "bigd", other, "bigd").unwrap();
+
+    assert_eq!(ext2.lookup(other, "bigd"), Ok(bigd));
+    assert_eq!(ext2.lookup(bigd, ".."), Ok(other));
+    assert!(ext2.lookup(ROOT_INODE, "bigd").is_err());
+    assert_eq!(ext2.get_inode(ROOT_INODE).hard_links, root_links - 1);
+    assert_eq!(ext2.get_inode(other).hard_links, 3);
+    // moving a directory beneath itself is found through `..` as well
+    assert!(ext2.rename(ROOT_INODE, "other", bigd, "other").is_err());
+}